@@ -0,0 +1,100 @@
+//! Colors of the results printed to the terminal, so that those of large
+//! runs can be scanned at a glance.
+
+use config::ColorMode;
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static STDOUT: AtomicBool = AtomicBool::new(false);
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Color {
+    Red,
+    Green,
+    Yellow,
+}
+
+/// Text that is colored once displayed, if its stream is.
+pub struct Painted<T> {
+    color: Color,
+    text: T,
+    enabled: bool,
+}
+
+impl<T> Painted<T> {
+    /// Leaves the text uncolored unless `enabled` holds.
+    pub fn enabled_if(mut self, enabled: bool) -> Painted<T> {
+        self.enabled &= enabled;
+        self
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.enabled {
+            return self.text.fmt(f);
+        }
+
+        let code = match self.color {
+            Color::Red => 31,
+            Color::Green => 32,
+            Color::Yellow => 33,
+        };
+
+        write!(f, "\x1b[{}m{}\x1b[0m", code, self.text)
+    }
+}
+
+/// Colors stdout and stderr as `mode` says, automatically each of them that
+/// is a terminal.
+pub fn enable(mode: ColorMode) {
+    let (stdout, stderr) = match mode {
+        ColorMode::Always => (true, true),
+        ColorMode::Never => (false, false),
+        ColorMode::Auto => {
+            // honours https://no-color.org and terminals that cannot color
+            let capable = env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && env::var_os("TERM").is_none_or(|term| term != "dumb");
+
+            (capable && is_terminal(Stream::Stdout), capable && is_terminal(Stream::Stderr))
+        },
+    };
+
+    STDOUT.store(stdout, Ordering::Relaxed);
+    STDERR.store(stderr, Ordering::Relaxed);
+}
+
+/// `text` colored for stdout.
+pub fn stdout<T: fmt::Display>(color: Color, text: T) -> Painted<T> {
+    Painted { color, text, enabled: STDOUT.load(Ordering::Relaxed) }
+}
+
+/// `text` colored for stderr.
+pub fn stderr<T: fmt::Display>(color: Color, text: T) -> Painted<T> {
+    Painted { color, text, enabled: STDERR.load(Ordering::Relaxed) }
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[cfg(unix)]
+fn is_terminal(stream: Stream) -> bool {
+    use libc;
+
+    let fd = match stream {
+        Stream::Stdout => libc::STDOUT_FILENO,
+        Stream::Stderr => libc::STDERR_FILENO,
+    };
+
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+#[cfg(not(unix))]
+fn is_terminal(_: Stream) -> bool {
+    false
+}
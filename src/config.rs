@@ -0,0 +1,1262 @@
+use errors::*;
+use num_cpus;
+use secrets;
+use serde_json::{self, Value};
+use sources::consul::ConsulSource;
+use sources::ec2::Ec2Source;
+use sources::etc_hosts::EtcHostsSource;
+use sources::kubernetes::KubernetesSource;
+use sources::mdns::MdnsSource;
+use sources::srv::SrvSource;
+use sources::ssh_config::SshConfigSource;
+use sources::terraform::TerraformSource;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+use template;
+use toml;
+use yaml;
+
+const CONFIG_TOKEN_VAR: &str = "EACH_CMD_CONFIG_TOKEN";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    #[serde(default)]
+    pub hostnames: Vec<HostEntry>,
+
+    /// Named groups of hosts, selected with `--group`.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<HostEntry>>,
+
+    /// Groups, of the config or inventory, that must all succeed before the
+    /// hosts of a group start.
+    #[serde(default)]
+    pub group_depends_on: BTreeMap<String, Vec<String>>,
+
+    /// Hostnames or glob patterns of hosts to leave out.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Lists of values, e.g. `{ "region": ["eu", "us"] }`, the command runs
+    /// on every host for each combination of.
+    #[serde(default)]
+    pub matrix: BTreeMap<String, Vec<Value>>,
+
+    /// Values substituted for `{key}` in the command on every host, unless
+    /// the host has its own value.
+    #[serde(default)]
+    pub vars: BTreeMap<String, Value>,
+    pub hosts_file: Option<String>,
+    pub hosts_command: Option<String>,
+    pub inventory: Option<String>,
+    pub items_csv: Option<String>,
+    pub items_jsonl: Option<String>,
+
+    /// Numbers run as items, e.g. shard numbers or ports.
+    pub items_range: Option<ItemRange>,
+    pub ec2: Option<Ec2Source>,
+    pub kubernetes: Option<KubernetesSource>,
+    pub consul: Option<ConsulSource>,
+    pub srv: Option<SrvSource>,
+    pub etc_hosts: Option<EtcHostsSource>,
+    pub ssh_config: Option<SshConfigSource>,
+    pub terraform: Option<TerraformSource>,
+    pub mdns: Option<MdnsSource>,
+    #[serde(default)]
+    pub cmd_to_run: CmdToRun,
+
+    /// Run locally once before any host, the run stopping if it fails.
+    pub before_all: Option<Cmd>,
+
+    /// Run locally once after every host, with the summary of the run in
+    /// its environment and stdin.
+    pub after_all: Option<Cmd>,
+
+    /// Rendered for each host like the command and run before it, the
+    /// command only running if it succeeds.
+    pub pre_cmd: Option<Cmd>,
+
+    /// Rendered for each host like the command and run after it, however
+    /// it ended.
+    pub post_cmd: Option<Cmd>,
+
+    /// Rendered for each host like the command and run before it, the
+    /// command being skipped as already done where the check succeeds.
+    pub check_cmd: Option<Cmd>,
+
+    /// Rendered for each host like the command and run once the command
+    /// succeeded, its stdout piped in by each_cmd.
+    pub pipe_into: Option<Cmd>,
+
+    /// Named steps run one after the other on each host in place of the
+    /// command, the host stopping at the first one that fails.
+    #[serde(default)]
+    pub steps: Vec<Step>,
+
+    /// Rendered for each host like the command and piped into its stdin.
+    pub stdin_template: Option<String>,
+
+    /// Script rendered for each host like the command, then piped into the
+    /// command, or run locally without one.
+    pub script_path: Option<String>,
+
+    /// Resolves every host before running, setting `{ip}` to its first
+    /// address of the version.
+    pub resolve_ip: Option<IpVersion>,
+
+    /// Condition on the host variables, e.g. `vars.role == "web"`, that a
+    /// host must meet to be run on.
+    pub when: Option<String>,
+
+    #[serde(default = "default_hostname_tag")]
+    pub hostname_tag: String,
+
+    /// Quotes substituted values for the shell.
+    #[serde(default)]
+    pub shell_quote: bool,
+
+    /// Passes the host to commands in `EACH_CMD_*` environment variables
+    /// instead of substituting it.
+    #[serde(default)]
+    pub host_env: bool,
+
+    #[serde(default = "num_cpus::get")]
+    pub thread_count: usize,
+
+    /// Commands running at once, `thread_count` if absent.
+    pub max_concurrency: Option<usize>,
+
+    /// Limit on command launches per second across all threads, e.g. for
+    /// bastions throttling new SSH connections.
+    pub max_starts_per_second: Option<f64>,
+
+    /// Delays the start of each host by a random time up to this, spreading
+    /// the load on shared backends.
+    pub start_jitter_ms: Option<u64>,
+
+    /// Runs the hosts in batches, each once the previous one finished.
+    pub batch_size: Option<BatchSize>,
+
+    /// Stops before the next batch if a host of the previous one failed.
+    #[serde(default)]
+    pub batch_require_success: bool,
+
+    /// Stops every command and skips the remaining hosts on the first
+    /// failure.
+    #[serde(default)]
+    pub fail_fast: bool,
+
+    /// Failed hosts for which the run exits with a failure, any of them if
+    /// absent.
+    pub fail_on: Option<FailOn>,
+
+    /// Runs the hosts in a random order, different on every run.
+    #[serde(default)]
+    pub shuffle: bool,
+
+    /// Format of what is printed to stdout, text if absent.
+    pub output: Option<OutputFormat>,
+
+    /// Whether the results are colored, only on terminals if absent.
+    pub color: Option<ColorMode>,
+
+    /// Prints the output of the commands line by line as it arrives, each
+    /// line prefixed with the host, instead of once they are done.
+    #[serde(default)]
+    pub stream: bool,
+
+    /// Shows how many hosts finished and failed so far while the commands
+    /// run, along with the time left.
+    #[serde(default)]
+    pub progress: bool,
+
+    /// Only prints the failures and the summary, leaving out the commands
+    /// started and the hosts that succeeded or were skipped.
+    #[serde(default)]
+    pub quiet: bool,
+
+    /// What else is printed: the rendered commands from 1, the full output
+    /// of the commands from 2 and why commands wait before starting from 3.
+    #[serde(default)]
+    pub verbosity: u64,
+
+    /// Prints the output shared by hosts once for all of them when they
+    /// are done, in place of that of each host.
+    #[serde(default)]
+    pub collate: bool,
+
+    /// Order that the results of the hosts are reported in, that of the
+    /// hosts if absent.
+    pub output_order: Option<OutputOrder>,
+
+    /// Failed hosts after which the hosts not started yet are skipped.
+    pub max_failures: Option<usize>,
+
+    /// Percentage of the hosts that, once exceeded by the failed ones, skips
+    /// the hosts not started yet.
+    pub max_fail_percentage: Option<f64>,
+
+    /// No timeout is applied if absent.
+    pub timeout_ms: Option<u64>,
+
+    /// Time a command may go without any output before it is killed,
+    /// however long it runs for overall.
+    pub idle_timeout_ms: Option<u64>,
+
+    /// Working directory of the commands, templated like them, that of
+    /// each_cmd if absent.
+    pub cwd: Option<String>,
+
+    /// Environment variables set for the commands, their values templated
+    /// like the commands.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Glob patterns of the variables of each_cmd that the commands inherit,
+    /// `DEFAULT_ENV_ALLOW` if absent.
+    pub env_allow: Option<Vec<String>>,
+
+    /// Glob patterns of the variables never inherited, even if allowed.
+    #[serde(default)]
+    pub env_deny: Vec<String>,
+
+    /// Limits on the resources of each command.
+    pub resource_limits: Option<ResourceLimits>,
+
+    /// Local user, by name or uid, that the commands run as.
+    pub run_as: Option<String>,
+
+    /// Wall-clock budget of the whole run, after which hosts still running
+    /// fail and those not started are skipped.
+    pub max_total_duration_ms: Option<u64>,
+
+    /// Time a command is given to exit after SIGTERM, on timeout or
+    /// cancellation, before it is killed.
+    #[serde(default = "default_kill_grace_ms")]
+    pub kill_grace_ms: u64,
+
+    /// Times a failed or timed out command is retried on a host before it
+    /// is reported as failed.
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Delays between retries, which follow each other immediately if
+    /// absent.
+    pub backoff: Option<Backoff>,
+
+    /// Failures that are retried, any if absent.
+    pub retry_on: Option<RetryOn>,
+
+    /// Polls each host, re-running the command until it succeeds, in place
+    /// of the retries.
+    pub until: Option<Until>,
+
+    /// Path to the run state file recording how each host ended, relative
+    /// to the config file.
+    pub state_file: Option<String>,
+
+    /// Schedule of daemon mode, as a cron expression.
+    pub cron: Option<String>,
+
+    /// Directory receiving the run state of each cycle in watch and daemon
+    /// modes, relative to the config file.
+    pub report_dir: Option<String>,
+
+    /// JSON report of how each host ended, relative to the config file.
+    pub report_json: Option<String>,
+
+    /// JUnit XML report with each host as a test case, relative to the
+    /// config file.
+    pub report_junit: Option<String>,
+
+    /// Markdown report of the run, to paste into tickets and comments,
+    /// relative to the config file.
+    pub report_md: Option<String>,
+
+    /// Directory receiving the full output of each host, one log file per
+    /// host, relative to the config file.
+    pub log_dir: Option<String>,
+
+    /// Files and URLs that the config was loaded from, its includes among
+    /// them, to tell whether it changed since.
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6,
+}
+
+impl FromStr for IpVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<IpVersion> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(IpVersion::Any),
+            "v4" | "ipv4" => Ok(IpVersion::V4),
+            "v6" | "ipv6" => Ok(IpVersion::V6),
+            _ => bail!("Unknown IP version '{}', expected any, v4 or v6", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFormat {
+    Text,
+
+    /// One JSON object per event, everything else going to stderr.
+    Ndjson,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => bail!("Unknown output format '{}', expected text or ndjson", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ColorMode {
+    /// Only when printing to a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ColorMode> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => bail!("Unknown color mode '{}', expected auto, always or never", s),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputOrder {
+    /// As each host finishes.
+    Completion,
+
+    /// In the order of the hosts, each once the hosts before it finished.
+    Input,
+}
+
+impl FromStr for OutputOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputOrder> {
+        match s.to_lowercase().as_str() {
+            "completion" => Ok(OutputOrder::Completion),
+            "input" => Ok(OutputOrder::Input),
+            _ => bail!("Unknown output order '{}', expected completion or input", s),
+        }
+    }
+}
+
+/// Delays growing by `multiplier` from `initial_ms` up to `max_ms`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Backoff {
+    pub initial_ms: u64,
+
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+
+    /// Delays keep growing if absent.
+    pub max_ms: Option<u64>,
+
+    /// Randomizes each delay between half and all of it, so that hosts
+    /// failing together do not all retry at once.
+    #[serde(default)]
+    pub jitter: bool,
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+impl Backoff {
+    /// Delay before retry number `attempt`, counting from zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let mut delay_ms = self.initial_ms as f64 * self.multiplier.powi(attempt as i32);
+
+        if let Some(max_ms) = self.max_ms {
+            delay_ms = delay_ms.min(max_ms as f64);
+        }
+
+        if self.jitter {
+            let fraction = (template::random_u64() >> 11) as f64 / (1u64 << 53) as f64;
+            delay_ms *= 0.5 + fraction / 2.0;
+        }
+
+        Duration::from_millis(delay_ms as u64)
+    }
+}
+
+/// Re-runs of a command that has not succeeded yet, `interval_ms` apart.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Until {
+    #[serde(default = "default_until_interval_ms")]
+    pub interval_ms: u64,
+
+    /// Attempts per host, the first run included, unlimited if absent.
+    pub max_attempts: Option<u32>,
+}
+
+fn default_until_interval_ms() -> u64 {
+    1000
+}
+
+impl Default for Until {
+    fn default() -> Until {
+        Until { interval_ms: default_until_interval_ms(), max_attempts: None }
+    }
+}
+
+/// Exit codes and whether timeouts are worth retrying, other failures being
+/// reported straight away.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryOn {
+    #[serde(default)]
+    pub exit_codes: Vec<i32>,
+
+    #[serde(default)]
+    pub timeout: bool,
+}
+
+impl FromStr for RetryOn {
+    type Err = Error;
+
+    /// Parses comma separated exit codes and `timeout`, e.g. `255,timeout`.
+    fn from_str(s: &str) -> Result<RetryOn> {
+        let mut retry_on = RetryOn::default();
+
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            if part == "timeout" {
+                retry_on.timeout = true;
+            } else {
+                let exit_code = part.parse()
+                    .chain_err(|| format!("Invalid retry condition '{}', expected an exit code or timeout", part))?;
+
+                retry_on.exit_codes.push(exit_code);
+            }
+        }
+
+        Ok(retry_on)
+    }
+}
+
+/// Count of hosts, or percentage of them such as `"20%"`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum BatchSize {
+    Count(usize),
+    Text(String),
+}
+
+impl BatchSize {
+    /// Number of hosts per batch out of `total`, at least one.
+    pub fn of(&self, total: usize) -> Result<usize> {
+        let size = match *self {
+            BatchSize::Count(count) => count,
+            BatchSize::Text(ref text) => match text.trim().strip_suffix('%') {
+                Some(percent) => {
+                    let percent: f64 = percent.trim().parse()
+                        .chain_err(|| format!("Invalid batch size '{}'", text))?;
+
+                    (total as f64 * percent / 100.0).ceil() as usize
+                },
+                None => text.trim().parse()
+                    .chain_err(|| format!("Invalid batch size '{}', expected a count or percentage", text))?,
+            },
+        };
+
+        Ok(size.max(1))
+    }
+}
+
+impl FromStr for BatchSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BatchSize> {
+        let batch_size = BatchSize::Text(s.to_owned());
+        batch_size.of(1)?;
+        Ok(batch_size)
+    }
+}
+
+/// When the run exits with a failure: on `"any"` failed host, once `"all"`
+/// the hosts that ran failed, `"never"`, or from a count or percentage of
+/// failed hosts such as `3` or `"20%"`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum FailOn {
+    Count(usize),
+    Text(String),
+}
+
+impl FailOn {
+    /// Whether `failed` hosts out of the `total` that ran fail the run.
+    pub fn fails(&self, failed: usize, total: usize) -> Result<bool> {
+        let fails = match *self {
+            FailOn::Count(count) => failed >= count.max(1),
+            FailOn::Text(ref text) => match text.trim().to_lowercase().as_str() {
+                "any" => failed > 0,
+                "all" => failed > 0 && failed == total,
+                "never" => false,
+                trimmed => match trimmed.strip_suffix('%') {
+                    Some(percent) => {
+                        let percent: f64 = percent.trim().parse()
+                            .chain_err(|| format!("Invalid failure threshold '{}'", text))?;
+
+                        failed > 0 && failed as f64 >= total as f64 * percent / 100.0
+                    },
+                    None => {
+                        let count: usize = trimmed.parse()
+                            .chain_err(|| format!("Invalid failure threshold '{}', expected any, all, never, a count or percentage", text))?;
+
+                        failed >= count.max(1)
+                    },
+                },
+            },
+        };
+
+        Ok(fails)
+    }
+}
+
+impl FromStr for FailOn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FailOn> {
+        let fail_on = FailOn::Text(s.to_owned());
+        fail_on.fails(0, 0)?;
+        Ok(fail_on)
+    }
+}
+
+/// Numbers from `start` to `end` inclusive, `step` apart, counting down if
+/// `step` is negative.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ItemRange {
+    pub start: i64,
+    pub end: i64,
+
+    #[serde(default = "default_step")]
+    pub step: i64,
+}
+
+fn default_step() -> i64 {
+    1
+}
+
+impl FromStr for ItemRange {
+    type Err = Error;
+
+    /// Parses `START:END` or `START:END:STEP`.
+    fn from_str(s: &str) -> Result<ItemRange> {
+        let parts = s.split(':')
+            .map(|part| part.trim().parse::<i64>())
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .chain_err(|| format!("Invalid item range '{}', expected START:END[:STEP]", s))?;
+
+        match parts[..] {
+            [start, end] => Ok(ItemRange { start, end, step: default_step() }),
+            [start, end, step] => Ok(ItemRange { start, end, step }),
+            _ => bail!("Invalid item range '{}', expected START:END[:STEP]", s),
+        }
+    }
+}
+
+/// Limits applied to each command and inherited by its children, each
+/// unlimited if absent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceLimits {
+    /// CPU time, after which the command gets SIGXCPU and then SIGKILL.
+    pub cpu_seconds: Option<u64>,
+
+    /// Virtual memory, beyond which allocations fail.
+    pub memory_mb: Option<u64>,
+
+    /// Open file descriptors.
+    pub open_files: Option<u64>,
+
+    /// Niceness of the command, up to 19 for the lowest priority.
+    pub nice: Option<i32>,
+}
+
+/// `KEY=VALUE` environment variable given on the command line.
+#[derive(Clone, Debug)]
+pub struct EnvVar {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for EnvVar {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EnvVar> {
+        match s.find('=') {
+            Some(index) if index > 0 => Ok(EnvVar { key: s[..index].to_owned(), value: s[index + 1..].to_owned() }),
+            _ => bail!("Invalid environment variable '{}', expected KEY=VALUE", s),
+        }
+    }
+}
+
+/// Duration given with a unit, e.g. `500ms`, `30s`, `5m` or `2h`, in seconds
+/// without one.
+#[derive(Clone, Copy, Debug)]
+pub struct Interval(pub Duration);
+
+impl FromStr for Interval {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Interval> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+
+        let value: f64 = value.parse()
+            .chain_err(|| format!("Invalid interval '{}', expected e.g. 30s", s))?;
+
+        let factor = match unit {
+            "ms" => 0.001,
+            "" | "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => bail!("Invalid interval unit '{}', expected ms, s, m or h", unit),
+        };
+
+        let interval = Duration::try_from_secs_f64(value * factor)
+            .chain_err(|| format!("Interval '{}' is too long", s))?;
+
+        // a run repeated without a pause would never let go of the hosts
+        if interval.is_zero() {
+            bail!("Interval '{}' must be longer than zero", s);
+        }
+
+        Ok(Interval(interval))
+    }
+}
+
+/// Either a single command, or a sequence of commands that runs in order
+/// until one of them fails.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum CmdToRun {
+    Single(Cmd),
+    Sequence(Vec<Cmd>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    pub name: String,
+    pub cmd: Cmd,
+
+    /// Timeout of the step instead of that of its host.
+    pub timeout_ms: Option<u64>,
+
+    /// Retries of the step instead of `retries`.
+    pub retries: Option<u32>,
+}
+
+/// A command line run by the shell, or an `{ "argv": [...] }` list of
+/// arguments run directly without one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Cmd {
+    Shell(String),
+    Argv { argv: Vec<String> },
+}
+
+impl Default for CmdToRun {
+    fn default() -> CmdToRun {
+        CmdToRun::Single(Cmd::Shell(String::new()))
+    }
+}
+
+impl CmdToRun {
+    pub fn commands(&self) -> Vec<&Cmd> {
+        match *self {
+            CmdToRun::Single(Cmd::Shell(ref cmd)) if cmd.is_empty() => Vec::new(),
+            CmdToRun::Single(ref cmd) => vec![cmd],
+            CmdToRun::Sequence(ref cmds) => cmds.iter().collect(),
+        }
+    }
+}
+
+impl Cmd {
+    /// Applies `f` to the command line, or to each of the arguments.
+    pub fn try_map<F: FnMut(&str) -> Result<String>>(&self, mut f: F) -> Result<Cmd> {
+        let cmd = match *self {
+            Cmd::Shell(ref cmd) => Cmd::Shell(f(cmd)?),
+            Cmd::Argv { ref argv } => Cmd::Argv { argv: argv.iter().map(|arg| f(arg)).collect::<Result<_>>()? },
+        };
+
+        Ok(cmd)
+    }
+
+    /// The command line, or every argument.
+    pub fn parts(&self) -> Vec<&str> {
+        match *self {
+            Cmd::Shell(ref cmd) => vec![cmd.as_str()],
+            Cmd::Argv { ref argv } => argv.iter().map(|arg| arg.as_str()).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cmd::Shell(ref cmd) => write!(f, "{}", cmd),
+            Cmd::Argv { ref argv } => {
+                let quoted: Vec<String> = argv.iter().map(|arg| template::shell_quote(arg)).collect();
+                write!(f, "{}", quoted.join(" "))
+            },
+        }
+    }
+}
+
+/// Entry of `hostnames`, either just the hostname or an object that also
+/// holds settings for that host.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum HostEntry {
+    Name(String),
+    Detailed(HostSpec),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HostSpec {
+    pub name: String,
+
+    /// Address substituted into the command, defaults to the name.
+    pub addr: Option<String>,
+
+    /// Values substituted for `{key}` in the command on this host.
+    #[serde(default)]
+    pub vars: BTreeMap<String, Value>,
+
+    /// Command to run on this host instead of `cmdToRun`.
+    pub cmd: Option<CmdToRun>,
+
+    /// Condition on the host variables for running on this host, on top of
+    /// the global `when`.
+    pub when: Option<String>,
+
+    /// Timeout on this host instead of `timeoutMs`.
+    pub timeout_ms: Option<u64>,
+
+    /// Working directory on this host instead of `cwd`.
+    pub cwd: Option<String>,
+
+    /// Environment variables for the commands on this host, on top of and
+    /// overriding `env`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// Variables inherited by default, enough for commands such as ssh to work
+/// without passing on credentials from the environment.
+pub const DEFAULT_ENV_ALLOW: &[&str] = &[
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "TERM", "LANG", "LC_*", "TZ", "TMPDIR", "SSH_AUTH_SOCK",
+];
+
+fn default_kill_grace_ms() -> u64 {
+    5000
+}
+
+fn default_hostname_tag() -> String {
+    "{}".to_owned()
+}
+
+impl Default for Config {
+    /// Config with every value at its default, as used when running without
+    /// a config file.
+    fn default() -> Config {
+        serde_json::from_value(Value::Object(Default::default()))
+            .expect("Every config value should have a default")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Guesses the format from the file extension, falling back to JSON.
+    pub fn from_path(path: &str) -> ConfigFormat {
+        let ext = Path::new(path).extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        match ext.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ConfigFormat> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(ConfigFormat::Json),
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            _ => bail!(ErrorKind::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+fn parse_value(content: &str, format: ConfigFormat) -> Result<Value> {
+    match format {
+        ConfigFormat::Json => serde_json::from_str(content)
+            .chain_err(|| "Unable to parse JSON config content"),
+
+        ConfigFormat::Toml => toml::parse(content)
+            .chain_err(|| "Unable to parse TOML config content"),
+
+        ConfigFormat::Yaml => yaml::parse(content)
+            .chain_err(|| "Unable to parse YAML config content"),
+    }
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    let mut config_file = File::open(path)
+        .chain_err(|| format!("Unable to open config file at {}", path.display()))?;
+
+    let mut buf = String::new();
+    let _ = config_file.read_to_string(&mut buf)
+        .chain_err(|| "Unable to read config file into string")?;
+
+    Ok(buf)
+}
+
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// URL of `relative` next to the document at `url`, whose query and fragment
+/// are not part of its path.
+fn join_url(url: &str, relative: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+
+    // the slashes of the scheme are not those of the path
+    let dir = match path.rfind('/') {
+        Some(index) if index > path.find("://").map_or(0, |scheme| scheme + 2) => &path[..index + 1],
+        _ => return format!("{}/{}", path, relative),
+    };
+
+    format!("{}{}", dir, relative)
+}
+
+/// Downloads the config at `url` with `curl`, sending the bearer token in
+/// `EACH_CMD_CONFIG_TOKEN` if set. The header goes through stdin to keep the
+/// token out of the process list.
+fn fetch(url: &str) -> Result<String> {
+    let token = env::var(CONFIG_TOKEN_VAR).ok();
+
+    let mut command = Command::new("curl");
+    command.args(["--silent", "--show-error", "--fail", "--location", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if token.is_some() {
+        command.args(["--header", "@-"]);
+    }
+
+    let mut child = command.spawn()
+        .chain_err(|| "Unable to launch 'curl', is it installed and on the PATH?")?;
+
+    if let (Some(mut stdin), Some(token)) = (child.stdin.take(), token) {
+        writeln!(stdin, "Authorization: Bearer {}", token)
+            .chain_err(|| "Unable to pass the config token to curl")?;
+    }
+
+    let output = child.wait_with_output()
+        .chain_err(|| "Unable to wait for curl")?;
+
+    if !output.status.success() {
+        bail!("Unable to download config from {}: {}", url, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    String::from_utf8(output.stdout)
+        .chain_err(|| format!("Config downloaded from {} is not valid UTF-8", url))
+}
+
+/// Reads the config at `path`, which may also be an HTTP(S) URL.
+fn read_source(path: &Path) -> Result<String> {
+    let path_str = path.to_string_lossy();
+
+    if is_url(&path_str) {
+        fetch(&path_str)
+    } else {
+        read_file(path)
+    }
+}
+
+/// Deep merges `overlay` onto `base`. Objects are merged key by key, any
+/// other value in `overlay` replaces the one in `base`.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (&mut Value::Object(ref mut base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// File which last set each top level key of the merged config, used to
+/// point at the culprit of errors.
+type Origins = BTreeMap<String, PathBuf>;
+
+/// Loads the config value at `path` on top of the configs that it lists in
+/// `include`, which are resolved relative to it and loaded in order.
+fn load_value(path: &Path, format: Option<ConfigFormat>, stack: &mut Vec<PathBuf>, origins: &mut Origins,
+    sources: &mut Vec<PathBuf>) -> Result<Value> {
+    let path_str = path.to_string_lossy().into_owned();
+    let content = read_source(path)?;
+    sources.push(path.to_owned());
+
+    let canonical_path = if is_url(&path_str) {
+        path.to_owned()
+    } else {
+        path.canonicalize()
+            .chain_err(|| format!("Unable to resolve config file path {}", path.display()))?
+    };
+
+    if stack.contains(&canonical_path) {
+        bail!("Config file at {} includes itself", path.display());
+    }
+
+    // the query string of a URL is not part of the extension
+    let format = format.unwrap_or_else(|| ConfigFormat::from_path(path_str.split('?').next().unwrap_or("")));
+
+    let mut value = parse_value(&content, format)
+        .chain_err(|| format!("Unable to parse config file at {}", path.display()))?;
+
+    let includes = match value.as_object_mut().and_then(|map| map.remove("include")) {
+        None => Vec::new(),
+        Some(Value::String(include)) => vec![include],
+        Some(includes) => serde_json::from_value::<Vec<String>>(includes)
+            .chain_err(|| format!("Expected 'include' in {} to be a path or a list of paths", path.display()))?,
+    };
+
+    stack.push(canonical_path);
+    let mut merged = Value::Object(Default::default());
+
+    for include in &includes {
+        // includes of a config fetched from a URL are fetched from next to
+        // it, never from the local files
+        let include_path = if is_url(&path_str) && !is_url(include) {
+            if Path::new(include).has_root() {
+                bail!("Config fetched from {} cannot include the local file {}", path_str, include);
+            }
+
+            PathBuf::from(join_url(&path_str, include))
+        } else {
+            resolve_path(&path_str, include)
+        };
+
+        let included = load_value(&include_path, None, stack, origins, sources)?;
+        merge(&mut merged, included);
+    }
+
+    stack.pop();
+
+    for key in value.as_object().into_iter().flat_map(|map| map.keys()) {
+        origins.insert(key.clone(), path.to_owned());
+    }
+
+    merge(&mut merged, value);
+
+    Ok(merged)
+}
+
+/// Expands `${VAR}` and `${VAR:-default}` references to environment
+/// variables in `s`. `$${` stands for a literal `${`, e.g. for variables that
+/// are meant to be expanded by the shell running the command instead.
+fn interpolate_str(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        if let Some(escaped) = after.strip_prefix("${") {
+            out.push_str("${");
+            rest = escaped;
+            continue;
+        }
+
+        if !after.starts_with('{') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+
+        let end = match after.find('}') {
+            Some(end) => end,
+            None => bail!("Unterminated variable reference in '{}'", s),
+        };
+
+        let reference = &after[1..end];
+
+        let (name, default) = match reference.find(":-") {
+            Some(split) => (&reference[..split], Some(&reference[split + 2..])),
+            None => (reference, None),
+        };
+
+        match (env::var(name), default) {
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(_), None) => bail!("Environment variable '{}' referenced in config is not set (write $${{{}}} to keep it literal)", name, name),
+        }
+
+        rest = &after[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn interpolate_env(value: &mut Value) -> Result<()> {
+    match *value {
+        Value::String(ref mut s) => *s = interpolate_str(s)?,
+        Value::Array(ref mut items) => for item in items {
+            interpolate_env(item)?;
+        },
+        Value::Object(ref mut map) => for (_, item) in map.iter_mut() {
+            interpolate_env(item)?;
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Finds the line on which `key` is set in the config file at `path`.
+fn key_line(path: &Path, key: &str) -> Option<usize> {
+    let content = read_file(path).ok()?;
+
+    let sets_key = |line: &str| line.match_indices(key).any(|(start, _)| {
+        let before = line[..start].trim_end_matches(['"', '\'']);
+        let after = line[start + key.len()..].trim_start_matches(['"', '\'']).trim_start();
+
+        (before.is_empty() || before.ends_with([' ', '\t', '{', ',']))
+            && (after.starts_with(':') || after.starts_with('='))
+    });
+
+    content.lines()
+        .position(sets_key)
+        .map(|index| index + 1)
+}
+
+fn describe_location(key: &str, origins: &Origins) -> String {
+    match origins.get(key) {
+        Some(path) => match key_line(path, key) {
+            Some(line) => format!(" ({} line {})", path.display(), line),
+            None => format!(" ({})", path.display()),
+        },
+        None => String::new(),
+    }
+}
+
+fn missing_field(msg: &str) -> Option<&str> {
+    msg.strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next())
+}
+
+/// Explains why `value` does not deserialize into a `Config`, naming every
+/// missing required key with its expected type and every key holding an
+/// invalid value along with where it was set.
+fn diagnose(value: &Value, origins: &Origins, error: &serde_json::Error) -> String {
+    let placeholders = [
+        ("string", Value::String(String::new())),
+        ("number", Value::from(0)),
+        ("list", Value::Array(Vec::new())),
+        ("object", Value::Object(Default::default())),
+        ("boolean", Value::Bool(false)),
+    ];
+
+    let check = |value: &Value| serde_json::from_value::<Config>(value.clone()).err().map(|e| e.to_string());
+    let accepts = |result: &Option<String>| result.as_ref().is_none_or(|msg| missing_field(msg).is_some());
+
+    let mut value = value.clone();
+    let mut missing = Vec::new();
+    let mut invalid = Vec::new();
+    let mut removed = BTreeSet::new();
+
+    while let Some(msg) = check(&value) {
+        // a missing key is filled with whichever placeholder satisfies it,
+        // which also tells the expected type
+        if let Some(field) = missing_field(&msg).filter(|field| value.get(*field).is_none()) {
+            let field = field.to_owned();
+
+            let fitting = placeholders.iter().find(|&(_, placeholder)| {
+                let mut filled = value.clone();
+                filled[&field] = placeholder.clone();
+                let result = check(&filled);
+                accepts(&result) && result.as_ref().and_then(|msg| missing_field(msg)) != Some(&field)
+            });
+
+            if let Some((kind, placeholder)) = fitting {
+                if !removed.contains(&field) {
+                    missing.push(format!("{} ({})", field, kind));
+                }
+
+                value[&field] = placeholder.clone();
+                continue;
+            }
+        }
+
+        // otherwise the culprit is the key whose removal changes the error
+        let keys: Vec<String> = value.as_object().into_iter().flat_map(|map| map.keys().cloned()).collect();
+
+        let culprit = keys.into_iter().find(|key| {
+            let mut trimmed = value.clone();
+            trimmed.as_object_mut().unwrap().remove(key);
+            check(&trimmed).as_ref() != Some(&msg)
+        });
+
+        match culprit {
+            Some(key) => {
+                invalid.push(format!("invalid value for '{}'{}: {}", key, describe_location(&key, origins), msg));
+                value.as_object_mut().unwrap().remove(&key);
+                removed.insert(key);
+            },
+
+            None => {
+                invalid.push(msg);
+                break;
+            },
+        }
+    }
+
+    let mut problems = Vec::new();
+
+    if !missing.is_empty() {
+        problems.push(format!("missing required keys: {}", missing.join(", ")));
+    }
+
+    problems.append(&mut invalid);
+
+    match problems.len() {
+        0 => error.to_string(),
+        1 => problems.remove(0),
+        count => format!("{} config problems:\n  - {}", count, problems.join("\n  - ")),
+    }
+}
+
+/// Reads the config file at `path`, using `format` if given or otherwise
+/// the format implied by the file extension. Included config files always
+/// use the format implied by their own extension.
+pub fn load(path: &str, format: Option<ConfigFormat>) -> Result<Config> {
+    let mut origins = Origins::new();
+    let mut sources = Vec::new();
+    let mut value = load_value(Path::new(path), format, &mut Vec::new(), &mut origins, &mut sources)?;
+    interpolate_env(&mut value)?;
+    secrets::decrypt_all(&mut value)?;
+
+    match serde_json::from_value(value.clone()) {
+        Ok(config) => Ok(Config { sources, ..config }),
+        Err(e) => Err(Error::from(diagnose(&value, &origins, &e)))
+            .chain_err(|| "Unable to parse config content into structure!"),
+    }
+}
+
+/// Resolves a path given inside the config file relative to the directory
+/// containing that config file. Paths in a config fetched from a URL are
+/// left relative to the working directory, and URLs are left as they are.
+pub fn resolve_path(config_path: &str, path: &str) -> PathBuf {
+    if is_url(path) {
+        return PathBuf::from(path);
+    }
+
+    let path = Path::new(path);
+
+    match Path::new(config_path).parent() {
+        Some(dir) if path.is_relative() && !is_url(config_path) => dir.join(path),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_url_resolves_next_to_the_document() {
+        assert_eq!(join_url("https://config.internal/jobs/restart.yaml?rev=a/b", "common.yaml"),
+            "https://config.internal/jobs/common.yaml");
+        assert_eq!(join_url("https://config.internal", "common.yaml"), "https://config.internal/common.yaml");
+        assert_eq!(join_url("https://config.internal/", "../common.yaml"), "https://config.internal/../common.yaml");
+    }
+
+    #[test]
+    fn resolve_path_keeps_urls_from_local_configs() {
+        assert_eq!(resolve_path("/etc/each_cmd/fleet.json", "https://config.internal/common.yaml"),
+            PathBuf::from("https://config.internal/common.yaml"));
+        assert_eq!(resolve_path("/etc/each_cmd/fleet.json", "common.yaml"), PathBuf::from("/etc/each_cmd/common.yaml"));
+        assert_eq!(resolve_path("https://config.internal/fleet.json", "common.yaml"), PathBuf::from("common.yaml"));
+    }
+
+    #[test]
+    fn interval_parses_units() {
+        assert_eq!("500ms".parse::<Interval>().unwrap().0, Duration::from_millis(500));
+        assert_eq!("1.5m".parse::<Interval>().unwrap().0, Duration::from_secs(90));
+        assert_eq!(" 30 ".parse::<Interval>().unwrap().0, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn interval_rejects_zero_and_overlong_values() {
+        for interval in &["0", "0s", "0.0ms", "99999999999999999999999", "1e3s", "5d", "-1s", ""] {
+            assert!(interval.parse::<Interval>().is_err(), "{:?} should not parse", interval);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Cron schedules of five fields, `minute hour day-of-month month
+//! day-of-week`, matched against the local time.
+//!
+//! Fields take `*`, numbers, `a-b` ranges and `/n` steps, separated by
+//! commas. Sunday is day 0 or 7 of the week, and the `@hourly`, `@daily`,
+//! `@weekly`, `@monthly` and `@yearly` shorthands are accepted.
+
+use errors::*;
+#[cfg(unix)]
+use libc;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Furthest ahead that a matching minute is looked for.
+const MAX_LOOKAHEAD: Duration = Duration::from_secs(5 * 366 * 24 * 3600);
+
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+
+    /// Whether the day of month and day of week are restricted, a day
+    /// matching either of them if both are.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+/// Broken down local time.
+struct LocalTime {
+    second: u32,
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    weekday: u32,
+    year: i32,
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Schedule> {
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            bail!("Invalid cron expression '{}', expected five fields", s);
+        }
+
+        let parse = |index: usize, min: u32, max: u32| {
+            parse_field(fields[index], min, max)
+                .chain_err(|| format!("Invalid cron expression '{}'", s))
+        };
+
+        let mut weekdays = parse(4, 0, 7)?;
+
+        // both 0 and 7 are Sunday
+        weekdays[0] |= weekdays[7];
+        weekdays.truncate(7);
+
+        Ok(Schedule {
+            minutes: parse(0, 0, 59)?,
+            hours: parse(1, 0, 23)?,
+            days: parse(2, 1, 31)?,
+            months: parse(3, 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl Schedule {
+    /// First matching minute after `time`.
+    pub fn next_after(&self, time: SystemTime) -> Result<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        let mut next = (secs / 60 + 1) * 60;
+        let end = secs + MAX_LOOKAHEAD.as_secs();
+
+        while next < end {
+            let local = local_time(next)?;
+
+            // skips whole days and hours that cannot match
+            if !self.months[local.month as usize] || !self.day_matches(&local) {
+                next = next_midnight(next, &local)?;
+            } else if !self.hours[local.hour as usize] {
+                next += u64::from(60 - local.minute) * 60;
+            } else if !self.minutes[local.minute as usize] {
+                next += 60;
+            } else {
+                return Ok(UNIX_EPOCH + Duration::from_secs(next));
+            }
+        }
+
+        bail!("Cron schedule never matches within {} years", MAX_LOOKAHEAD.as_secs() / (366 * 24 * 3600))
+    }
+
+    fn day_matches(&self, local: &LocalTime) -> bool {
+        let day = self.days[local.day as usize];
+        let weekday = self.weekdays[local.weekday as usize];
+
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            (true, false) => day,
+            (false, true) => weekday,
+            (false, false) => true,
+        }
+    }
+}
+
+/// Flags of the values in `min..=max` that the field matches, indexed by
+/// value.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>> {
+    let mut matches = vec![false; max as usize + 1];
+
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(index) => {
+                let step = part[index + 1..].parse::<u32>()
+                    .chain_err(|| format!("Invalid step in '{}'", part))?;
+
+                (&part[..index], step)
+            },
+            None => (part, 1),
+        };
+
+        if step == 0 {
+            bail!("Zero step in '{}'", part);
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let bounds = range.split('-')
+                .map(|bound| bound.parse::<u32>())
+                .collect::<::std::result::Result<Vec<_>, _>>()
+                .chain_err(|| format!("Invalid value in '{}'", part))?;
+
+            match bounds[..] {
+                // a single value with a step runs up to the maximum
+                [start] if step > 1 => (start, max),
+                [start] => (start, start),
+                [start, end] => (start, end),
+                _ => bail!("Invalid range in '{}'", part),
+            }
+        };
+
+        if start < min || end > max || start > end {
+            bail!("Value out of {}-{} in '{}'", min, max, part);
+        }
+
+        for value in (start..end + 1).step_by(step as usize) {
+            matches[value as usize] = true;
+        }
+    }
+
+    Ok(matches)
+}
+
+#[cfg(unix)]
+fn local_time(secs: u64) -> Result<LocalTime> {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        bail!("Unable to get the local time");
+    }
+
+    Ok(LocalTime {
+        second: tm.tm_sec as u32,
+        minute: tm.tm_min as u32,
+        hour: tm.tm_hour as u32,
+        day: tm.tm_mday as u32,
+        month: tm.tm_mon as u32 + 1,
+        weekday: tm.tm_wday as u32,
+        year: tm.tm_year + 1900,
+    })
+}
+
+/// Start of the local day after `local`, the time at `secs`. Days are not
+/// always 24 hours long, e.g. when daylight saving time starts or ends.
+#[cfg(unix)]
+fn next_midnight(secs: u64, local: &LocalTime) -> Result<u64> {
+    let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+    tm.tm_year = local.year - 1900;
+    tm.tm_mon = local.month as libc::c_int - 1;
+
+    // mktime normalises the day past the end of the month
+    tm.tm_mday = local.day as libc::c_int + 1;
+    tm.tm_isdst = -1;
+
+    let midnight = unsafe { libc::mktime(&mut tm) };
+
+    if midnight == -1 || midnight as u64 <= secs {
+        bail!("Unable to get the local time of the day after {}", secs);
+    }
+
+    Ok(midnight as u64)
+}
+
+#[cfg(not(unix))]
+fn next_midnight(secs: u64, _local: &LocalTime) -> Result<u64> {
+    Ok((secs / 86400 + 1) * 86400)
+}
+
+/// UTC, where the local time zone is not known.
+#[cfg(not(unix))]
+fn local_time(secs: u64) -> Result<LocalTime> {
+    // days to civil date, after Howard Hinnant's algorithm
+    let days = (secs / 86400) as i64;
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    Ok(LocalTime {
+        second: (secs % 60) as u32,
+        minute: (secs / 60 % 60) as u32,
+        hour: (secs / 3600 % 24) as u32,
+        day: day as u32,
+        month: month as u32,
+        weekday: ((days + 4).rem_euclid(7)) as u32,
+        year: year as i32,
+    })
+}
+
+/// Local time formatted as `YYYYMMDD-HHMMSS`, e.g. to name files by.
+pub fn timestamp(time: SystemTime) -> Result<String> {
+    let secs = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let local = local_time(secs)?;
+
+    Ok(format!("{:04}{:02}{:02}-{:02}{:02}{:02}",
+        local.year, local.month, local.day, local.hour, local.minute, local.second))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::{Mutex, MutexGuard};
+
+    static TZ: Mutex<()> = Mutex::new(());
+
+    extern "C" {
+        fn tzset();
+    }
+
+    /// Keeps the local time zone at `tz` while held.
+    fn time_zone(tz: &str) -> MutexGuard<'static, ()> {
+        let guard = TZ.lock().unwrap_or_else(|e| e.into_inner());
+        env::set_var("TZ", tz);
+        unsafe { tzset() };
+        guard
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn next_after_skips_a_day_shortened_by_daylight_saving_time() {
+        let _tz = time_zone("America/New_York");
+        let schedule: Schedule = "30 0 * * 1".parse().unwrap();
+
+        // from Saturday 2026-03-07 12:00 EST, the clocks going forward on
+        // the Sunday, to Monday 2026-03-09 00:30 EDT
+        assert_eq!(schedule.next_after(at(1_772_902_800)).unwrap(), at(1_773_030_600));
+    }
+
+    fn next(expr: &str, secs: u64) -> u64 {
+        let schedule: Schedule = expr.parse().unwrap();
+        schedule.next_after(at(secs)).unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    // from Thursday 2026-01-01 00:00 UTC
+    const NEW_YEAR: u64 = 1_767_225_600;
+
+    #[test]
+    fn next_after_expands_shorthands() {
+        let _tz = time_zone("UTC");
+
+        assert_eq!(next("@hourly", NEW_YEAR), NEW_YEAR + 3600);
+        assert_eq!(next("@daily", NEW_YEAR), NEW_YEAR + 86400);
+        assert_eq!(next("@weekly", NEW_YEAR), 1_767_484_800);
+        assert_eq!(next("@monthly", NEW_YEAR), 1_769_904_000);
+        assert_eq!(next("@yearly", NEW_YEAR), 1_798_761_600);
+        assert_eq!(next("@annually", NEW_YEAR), 1_798_761_600);
+    }
+
+    #[test]
+    fn next_after_matches_fields() {
+        let _tz = time_zone("UTC");
+
+        assert_eq!(next("*/15 * * * *", NEW_YEAR), NEW_YEAR + 15 * 60);
+        assert_eq!(next("*/15 * * * *", NEW_YEAR + 30), NEW_YEAR + 15 * 60);
+        assert_eq!(next("0 0 * * 7", NEW_YEAR), next("0 0 * * 0", NEW_YEAR));
+        assert_eq!(next("0 0 29 2 *", NEW_YEAR), 1_835_395_200);
+
+        // a day matches either of the day of month and the day of week
+        assert_eq!(next("0 9 15 * 1", NEW_YEAR), 1_767_603_600);
+    }
+
+    #[test]
+    fn next_after_fails_for_impossible_dates() {
+        let _tz = time_zone("UTC");
+        let schedule: Schedule = "0 0 31 2 *".parse().unwrap();
+
+        assert!(schedule.next_after(at(NEW_YEAR)).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expr in &["* * * *", "* * * * * *", "60 * * * *", "* 24 * * *", "*/0 * * * *", "5-1 * * * *",
+            "a * * * *", "1-2-3 * * * *", "* * 0 * *", "* * * 13 *", "* * * * 8", "@often"] {
+            assert!(expr.parse::<Schedule>().is_err(), "{:?} should not parse", expr);
+        }
+    }
+
+    #[test]
+    fn timestamp_formats_local_time() {
+        let _tz = time_zone("UTC");
+        assert_eq!(timestamp(at(NEW_YEAR + 3723)).unwrap(), "20260101-010203");
+    }
+}
@@ -0,0 +1,48 @@
+error_chain! {
+    errors {
+        CommandLaunch {
+            description("command launch error")
+            display("command launch error")
+        }
+        Timeout {
+            description("execution timeout")
+            display("execution timeout")
+        }
+        IdleTimeout {
+            description("idle timeout")
+            display("no output within the idle timeout")
+        }
+        DeadlineExceeded {
+            description("run deadline exceeded")
+            display("run deadline exceeded")
+        }
+        Cancelled {
+            description("run cancelled")
+            display("run cancelled")
+        }
+        Aborted {
+            description("run aborted")
+            display("run aborted after a failure")
+        }
+        HostsFailed(failed: usize, total: usize) {
+            description("hosts failed")
+            display("{} of {} hosts failed", failed, total)
+        }
+        TooManyFailures {
+            description("too many failures")
+            display("run stopped after too many failures")
+        }
+        Syntax(line: usize, col: usize, msg: String) {
+            description("config syntax error")
+            display("syntax error at line {}, column {}: {}", line, col, msg)
+        }
+        UnknownGroup(group: String) {
+            description("unknown host group")
+            display("unknown host group '{}'", group)
+        }
+        UnknownFormat(format: String) {
+            description("unknown config format")
+            display("unknown config format '{}'", format)
+        }
+    }
+}
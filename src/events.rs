@@ -0,0 +1,86 @@
+//! Events of the run written to stdout as newline delimited JSON, one
+//! object per line, for log pipelines to consume.
+//!
+//! Once enabled, the original stdout only receives the events, everything
+//! else printed going to stderr instead.
+
+use serde_json;
+use state::HostStatus;
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+
+/// Where the events go, only set once enabled.
+static OUTPUT: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Event<'a> {
+    #[serde(rename_all = "camelCase")]
+    Started { host: &'a str, command: String },
+
+    #[serde(rename_all = "camelCase")]
+    Stdout { host: &'a str, line: &'a str },
+
+    #[serde(rename_all = "camelCase")]
+    Stderr { host: &'a str, line: &'a str },
+
+    #[serde(rename_all = "camelCase")]
+    TimedOut { host: &'a str, reason: String },
+
+    #[serde(rename_all = "camelCase")]
+    Finished {
+        host: &'a str,
+        status: HostStatus,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        duration_ms: Option<u64>,
+        reason: Option<&'a str>,
+    },
+}
+
+/// Sends the events to stdout, moving everything else printed to stderr.
+#[cfg(unix)]
+pub fn enable() {
+    use libc;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
+    let _ = io::stdout().flush();
+
+    // keeps the original stdout for the events
+    let output: Box<dyn Write + Send> = unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::from_raw_fd(fd))
+        }
+    };
+
+    let _ = OUTPUT.set(Mutex::new(output));
+}
+
+/// Sends the events to stdout, along with everything else printed.
+#[cfg(not(unix))]
+pub fn enable() {
+    let _ = OUTPUT.set(Mutex::new(Box::new(io::stdout())));
+}
+
+pub fn enabled() -> bool {
+    OUTPUT.get().is_some()
+}
+
+/// Writes `event` as a line of its own, if the events are enabled.
+pub fn emit(event: &Event) {
+    let output = match OUTPUT.get() {
+        Some(output) => output,
+        None => return,
+    };
+
+    if let Ok(line) = serde_json::to_string(event) {
+        let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(output, "{}", line);
+        let _ = output.flush();
+    }
+}
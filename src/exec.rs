@@ -0,0 +1,601 @@
+use config::{Cmd, ResourceLimits};
+use errors::*;
+use events::{self, Event};
+use futures::Future;
+use futures::future::{self, Loop};
+use libc;
+use meter;
+use reactor::{self, Limits};
+use secrets;
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use user::RunAs;
+use verbosity::{self, Level};
+
+/// Interval at which a paused run checks whether to go on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Commands to run for a single host, along with how to run them.
+#[derive(Clone, Debug, Default)]
+pub struct Job {
+    pub cmds: Vec<Cmd>,
+
+    /// Command run first, the others only running if it fails, i.e. if
+    /// what they do is not done yet.
+    pub check: Option<Cmd>,
+
+    /// Run before the commands, which only run if it succeeds.
+    pub pre: Option<Cmd>,
+
+    /// Run after the commands however they ended, once `pre` started.
+    pub post: Option<Cmd>,
+
+    /// Prefix of the output lines of the commands, printed as they arrive
+    /// if set, or host of their events.
+    pub stream_prefix: Option<String>,
+
+    /// Lets the commands finish when the run is cancelled or aborted, e.g.
+    /// to clean up after others.
+    pub ignore_stop: bool,
+
+    /// Command that the stdout of the commands is piped into once they
+    /// succeeded, its own stdout taking their place.
+    pub pipe_into: Option<Cmd>,
+
+    /// Steps run in place of the commands, stopping at the first failure.
+    pub steps: Vec<Step>,
+
+    /// Extra environment variables for the commands.
+    pub env: Vec<(String, String)>,
+
+    /// Variables of each_cmd passed on to the commands, all of them if
+    /// absent.
+    pub inherited_env: Option<Arc<Vec<(String, String)>>>,
+
+    /// Working directory of the commands, that of each_cmd if absent.
+    pub cwd: Option<String>,
+
+    /// Content piped into the stdin of each command.
+    pub stdin: Option<String>,
+
+    /// Script run from a temporary file instead of the commands.
+    pub script: Option<String>,
+
+    /// Time the commands may take together before they are killed.
+    pub timeout: Option<Duration>,
+
+    /// Time each command may go without output before it is killed.
+    pub idle_timeout: Option<Duration>,
+
+    /// Instant at which any command still running is killed, regardless of
+    /// the timeout.
+    pub deadline: Option<Instant>,
+
+    /// Time given to a command to exit after SIGTERM before it is killed.
+    pub kill_grace: Duration,
+
+    /// Limit on how often commands start, shared between jobs.
+    pub throttle: Option<Arc<Throttle>>,
+
+    pub resource_limits: Option<ResourceLimits>,
+
+    /// Account that the commands run as, that of each_cmd if absent.
+    pub run_as: Option<Arc<RunAs>>,
+}
+
+impl Job {
+    /// Job running `cmd` alone, e.g. to check or prepare the host that the
+    /// job is for, `ignore_stop` letting it finish when the run stops.
+    pub fn hook(&self, cmd: &Cmd, ignore_stop: bool) -> Job {
+        Job {
+            cmds: vec![cmd.clone()],
+            steps: Vec::new(),
+            check: None,
+            pre: None,
+            post: None,
+            pipe_into: None,
+            stdin: None,
+            script: None,
+            ignore_stop,
+            ..self.clone()
+        }
+    }
+}
+
+/// Step of a job, run like a job of its own.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub name: String,
+    pub cmd: Cmd,
+
+    /// Timeout of the step instead of that of the job.
+    pub timeout: Option<Duration>,
+
+    /// Retries of the step instead of those of the run.
+    pub retries: Option<u32>,
+}
+
+/// Spaces out command launches evenly.
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Duration,
+    next_start: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn per_second(starts: f64) -> Throttle {
+        Throttle {
+            interval: Duration::from_secs_f64(1.0 / starts),
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until the next free launch slot, taking it.
+    fn wait(&self) {
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap_or_else(|e| e.into_inner());
+            let start = cmp::max(*next_start, Instant::now());
+            *next_start = start + self.interval;
+            start
+        };
+
+        let now = Instant::now();
+
+        if start > now {
+            verbosity::trace(|| format!("Throttling the start of a command by {} ms", (start - now).as_millis()));
+            thread::sleep(start - now);
+        }
+    }
+}
+
+const RUNNING: usize = 0;
+const CANCELLED: usize = 1;
+const ABORTED: usize = 2;
+const HALTED: usize = 3;
+
+/// Whether the run goes on, was cancelled by a signal, aborted itself or
+/// only lets running commands finish.
+static RUN_STATE: AtomicUsize = AtomicUsize::new(RUNNING);
+
+/// Whether commands wait before starting, and whether that was announced.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static PAUSE_ANNOUNCED: AtomicBool = AtomicBool::new(false);
+
+/// Makes SIGINT and SIGTERM cancel the run, stopping running commands like
+/// timed out ones and skipping the others. A second signal exits at once.
+/// SIGUSR1 pauses the start of commands and SIGUSR2 resumes it.
+#[cfg(unix)]
+pub fn cancel_on_signals() {
+    extern "C" fn on_signal(_: libc::c_int) {
+        if RUN_STATE.swap(CANCELLED, Ordering::SeqCst) == CANCELLED {
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    extern "C" fn on_pause(signal: libc::c_int) {
+        PAUSED.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+        PAUSE_ANNOUNCED.store(false, Ordering::SeqCst);
+    }
+
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGUSR1, on_pause as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, on_pause as *const () as libc::sighandler_t);
+    }
+}
+
+/// Blocks while the run is paused, unless it stops meanwhile.
+pub fn wait_while_paused() {
+    while PAUSED.load(Ordering::SeqCst) && stopped().is_none() {
+        if !PAUSE_ANNOUNCED.swap(true, Ordering::SeqCst) {
+            let _ = writeln!(io::stderr(), "Paused starting commands, send SIGUSR2 to resume");
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn cancel_on_signals() {}
+
+/// Stops the running commands and skips the others, like a cancellation.
+pub fn abort() {
+    let _ = RUN_STATE.compare_exchange(RUNNING, ABORTED, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Skips the commands not started yet, letting running ones finish.
+pub fn halt() {
+    let _ = RUN_STATE.compare_exchange(RUNNING, HALTED, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Lets the next run start after one that was aborted or halted, unless
+/// it was cancelled.
+pub fn reset() {
+    let _ = RUN_STATE.compare_exchange(ABORTED, RUNNING, Ordering::SeqCst, Ordering::SeqCst);
+    let _ = RUN_STATE.compare_exchange(HALTED, RUNNING, Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Whether the run was cancelled by a signal.
+pub fn cancelled() -> bool {
+    RUN_STATE.load(Ordering::SeqCst) == CANCELLED
+}
+
+/// Error for why the run stopped early, if it did.
+pub fn stopped() -> Option<ErrorKind> {
+    match RUN_STATE.load(Ordering::SeqCst) {
+        CANCELLED => Some(ErrorKind::Cancelled),
+        ABORTED => Some(ErrorKind::Aborted),
+        HALTED => Some(ErrorKind::TooManyFailures),
+        _ => None,
+    }
+}
+
+/// Error for why running commands are to be stopped, if they are.
+pub fn interrupted() -> Option<ErrorKind> {
+    stopped().filter(|kind| !matches!(*kind, ErrorKind::TooManyFailures))
+}
+
+fn shell_command(cmd: &str) -> Command {
+    let (shell, flag) = if cfg!(target_os = "windows") {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    let mut command = Command::new(shell);
+    command.args([flag, cmd]);
+    command
+}
+
+pub fn run_cmd(cmd: &str) -> Result<Output> {
+    shell_command(cmd)
+        .output()
+        .chain_err(|| ErrorKind::CommandLaunch)
+}
+
+/// Output of a job once all of its commands ran.
+pub type JobFuture = Box<dyn Future<Item = Output, Error = Error> + Send>;
+
+/// Starts the commands of `job` in order until one of them fails. The
+/// output holds the stdout and stderr of every command that ran, and the
+/// exit status of the last of them.
+pub fn start_job(job: Job) -> JobFuture {
+    if let Some(script) = job.script.clone() {
+        return start_script(job, &script);
+    }
+
+    if job.cmds.is_empty() {
+        return Box::new(future::err("No command to run".into()));
+    }
+
+    let timeout_at = job.timeout.map(|timeout| Instant::now() + timeout);
+    let job = Arc::new(job);
+
+    Box::new(future::loop_fn((0, None), move |(index, combined): (usize, Option<Output>)| {
+        let job = job.clone();
+
+        let stdin = job.stdin.clone().map(String::into_bytes);
+
+        start_command(&job, &job.cmds[index], stdin, timeout_at).map(move |output| {
+            let success = output.status.success();
+
+            let combined = match combined {
+                None => output,
+                Some(mut combined) => {
+                    combined.stdout.extend(output.stdout);
+                    combined.stderr.extend(output.stderr);
+                    combined.status = output.status;
+                    combined
+                },
+            };
+
+            if success && index + 1 < job.cmds.len() {
+                Loop::Continue((index + 1, Some(combined)))
+            } else {
+                Loop::Break((job, combined))
+            }
+        })
+    })
+    .and_then(move |(job, output)| match job.pipe_into {
+        Some(ref pipe_into) if output.status.success() => {
+            let Output { stdout, stderr, .. } = output;
+
+            Box::new(start_command(&job, pipe_into, Some(stdout), timeout_at).map(move |mut piped| {
+                piped.stderr = [stderr, piped.stderr].concat();
+                piped
+            })) as JobFuture
+        },
+        _ => Box::new(future::ok(output)),
+    }))
+}
+
+/// Starts `cmd` with `stdin` piped in. Past `timeout_at`, past the deadline
+/// of `job` or on cancellation, the command is stopped along with the
+/// processes it started and fails with the matching error.
+fn start_command(job: &Job, cmd: &Cmd, stdin: Option<Vec<u8>>, timeout_at: Option<Instant>) -> JobFuture {
+    if verbosity::shows(Level::Commands) {
+        let _hidden = meter::hide();
+        println!("Running command: {}", secrets::redact(&cmd.to_string()));
+    }
+
+    if let Some(ref host) = job.stream_prefix {
+        events::emit(&Event::Started { host, command: secrets::redact(&cmd.to_string()) });
+    }
+
+    let mut command = match *cmd {
+        Cmd::Shell(ref cmd) => shell_command(cmd),
+        Cmd::Argv { ref argv } => match argv.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            },
+            None => return Box::new(future::err("Command argv must not be empty".into())),
+        },
+    };
+
+    // sudo gets the variables to keep, switching users directly sets up
+    // those of the account as a login would
+    match job.run_as {
+        Some(ref run_as) if run_as.sudo => {
+            let keep: Vec<String> = job.env.iter().map(|(key, _)| key.clone()).collect();
+            command = run_as.sudo_command(&command, &keep);
+        },
+        _ => {},
+    }
+
+    if let Some(ref inherited_env) = job.inherited_env {
+        command.env_clear().envs(inherited_env.iter().map(|(key, value)| (key, value)));
+    }
+
+    if let Some(run_as) = job.run_as.as_ref().filter(|run_as| !run_as.sudo) {
+        command.envs(run_as.env());
+    }
+
+    command
+        .envs(job.env.iter().map(|(key, value)| (key, value)))
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if let Some(ref cwd) = job.cwd {
+        command.current_dir(cwd);
+    }
+
+    let own_group = timeout_at.is_some() || job.idle_timeout.is_some() || job.deadline.is_some();
+
+    // a process group of its own lets the whole tree be stopped, it is only
+    // made when needed since it detaches the command from the terminal
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        if own_group {
+            command.process_group(0);
+        }
+
+        if let Some(limits) = job.resource_limits {
+            // only makes system calls, as is safe between fork and exec
+            unsafe {
+                command.pre_exec(move || apply_resource_limits(&limits));
+            }
+        }
+
+        // switches last, limits such as lower niceness needing privileges
+        if let Some(run_as) = job.run_as.as_ref().filter(|run_as| !run_as.sudo).cloned() {
+            unsafe {
+                command.pre_exec(move || run_as.switch());
+            }
+        }
+    }
+
+    if let Some(ref throttle) = job.throttle {
+        throttle.wait();
+    }
+
+    let child = match (command.spawn(), job.run_as.as_ref()) {
+        (Ok(child), _) => child,
+        (Err(e), Some(run_as)) => return Box::new(future::err(format!("Unable to run command as {}: {}", run_as.user, e).into())),
+        (Err(e), None) => return Box::new(future::result(Err(e).chain_err(|| ErrorKind::CommandLaunch))),
+    };
+
+    let limits = Limits {
+        own_group,
+        idle_timeout: job.idle_timeout,
+        ignore_stop: job.ignore_stop,
+        timeout_at,
+        deadline: job.deadline,
+        kill_grace: job.kill_grace,
+    };
+
+    Box::new(reactor::watch(child, stdin, job.stream_prefix.clone(), limits).then(|done| match done {
+        Ok(result) => result,
+        Err(_) => bail!("Command watcher stopped before the command ended"),
+    }))
+}
+
+#[cfg(unix)]
+fn apply_resource_limits(limits: &ResourceLimits) -> io::Result<()> {
+    let set = |resource, value: Option<u64>| match value {
+        Some(value) => {
+            let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: value as libc::rlim_t };
+
+            if unsafe { libc::setrlimit(resource, &limit) } == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        },
+        None => Ok(()),
+    };
+
+    set(libc::RLIMIT_CPU, limits.cpu_seconds)?;
+    set(libc::RLIMIT_AS, limits.memory_mb.map(|memory_mb| memory_mb * 1024 * 1024))?;
+    set(libc::RLIMIT_NOFILE, limits.open_files)?;
+
+    if let Some(nice) = limits.nice {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `script` to a file of a private temporary directory and starts it,
+/// through its `#!` line if it has one and through `sh` otherwise.
+fn start_script(job: Job, script: &str) -> JobFuture {
+    // the user sudo switches to cannot read a file that only each_cmd can,
+    // so its interpreter reads the script from stdin instead
+    if job.run_as.as_ref().is_some_and(|run_as| run_as.sudo) {
+        return start_job(Job {
+            cmds: vec![Cmd::Argv { argv: interpreter(script) }],
+            script: None,
+            stdin: Some(script.to_owned()),
+            ..job
+        });
+    }
+
+    let dir = match private_dir() {
+        Ok(dir) => dir,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let path = dir.join("script");
+
+    if let Err(e) = write_script(&job, &dir, &path, script) {
+        let _ = fs::remove_dir_all(&dir);
+        return Box::new(future::err(e));
+    }
+
+    let path_str = path.to_string_lossy().into_owned();
+
+    let argv = if script.starts_with("#!") {
+        vec![path_str]
+    } else {
+        vec!["sh".to_owned(), path_str]
+    };
+
+    let started = start_job(Job { cmds: vec![Cmd::Argv { argv }], script: None, ..job });
+
+    Box::new(started.then(move |result| {
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }))
+}
+
+/// Interpreter running `script` from its stdin, that of its `#!` line,
+/// given the rest of the line as its argument, or `sh` without one.
+fn interpreter(script: &str) -> Vec<String> {
+    if !script.starts_with("#!") {
+        return vec!["sh".to_owned(), "-s".to_owned()];
+    }
+
+    let line = script[2..].lines().next().unwrap_or("").trim();
+
+    // like the kernel, everything after the interpreter is one argument
+    match line.find(char::is_whitespace) {
+        Some(end) => vec![line[..end].to_owned(), line[end..].trim().to_owned()],
+        None => vec![line.to_owned()],
+    }
+}
+
+/// Creates a new file at `path` in `dir` holding `script`, which no other
+/// account than the one commands run as can open, replace or read.
+#[cfg(unix)]
+fn write_script(job: &Job, dir: &Path, path: &Path, script: &str) -> Result<()> {
+    use std::os::unix::fs::{self as unix_fs, OpenOptionsExt};
+
+    // never follows a link put in place of the file
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o700)
+        .open(path)
+        .chain_err(|| format!("Unable to create script at {}", path.display()))?;
+
+    file.write_all(script.as_bytes())
+        .chain_err(|| format!("Unable to write script to {}", path.display()))?;
+
+    // switching users directly, the script is handed over to the user so
+    // that it stays unreadable by anyone else
+    if let Some(ref run_as) = job.run_as {
+        let (uid, gid) = run_as.owner();
+
+        unix_fs::fchown(&file, Some(uid), Some(gid))
+            .and_then(|_| unix_fs::chown(dir, Some(uid), Some(gid)))
+            .chain_err(|| format!("Unable to hand script at {} over to {}", path.display(), run_as.user))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_script(_job: &Job, _dir: &Path, path: &Path, script: &str) -> Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(script.as_bytes()))
+        .chain_err(|| format!("Unable to write script to {}", path.display()))
+}
+
+/// New directory of the temporary directory that only the current account
+/// can access, under a name that cannot be guessed.
+#[cfg(unix)]
+fn private_dir() -> Result<PathBuf> {
+    use std::ffi::{CString, OsString};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let template = env::temp_dir().join("each_cmd-XXXXXX");
+    let template = CString::new(template.as_os_str().as_bytes())
+        .chain_err(|| "Temporary directory path must not contain NUL")?;
+
+    let mut template = template.into_bytes_with_nul();
+
+    // mkdtemp only fills in the X's of the template, creating the
+    // directory with mode 0700
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        return Err(io::Error::last_os_error()).chain_err(|| "Unable to create temporary directory for script");
+    }
+
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+#[cfg(not(unix))]
+fn private_dir() -> Result<PathBuf> {
+    use template;
+
+    let dir = env::temp_dir().join(format!("each_cmd-{:016x}", template::random_u64()));
+
+    fs::create_dir(&dir)
+        .chain_err(|| format!("Unable to create temporary directory {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpreter_defaults_to_sh_reading_stdin() {
+        assert_eq!(interpreter("echo hi\n"), vec!["sh", "-s"]);
+    }
+
+    #[test]
+    fn interpreter_follows_the_shebang_line() {
+        assert_eq!(interpreter("#!/bin/bash\necho hi\n"), vec!["/bin/bash"]);
+        assert_eq!(interpreter("#! /usr/bin/env  python3 -u \nprint()\n"), vec!["/usr/bin/env", "python3 -u"]);
+    }
+}
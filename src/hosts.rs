@@ -0,0 +1,872 @@
+use config::{CmdToRun, HostEntry, IpVersion, ItemRange};
+use errors::*;
+use exec::run_cmd;
+use regex::Regex;
+use serde_json::{self, Map, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A single execution target, along with the variables available to its
+/// command.
+#[derive(Clone, Debug, Default)]
+pub struct Host {
+    pub name: String,
+
+    /// Address to connect to, if different from the name.
+    pub addr: Option<String>,
+    pub vars: BTreeMap<String, String>,
+
+    /// Command to run instead of the configured one.
+    pub cmd: Option<CmdToRun>,
+
+    /// Condition for running on this host.
+    pub when: Option<String>,
+
+    /// Timeout on this host instead of the configured one.
+    pub timeout_ms: Option<u64>,
+
+    /// Working directory on this host instead of the configured one.
+    pub cwd: Option<String>,
+
+    /// Environment variables on this host, overriding the configured ones.
+    pub env: BTreeMap<String, String>,
+}
+
+impl Host {
+    pub fn new<S: Into<String>>(name: S) -> Host {
+        Host { name: name.into(), ..Host::default() }
+    }
+
+    /// Copy of this host under another name, e.g. for each expansion of a
+    /// hostname pattern.
+    fn renamed<S: Into<String>>(&self, name: S) -> Host {
+        Host { name: name.into(), ..self.clone() }
+    }
+
+    pub fn addr(&self) -> &str {
+        self.addr.as_ref().unwrap_or(&self.name)
+    }
+}
+
+impl<'a> From<&'a HostEntry> for Host {
+    fn from(entry: &'a HostEntry) -> Host {
+        match *entry {
+            HostEntry::Name(ref name) => Host::new(name.clone()),
+            HostEntry::Detailed(ref spec) => {
+                let mut vars = BTreeMap::new();
+
+                for (key, value) in &spec.vars {
+                    flatten_json(key, value, &mut vars);
+                }
+
+                Host {
+                    name: spec.name.clone(),
+                    addr: spec.addr.clone(),
+                    vars,
+                    cmd: spec.cmd.clone(),
+                    when: spec.when.clone(),
+                    timeout_ms: spec.timeout_ms,
+                    cwd: spec.cwd.clone(),
+                    env: spec.env.clone(),
+                }
+            },
+        }
+    }
+}
+
+/// Reads one hostname per line, ignoring surrounding whitespace, blank lines
+/// and anything after a `#`.
+pub fn read_lines<R: BufRead>(reader: R) -> Result<Vec<String>> {
+    let mut hostnames = Vec::new();
+
+    for line in reader.lines() {
+        let line = line.chain_err(|| "Unable to read hostname line")?;
+        let hostname = line.split('#').next().unwrap_or("").trim();
+
+        if !hostname.is_empty() {
+            hostnames.push(hostname.to_owned());
+        }
+    }
+
+    Ok(hostnames)
+}
+
+/// Hostnames read from stdin, kept for the later cycles of repeated runs.
+static STDIN_HOSTNAMES: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+/// Reads the hostnames from stdin the first time, giving the same ones on
+/// every later call.
+pub fn from_stdin() -> Result<Vec<String>> {
+    let mut hostnames = STDIN_HOSTNAMES.lock().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(ref hostnames) = *hostnames {
+        return Ok(hostnames.clone());
+    }
+
+    let stdin = io::stdin();
+    let lock = stdin.lock();
+
+    let read = read_lines(lock)
+        .chain_err(|| "Unable to read hostnames from stdin")?;
+
+    *hostnames = Some(read.clone());
+    Ok(read)
+}
+
+pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+
+    let file = File::open(path)
+        .chain_err(|| format!("Unable to open hosts file at {}", path.display()))?;
+
+    read_lines(BufReader::new(file))
+        .chain_err(|| format!("Unable to read hostnames from {}", path.display()))
+}
+
+/// Runs `cmd` and reads hostnames from its stdout, which may either be a JSON
+/// array of strings or one hostname per line.
+pub fn from_command(cmd: &str) -> Result<Vec<String>> {
+    let output = run_cmd(cmd)
+        .chain_err(|| format!("Unable to run hosts command '{}'", cmd))?;
+
+    if !output.status.success() {
+        bail!("Hosts command '{}' failed with {}: {}",
+            cmd, output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    if stdout.trim_start().starts_with('[') {
+        serde_json::from_str(&stdout)
+            .chain_err(|| format!("Unable to parse output of hosts command '{}' as JSON array", cmd))
+    } else {
+        read_lines(stdout.as_bytes())
+    }
+}
+
+/// Parses the inside of `[...]` as a numeric (`01-20`, `1:10:2`) or
+/// alphabetic (`a-f`) range, returning `None` if it is not a range at all.
+fn parse_range(spec: &str) -> Result<Option<Vec<String>>> {
+    let (bounds, step) = {
+        let parts: Vec<&str> = spec.split(':').collect();
+
+        match parts.len() {
+            1 => (spec.splitn(2, '-').collect::<Vec<_>>(), None),
+            2 => (parts, None),
+            3 => (parts[..2].to_vec(), Some(parts[2])),
+            _ => return Ok(None),
+        }
+    };
+
+    if bounds.len() != 2 {
+        return Ok(None);
+    }
+
+    let (start, end) = (bounds[0], bounds[1]);
+
+    let step = match step {
+        Some(step) => match step.parse::<usize>() {
+            Ok(step) if step > 0 => step,
+            _ => return Ok(None),
+        },
+        None => 1,
+    };
+
+    if let (Ok(first), Ok(last)) = (start.parse::<u64>(), end.parse::<u64>()) {
+        if first > last {
+            bail!("Range start '{}' is after its end '{}'", start, end);
+        }
+
+        // a leading zero on the start pads every value to its width
+        let width = if start.len() > 1 && start.starts_with('0') { start.len() } else { 0 };
+
+        let values = (first..last + 1).step_by(step)
+            .map(|n| format!("{:0width$}", n, width = width))
+            .collect();
+
+        return Ok(Some(values));
+    }
+
+    let is_letter = |s: &str| s.len() == 1 && s.chars().all(|c| c.is_ascii_alphabetic());
+
+    if is_letter(start) && is_letter(end) {
+        let (first, last) = (start.as_bytes()[0], end.as_bytes()[0]);
+
+        if first > last {
+            bail!("Range start '{}' is after its end '{}'", start, end);
+        }
+
+        let values = (first..last + 1).step_by(step)
+            .map(|c| (c as char).to_string())
+            .collect();
+
+        return Ok(Some(values));
+    }
+
+    Ok(None)
+}
+
+/// Expands every range such as `web[01-20].example.com` in `pattern` into
+/// the full list of hostnames. Brackets that do not hold a range, such as
+/// IPv6 literals, are kept verbatim.
+pub fn expand_ranges(pattern: &str) -> Result<Vec<String>> {
+    let mut search = 0;
+
+    while let Some(found) = pattern[search..].find('[') {
+        let open = search + found;
+
+        let close = match pattern[open..].find(']') {
+            Some(found) => open + found,
+            None => break,
+        };
+
+        if let Some(values) = parse_range(&pattern[open + 1..close])? {
+            let prefix = &pattern[..open];
+            let suffixes = expand_ranges(&pattern[close + 1..])?;
+
+            let mut hostnames = Vec::with_capacity(values.len() * suffixes.len());
+
+            for value in &values {
+                for suffix in &suffixes {
+                    hostnames.push(format!("{}{}{}", prefix, value, suffix));
+                }
+            }
+
+            return Ok(hostnames);
+        }
+
+        search = open + 1;
+    }
+
+    Ok(vec![pattern.to_owned()])
+}
+
+/// Finds the brace that closes the one at `open`, skipping nested pairs.
+fn matching_brace(pattern: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, c) in pattern[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some(open + i);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Splits the inside of a brace pair on its top level commas.
+fn split_alternatives(inner: &str) -> Vec<&str> {
+    let mut alternatives = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in inner.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                alternatives.push(&inner[start..i]);
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+
+    alternatives.push(&inner[start..]);
+    alternatives
+}
+
+/// Expands shell style braces such as `db-{a,b,c}.internal`, including
+/// nested ones. Braces without a comma are kept verbatim.
+pub fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut search = 0;
+
+    while let Some(found) = pattern[search..].find('{') {
+        let open = search + found;
+
+        let close = match matching_brace(pattern, open) {
+            Some(close) => close,
+            None => break,
+        };
+
+        let alternatives = split_alternatives(&pattern[open + 1..close]);
+
+        if alternatives.len() > 1 {
+            let prefix = &pattern[..open];
+            let suffix = &pattern[close + 1..];
+
+            return alternatives.iter()
+                .flat_map(|alternative| expand_braces(&format!("{}{}{}", prefix, alternative, suffix)))
+                .collect();
+        }
+
+        search = open + 1;
+    }
+
+    vec![pattern.to_owned()]
+}
+
+pub fn expand_all(hosts: &[Host]) -> Result<Vec<Host>> {
+    let mut expanded_hosts = Vec::new();
+
+    for host in hosts {
+        for braced in expand_braces(&host.name) {
+            let expanded = expand_ranges(&braced)
+                .chain_err(|| format!("Unable to expand hostname '{}'", host.name))?;
+
+            expanded_hosts.extend(expanded.into_iter().map(|name| host.renamed(name)));
+        }
+    }
+
+    Ok(expanded_hosts)
+}
+
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains('*') || pattern.contains('?')
+}
+
+/// Matches `text` against a glob where `*` matches any run of characters
+/// and `?` matches exactly one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // lets the last star swallow one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Replaces every host named by a glob pattern with copies named after the
+/// `known` hosts that it matches, keeping the other hosts as they are.
+pub fn resolve_globs(hosts: &[Host], known: &[String]) -> Result<Vec<Host>> {
+    let mut resolved = Vec::new();
+
+    for host in hosts {
+        if !is_glob(&host.name) {
+            resolved.push(host.clone());
+            continue;
+        }
+
+        let matches: Vec<Host> = known.iter()
+            .filter(|known| glob_match(&host.name, known))
+            .map(|known| host.renamed(known.clone()))
+            .collect();
+
+        if matches.is_empty() {
+            bail!("Hostname pattern '{}' matches no inventory host", host.name);
+        }
+
+        resolved.extend(matches);
+    }
+
+    Ok(resolved)
+}
+
+/// Pattern of `--limit`, matching host names.
+enum LimitPattern<'a> {
+    Glob(&'a str),
+    Regex(Regex),
+}
+
+impl<'a> LimitPattern<'a> {
+    fn matches(&self, name: &str) -> bool {
+        match *self {
+            LimitPattern::Glob(glob) => glob_match(glob, name),
+            LimitPattern::Regex(ref regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Keeps the hosts matching any of `patterns`, which are glob patterns, or
+/// regular expressions when prefixed with `~`. Patterns prefixed with `!`
+/// leave out the hosts that they match instead, out of all the hosts if no
+/// other pattern is given.
+pub fn limit(hosts: Vec<Host>, patterns: &[String]) -> Result<Vec<Host>> {
+    let mut included = Vec::new();
+    let mut excluded = Vec::new();
+
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+
+        let pattern = match pattern.strip_prefix('~') {
+            Some(regex) => LimitPattern::Regex(Regex::new(regex)?),
+            None => LimitPattern::Glob(pattern),
+        };
+
+        if negated {
+            excluded.push(pattern);
+        } else {
+            included.push(pattern);
+        }
+    }
+
+    let limited: Vec<Host> = hosts.into_iter()
+        .filter(|host| {
+            (included.is_empty() || included.iter().any(|pattern| pattern.matches(&host.name)))
+                && !excluded.iter().any(|pattern| pattern.matches(&host.name))
+        })
+        .collect();
+
+    if limited.is_empty() {
+        bail!("No host matches the limit '{}'", patterns.join(","));
+    }
+
+    Ok(limited)
+}
+
+/// Repeats every host for each combination of the `matrix` values, which
+/// are set as variables. Without any hosts, each combination is run on its
+/// own, named after its values.
+pub fn apply_matrix(hosts: Vec<Host>, matrix: &BTreeMap<String, Vec<Value>>) -> Result<Vec<Host>> {
+    if matrix.is_empty() {
+        return Ok(hosts);
+    }
+
+    let mut combinations: Vec<Vec<(String, String)>> = vec![Vec::new()];
+
+    for (key, values) in matrix {
+        if values.is_empty() {
+            bail!("Matrix list '{}' has no values", key);
+        }
+
+        let mut texts = Vec::with_capacity(values.len());
+
+        for value in values {
+            let mut vars = BTreeMap::new();
+            flatten_json(key, value, &mut vars);
+            texts.push(vars.remove(key).unwrap_or_default());
+        }
+
+        combinations = combinations.into_iter()
+            .flat_map(|combination| texts.iter().map(move |text| {
+                let mut combination = combination.clone();
+                combination.push((key.clone(), text.clone()));
+                combination
+            }))
+            .collect();
+    }
+
+    if hosts.is_empty() {
+        let hosts = combinations.into_iter()
+            .map(|combination| {
+                let name: Vec<&str> = combination.iter().map(|(_, value)| value.as_str()).collect();
+                Host { vars: combination.iter().cloned().collect(), ..Host::new(name.join(",")) }
+            })
+            .collect();
+
+        return Ok(hosts);
+    }
+
+    let mut combined = Vec::with_capacity(hosts.len() * combinations.len());
+
+    for host in hosts {
+        for combination in &combinations {
+            let mut host = host.clone();
+            host.vars.extend(combination.iter().cloned());
+            combined.push(host);
+        }
+    }
+
+    Ok(combined)
+}
+
+/// Splits CSV content into records of fields. Fields may be quoted, in which
+/// case they can hold commas, newlines and doubled quotes.
+fn parse_csv(content: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut chars = content.chars().peekable();
+    let mut in_quotes = false;
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                '"' => in_quotes = false,
+                _ => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+
+                    field.push(c);
+                },
+            }
+
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => record.push(::std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' => {
+                record.push(::std::mem::take(&mut field));
+                records.push(::std::mem::take(&mut record));
+                line += 1;
+            },
+            _ => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        bail!(ErrorKind::Syntax(line, 1, "unterminated quoted field".to_owned()));
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // blank lines carry no item
+    records.retain(|record| !(record.len() == 1 && record[0].trim().is_empty()));
+    Ok(records)
+}
+
+/// Reads hosts from CSV content whose header row names the variables. The
+/// host is named by its `hostname` column, or else by its first column.
+pub fn read_csv(content: &str) -> Result<Vec<Host>> {
+    let mut records = parse_csv(content)?.into_iter();
+
+    let header: Vec<String> = match records.next() {
+        Some(header) => header.into_iter().map(|column| column.trim().to_owned()).collect(),
+        None => return Ok(Vec::new()),
+    };
+
+    let name_index = header.iter().position(|column| column == "hostname").unwrap_or(0);
+    let mut hosts = Vec::new();
+
+    for (i, record) in records.enumerate() {
+        if record.len() != header.len() {
+            bail!("CSV row {} has {} fields but the header has {}", i + 2, record.len(), header.len());
+        }
+
+        let mut host = Host::new(record[name_index].clone());
+        host.vars = header.iter().cloned().zip(record).collect();
+        hosts.push(host);
+    }
+
+    Ok(hosts)
+}
+
+pub fn read_file(path: &Path, kind: &str) -> Result<String> {
+    let mut file = File::open(path)
+        .chain_err(|| format!("Unable to open {} file at {}", kind, path.display()))?;
+
+    let mut content = String::new();
+    let _ = file.read_to_string(&mut content)
+        .chain_err(|| format!("Unable to read {} file into string", kind))?;
+
+    Ok(content)
+}
+
+pub fn from_csv_file<P: AsRef<Path>>(path: P) -> Result<Vec<Host>> {
+    let path = path.as_ref();
+
+    read_csv(&read_file(path, "CSV")?)
+        .chain_err(|| format!("Unable to parse CSV file at {}", path.display()))
+}
+
+/// Adds `value` to `vars` under `key`, flattening nested objects and arrays
+/// into dotted keys such as `disk.0.size` while also keeping their JSON text.
+pub fn flatten_json(key: &str, value: &Value, vars: &mut BTreeMap<String, String>) {
+    let nested: Vec<(String, &Value)> = match *value {
+        Value::Object(ref map) => map.iter()
+            .map(|(k, v)| (format!("{}.{}", key, k), v))
+            .collect(),
+
+        Value::Array(ref items) => items.iter()
+            .enumerate()
+            .map(|(i, v)| (format!("{}.{}", key, i), v))
+            .collect(),
+
+        Value::String(ref s) => {
+            vars.insert(key.to_owned(), s.clone());
+            return;
+        },
+
+        Value::Null => {
+            vars.insert(key.to_owned(), String::new());
+            return;
+        },
+
+        _ => {
+            vars.insert(key.to_owned(), value.to_string());
+            return;
+        },
+    };
+
+    vars.insert(key.to_owned(), value.to_string());
+
+    for (nested_key, nested_value) in nested {
+        flatten_json(&nested_key, nested_value, vars);
+    }
+}
+
+/// Reads hosts from JSON Lines content, where each non-blank line is an
+/// object of variables. The host is named by its `hostname` variable, or
+/// else after its line number.
+pub fn read_json_lines(content: &str) -> Result<Vec<Host>> {
+    let mut hosts = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let object: Map<String, Value> = serde_json::from_str(line)
+            .chain_err(|| format!("Line {} is not a JSON object", i + 1))?;
+
+        let mut vars = BTreeMap::new();
+
+        for (key, value) in &object {
+            flatten_json(key, value, &mut vars);
+        }
+
+        let name = vars.get("hostname")
+            .cloned()
+            .unwrap_or_else(|| format!("item-{}", i + 1));
+
+        hosts.push(Host { name, vars, ..Host::default() });
+    }
+
+    Ok(hosts)
+}
+
+pub fn from_json_lines_file<P: AsRef<Path>>(path: P) -> Result<Vec<Host>> {
+    let path = path.as_ref();
+
+    read_json_lines(&read_file(path, "JSON Lines")?)
+        .chain_err(|| format!("Unable to parse JSON Lines file at {}", path.display()))
+}
+
+/// Lists one host per number of the range, named by the number and also
+/// carrying it in the `item` variable.
+pub fn from_range(range: &ItemRange) -> Result<Vec<Host>> {
+    if range.step == 0 {
+        bail!("Item range step must not be zero");
+    }
+
+    let mut hosts = Vec::new();
+    let mut item = range.start;
+
+    while (range.step > 0 && item <= range.end) || (range.step < 0 && item >= range.end) {
+        let mut host = Host::new(item.to_string());
+        host.vars.insert("item".into(), item.to_string());
+        hosts.push(host);
+
+        item = match item.checked_add(range.step) {
+            Some(item) => item,
+            None => break,
+        };
+    }
+
+    Ok(hosts)
+}
+
+/// Sets the `ip` variable of every host without one to the first address of
+/// the version that its address resolves to, giving back why each host that
+/// does not resolve failed to.
+pub fn resolve_ips(hosts: &mut [Host], version: IpVersion) -> Vec<Option<Error>> {
+    hosts.iter_mut()
+        .map(|host| resolve_ip(host, version).err())
+        .collect()
+}
+
+fn resolve_ip(host: &mut Host, version: IpVersion) -> Result<()> {
+    if host.vars.contains_key("ip") {
+        return Ok(());
+    }
+
+    // the cause is part of the message, which is all the report keeps
+    let addrs = (host.addr(), 0).to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {}: {}", host.addr(), e))?;
+
+    let ip = addrs.map(|addr| addr.ip())
+        .find(|ip| match version {
+            IpVersion::Any => true,
+            IpVersion::V4 => ip.is_ipv4(),
+            IpVersion::V6 => ip.is_ipv6(),
+        });
+
+    match ip {
+        Some(ip) => {
+            host.vars.insert("ip".into(), ip.to_string());
+            Ok(())
+        },
+        None => {
+            let version = match version {
+                IpVersion::Any => "IP",
+                IpVersion::V4 => "IPv4",
+                IpVersion::V6 => "IPv6",
+            };
+
+            bail!("{} has no {} address", host.addr(), version)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(names: &[&str]) -> Vec<Host> {
+        names.iter().map(|&name| Host::new(name)).collect()
+    }
+
+    fn names(hosts: &[Host]) -> Vec<&str> {
+        hosts.iter().map(|host| host.name.as_str()).collect()
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|&pattern| pattern.to_owned()).collect()
+    }
+
+    #[test]
+    fn glob_match_handles_stars_and_question_marks() {
+        assert!(glob_match("web-*.eu", "web-12.eu"));
+        assert!(glob_match("web-?", "web-1"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*a*b", "xaaab"));
+        assert!(!glob_match("web-?", "web-12"));
+        assert!(!glob_match("web-*.eu", "web-1.us"));
+    }
+
+    #[test]
+    fn limit_keeps_hosts_matching_a_glob_or_regex() {
+        let limited = limit(hosts(&["web-1.eu", "web-2.us", "db-1"]), &patterns(&["web-*.eu", "~^db-\\d$"])).unwrap();
+        assert_eq!(names(&limited), vec!["web-1.eu", "db-1"]);
+    }
+
+    #[test]
+    fn limit_leaves_out_negated_patterns() {
+        let all = || hosts(&["web-1", "web-2", "web-3", "db-1"]);
+
+        let limited = limit(all(), &patterns(&["web-*", "!web-2"])).unwrap();
+        assert_eq!(names(&limited), vec!["web-1", "web-3"]);
+
+        // negated patterns alone start from all the hosts
+        let limited = limit(all(), &patterns(&["!web-*"])).unwrap();
+        assert_eq!(names(&limited), vec!["db-1"]);
+
+        let limited = limit(all(), &patterns(&["!~^web-[12]$"])).unwrap();
+        assert_eq!(names(&limited), vec!["web-3", "db-1"]);
+    }
+
+    #[test]
+    fn limit_fails_when_nothing_matches() {
+        assert!(limit(hosts(&["web-1"]), &patterns(&["db-*"])).is_err());
+        assert!(limit(hosts(&["web-1"]), &patterns(&["!web-*"])).is_err());
+        assert!(limit(hosts(&["web-1"]), &patterns(&["~(web"])).is_err());
+    }
+
+    #[test]
+    fn expand_ranges_pads_steps_and_letters() {
+        assert_eq!(expand_ranges("web[08-10].eu").unwrap(), vec!["web08.eu", "web09.eu", "web10.eu"]);
+        assert_eq!(expand_ranges("n[0:10:5]").unwrap(), vec!["n0", "n5", "n10"]);
+        assert_eq!(expand_ranges("rack-[a-c]").unwrap(), vec!["rack-a", "rack-b", "rack-c"]);
+        assert_eq!(expand_ranges("r[1-2]u[1-2]").unwrap(), vec!["r1u1", "r1u2", "r2u1", "r2u2"]);
+    }
+
+    #[test]
+    fn expand_ranges_keeps_brackets_without_a_range() {
+        assert_eq!(expand_ranges("[::1]").unwrap(), vec!["[::1]"]);
+        assert_eq!(expand_ranges("web[x]").unwrap(), vec!["web[x]"]);
+        assert_eq!(expand_ranges("web[1-").unwrap(), vec!["web[1-"]);
+    }
+
+    #[test]
+    fn expand_ranges_rejects_backward_ranges() {
+        assert!(expand_ranges("web[10-1]").is_err());
+        assert!(expand_ranges("rack-[c-a]").is_err());
+    }
+
+    #[test]
+    fn expand_braces_handles_nesting_and_keeps_single_words() {
+        assert_eq!(expand_braces("db-{a,b}.internal"), vec!["db-a.internal", "db-b.internal"]);
+        assert_eq!(expand_braces("{web-{1,2},db}"), vec!["web-1", "web-2", "db"]);
+        assert_eq!(expand_braces("{x,y}{1,2}"), vec!["x1", "x2", "y1", "y2"]);
+        assert_eq!(expand_braces("web-{1}"), vec!["web-{1}"]);
+        assert_eq!(expand_braces("web-{1,2"), vec!["web-{1,2"]);
+    }
+
+    #[test]
+    fn expand_all_combines_braces_and_ranges() {
+        let expanded = expand_all(&hosts(&["{web,db}-[1-2]"])).unwrap();
+        assert_eq!(names(&expanded), vec!["web-1", "web-2", "db-1", "db-2"]);
+
+        assert!(expand_all(&hosts(&["{web,db}-[2-1]"])).is_err());
+    }
+
+    #[test]
+    fn resolve_globs_selects_known_hosts() {
+        let known = patterns(&["web-1", "web-2", "db-1"]);
+        let resolved = resolve_globs(&hosts(&["web-*", "cache"]), &known).unwrap();
+
+        assert_eq!(names(&resolved), vec!["web-1", "web-2", "cache"]);
+        assert!(resolve_globs(&hosts(&["nothing-*"]), &known).is_err());
+    }
+
+    #[test]
+    fn read_csv_handles_quoted_fields() {
+        let hosts = read_csv("role,hostname,note\r\nweb,\"web-1\",\"a, \"\"quoted\"\"\nnote\"\n\ndb,db-1,\n").unwrap();
+
+        assert_eq!(names(&hosts), vec!["web-1", "db-1"]);
+        assert_eq!(hosts[0].vars["note"], "a, \"quoted\"\nnote");
+        assert_eq!(hosts[0].vars["role"], "web");
+        assert_eq!(hosts[1].vars["note"], "");
+    }
+
+    #[test]
+    fn read_csv_names_hosts_by_the_first_column_without_hostname() {
+        let hosts = read_csv("name,port\nweb-1,22").unwrap();
+
+        assert_eq!(names(&hosts), vec!["web-1"]);
+        assert_eq!(hosts[0].vars["port"], "22");
+        assert!(read_csv("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_csv_rejects_malformed_content() {
+        assert!(read_csv("hostname,note\nweb-1,\"unterminated\n").is_err());
+        assert!(read_csv("hostname,note\nweb-1\n").is_err());
+        assert!(read_csv("hostname,note\nweb-1,a,b\n").is_err());
+    }
+}
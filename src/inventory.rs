@@ -0,0 +1,240 @@
+//! Reader for Ansible style INI inventories, e.g.
+//!
+//! ```ini
+//! [web]
+//! web-1.example.com
+//! web-2.example.com http_port=8080
+//!
+//! [db]
+//! db-1.example.com
+//!
+//! [prod:children]
+//! web
+//! db
+//! ```
+//!
+//! Host variables and `[group:vars]` sections are accepted but ignored.
+
+use errors::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const UNGROUPED: &str = "ungrouped";
+
+#[derive(Debug, Default)]
+struct Group {
+    name: String,
+    hosts: Vec<String>,
+    children: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Inventory {
+    groups: Vec<Group>,
+}
+
+enum Section {
+    Hosts(usize),
+    Children(usize),
+    Vars,
+}
+
+impl Inventory {
+    pub fn parse(content: &str) -> Result<Inventory> {
+        let mut inventory = Inventory::default();
+        let mut section = Section::Hosts(inventory.group_index(UNGROUPED));
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    bail!(ErrorKind::Syntax(i + 1, 1, "unterminated group header".to_owned()));
+                }
+
+                let header = &line[1..line.len() - 1];
+                let mut parts = header.splitn(2, ':');
+                let name = parts.next().unwrap_or("").trim();
+
+                if name.is_empty() {
+                    bail!(ErrorKind::Syntax(i + 1, 2, "empty group name".to_owned()));
+                }
+
+                let index = inventory.group_index(name);
+
+                section = match parts.next().map(|kind| kind.trim()) {
+                    None => Section::Hosts(index),
+                    Some("children") => Section::Children(index),
+                    Some("vars") => Section::Vars,
+                    Some(kind) => bail!(ErrorKind::Syntax(i + 1, 2, format!("unknown section type '{}'", kind))),
+                };
+
+                continue;
+            }
+
+            // the first token names the host or child group, the rest are
+            // variables
+            let name = line.split_whitespace().next().unwrap_or("").to_owned();
+
+            match section {
+                Section::Hosts(index) => inventory.groups[index].hosts.push(name),
+                Section::Children(index) => {
+                    inventory.group_index(&name);
+                    inventory.groups[index].children.push(name);
+                },
+                Section::Vars => (),
+            }
+        }
+
+        Ok(inventory)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Inventory> {
+        let path = path.as_ref();
+
+        let mut file = File::open(path)
+            .chain_err(|| format!("Unable to open inventory file at {}", path.display()))?;
+
+        let mut content = String::new();
+        let _ = file.read_to_string(&mut content)
+            .chain_err(|| "Unable to read inventory file into string")?;
+
+        Inventory::parse(&content)
+            .chain_err(|| format!("Unable to parse inventory file at {}", path.display()))
+    }
+
+    fn group_index(&mut self, name: &str) -> usize {
+        match self.groups.iter().position(|group| group.name == name) {
+            Some(index) => index,
+            None => {
+                self.groups.push(Group { name: name.to_owned(), ..Group::default() });
+                self.groups.len() - 1
+            },
+        }
+    }
+
+    pub fn has_group(&self, name: &str) -> bool {
+        name == "all" || self.groups.iter().any(|group| group.name == name)
+    }
+
+    /// Lists the hosts of the given groups, including those of their child
+    /// groups, in file order and without duplicates. The special group `all`
+    /// matches every host.
+    pub fn hosts<S: AsRef<str>>(&self, groups: &[S]) -> Result<Vec<String>> {
+        let mut hosts = Vec::new();
+        let mut seen_hosts = HashSet::new();
+        let mut seen_groups = HashSet::new();
+
+        for group in groups {
+            let group = group.as_ref();
+
+            if !self.has_group(group) {
+                bail!(ErrorKind::UnknownGroup(group.to_owned()));
+            }
+
+            if group == "all" {
+                for group in &self.groups {
+                    self.collect(&group.name, &mut hosts, &mut seen_hosts, &mut seen_groups);
+                }
+            } else {
+                self.collect(group, &mut hosts, &mut seen_hosts, &mut seen_groups);
+            }
+        }
+
+        Ok(hosts)
+    }
+
+    fn collect<'a>(&'a self, name: &'a str, hosts: &mut Vec<String>, seen_hosts: &mut HashSet<String>, seen_groups: &mut HashSet<&'a str>) {
+        // also guards against cyclic children declarations
+        if !seen_groups.insert(name) {
+            return;
+        }
+
+        if let Some(group) = self.groups.iter().find(|group| group.name == name) {
+            for host in &group.hosts {
+                if seen_hosts.insert(host.clone()) {
+                    hosts.push(host.clone());
+                }
+            }
+
+            for child in &group.children {
+                self.collect(child, hosts, seen_hosts, seen_groups);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVENTORY: &str = "
+lonely.example.com
+
+[web]
+web-1.example.com
+web-[01:03].eu.example.com http_port=8080
+
+[db]
+db-1.example.com ansible_user=postgres
+; a comment
+web-1.example.com
+
+[db:vars]
+not-a-host.example.com
+ntp_server=ntp.example.com
+
+[prod:children]
+web
+db
+";
+
+    #[test]
+    fn hosts_include_child_groups_without_duplicates() {
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+
+        assert_eq!(inventory.hosts(&["prod"]).unwrap(),
+            vec!["web-1.example.com", "web-[01:03].eu.example.com", "db-1.example.com"]);
+        assert_eq!(inventory.hosts(&["db", "web"]).unwrap(),
+            vec!["db-1.example.com", "web-1.example.com", "web-[01:03].eu.example.com"]);
+    }
+
+    #[test]
+    fn hosts_keep_ranges_for_expanding() {
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+        let hosts = inventory.hosts(&["web"]).unwrap();
+
+        assert_eq!(::hosts::expand_ranges(&hosts[1]).unwrap(),
+            vec!["web-01.eu.example.com", "web-02.eu.example.com", "web-03.eu.example.com"]);
+    }
+
+    #[test]
+    fn vars_sections_add_no_hosts() {
+        let inventory = Inventory::parse(INVENTORY).unwrap();
+        let all = inventory.hosts(&["all"]).unwrap();
+
+        assert_eq!(all[0], "lonely.example.com");
+        assert!(!all.iter().any(|host| host.contains("not-a-host") || host.contains("ntp")), "{:?}", all);
+        assert_eq!(all.len(), 4);
+    }
+
+    #[test]
+    fn cyclic_children_end() {
+        let inventory = Inventory::parse("[a:children]\nb\n\n[b:children]\na\n\n[b]\nhost-b\n").unwrap();
+        assert_eq!(inventory.hosts(&["a"]).unwrap(), vec!["host-b"]);
+    }
+
+    #[test]
+    fn rejects_malformed_inventories() {
+        assert!(Inventory::parse("[web\nweb-1").is_err());
+        assert!(Inventory::parse("[]\nweb-1").is_err());
+        assert!(Inventory::parse("[web:hosts]\nweb-1").is_err());
+        assert!(Inventory::parse("[web]\nweb-1").unwrap().hosts(&["db"]).is_err());
+    }
+}
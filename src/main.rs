@@ -1,139 +1,1784 @@
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate error_chain;
 
-extern crate futures;
-extern crate futures_cpupool;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate libc;
+extern crate num_cpus;
+
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate structopt;
+
+#[macro_use]
+extern crate structopt_derive;
+extern crate tokio_timer;
+
+mod color;
+mod config;
+mod cron;
+mod errors;
+mod events;
+mod exec;
+mod hosts;
+mod inventory;
+mod meter;
+mod reactor;
+mod regex;
+mod report;
+mod runner;
+mod secrets;
+mod sources;
+mod stages;
+mod state;
+mod template;
+mod toml;
+mod user;
+mod verbosity;
+mod yaml;
+
+use color::Color;
+use config::{BatchSize, Cmd, CmdToRun, ColorMode, Config, ConfigFormat, EnvVar, FailOn, HostEntry, Interval, IpVersion, ItemRange, OutputFormat, OutputOrder, RetryOn, Until};
+use cron::Schedule;
+use events::Event;
+use exec::{Job, Throttle};
+use futures::Future;
+use hosts::Host;
+use inventory::Inventory;
+use report::{DurationStats, HostReport, RunReport};
+use runner::{Finished, Outcome, Runner};
+use stages::Stages;
+use state::{HostStatus, RunState};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::process::{self, Output};
+use std::slice;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
+use template::Position;
+use user::RunAs;
+use verbosity::Level;
+
+use errors::*;
+
+/// Time between checks for cancellation while waiting for the next cycle.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Hosts listed in the summary as the slowest.
+const SLOWEST_HOSTS: usize = 5;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "Each Command Program", about = "Runs command on given list of hostnames.")]
+struct MainArgMap {
+    #[structopt(short = "c", long = "config", help = "Path or HTTP(S) URL of config file, optional if the hosts and command are given as arguments")]
+    config_path: Option<String>,
+
+    #[structopt(short = "f", long = "format", help = "Config file format (json, toml, yaml), guessed from the file extension if absent")]
+    format: Option<ConfigFormat>,
+
+    #[structopt(long = "cmd", help = "Command to run, overriding cmdToRun")]
+    cmd_to_run: Option<String>,
+
+    #[structopt(long = "tag", help = "Tag replaced by the hostname in the command, overriding hostnameTag")]
+    hostname_tag: Option<String>,
+
+    #[structopt(long = "shell-quote", help = "Quotes substituted values for the shell, as with shellQuote")]
+    shell_quote: bool,
+
+    #[structopt(long = "host-env", help = "Passes the host in environment variables instead of substituting it, as with hostEnv")]
+    host_env: bool,
+
+    #[structopt(short = "t", long = "threads", help = "Number of worker threads, overriding threadCount")]
+    thread_count: Option<usize>,
+
+    #[structopt(short = "j", long = "max-concurrency", help = "Number of commands running at once, overriding maxConcurrency (defaults to the thread count)")]
+    max_concurrency: Option<usize>,
+
+    #[structopt(long = "max-starts-per-second", help = "Limit on command launches per second, overriding maxStartsPerSecond")]
+    max_starts_per_second: Option<f64>,
+
+    #[structopt(long = "start-jitter", help = "Delays the start of each host by up to this many milliseconds, overriding startJitterMs")]
+    start_jitter_ms: Option<u64>,
+
+    #[structopt(long = "batch-size", help = "Runs the hosts in batches of this count or percentage, e.g. 5 or 20%, overriding batchSize")]
+    batch_size: Option<BatchSize>,
+
+    #[structopt(long = "fail-on", help = "Exits with a failure on any failed host, once all failed, never, or from a count or percentage of them, e.g. 3 or 20%, overriding failOn")]
+    fail_on: Option<FailOn>,
+
+    #[structopt(long = "batch-require-success", help = "Stops before the next batch if a host of the previous one failed")]
+    batch_require_success: bool,
+
+    #[structopt(long = "dry-run", help = "Prints the rendered command for each host without running it")]
+    dry_run: bool,
+
+    #[structopt(long = "step", help = "Asks before running on each host, one host at a time")]
+    step: bool,
+
+    #[structopt(long = "canary", help = "Runs on this many hosts first, and on the rest once confirmed")]
+    canary: Option<usize>,
+
+    #[structopt(long = "yes", help = "Continues after the canary hosts without asking")]
+    yes: bool,
+
+    #[structopt(long = "shuffle", help = "Runs the hosts in a random order, different on every run")]
+    shuffle: bool,
+
+    #[structopt(long = "output", help = "Prints text, or one JSON object per event with ndjson and everything else to stderr, overriding output")]
+    output: Option<OutputFormat>,
+
+    #[structopt(long = "color", help = "Colors the results with auto, always or never, auto only coloring terminals, overriding color")]
+    color: Option<ColorMode>,
+
+    #[structopt(long = "stream", help = "Prints the output of the commands line by line as it arrives, prefixed with the host")]
+    stream: bool,
+
+    #[structopt(long = "progress", help = "Shows the hosts finished and failed so far along with the time left while the commands run")]
+    progress: bool,
+
+    #[structopt(short = "q", long = "quiet", help = "Only prints the failures and the summary of the run")]
+    quiet: bool,
+
+    #[structopt(short = "v", help = "Also prints the rendered commands, then with -vv their full output and with -vvv why they wait before starting, overriding verbosity")]
+    verbose: u64,
+
+    #[structopt(long = "collate", help = "Prints the output shared by hosts once for all of them, e.g. to see what version is deployed where")]
+    collate: bool,
+
+    #[structopt(long = "output-order", help = "Reports the results of the hosts as each finishes with completion, or in the order of the hosts with input, overriding outputOrder")]
+    output_order: Option<OutputOrder>,
+
+    #[structopt(long = "fail-fast", help = "Stops every command and skips the remaining hosts on the first failure")]
+    fail_fast: bool,
+
+    #[structopt(long = "max-failures", help = "Skips the remaining hosts once this many failed, overriding maxFailures")]
+    max_failures: Option<usize>,
+
+    #[structopt(long = "max-fail-percentage", help = "Skips the remaining hosts once more than this percentage failed, overriding maxFailPercentage")]
+    max_fail_percentage: Option<f64>,
+
+    #[structopt(long = "timeout", help = "Command timeout in milliseconds, overriding timeoutMs")]
+    timeout_ms: Option<u64>,
+
+    #[structopt(long = "idle-timeout", help = "Kills commands without output for this many milliseconds, overriding idleTimeoutMs")]
+    idle_timeout_ms: Option<u64>,
+
+    #[structopt(long = "env", help = "KEY=VALUE environment variable for the commands, templated like them, may be repeated")]
+    env: Vec<EnvVar>,
+
+    #[structopt(long = "env-allow", help = "Comma separated glob patterns of the variables passed on to the commands, overriding envAllow (* for all)")]
+    env_allow: Vec<String>,
+
+    #[structopt(long = "env-deny", help = "Comma separated glob patterns of the variables never passed on to the commands, added to envDeny")]
+    env_deny: Vec<String>,
+
+    #[structopt(long = "run-as", help = "Local user that the commands run as, switched to directly as root and through sudo otherwise, overriding runAs")]
+    run_as: Option<String>,
+
+    #[structopt(long = "cwd", help = "Working directory of the commands, templated like them, overriding cwd")]
+    cwd: Option<String>,
+
+    #[structopt(long = "max-total-duration", help = "Milliseconds after which the whole run stops, overriding maxTotalDurationMs")]
+    max_total_duration_ms: Option<u64>,
+
+    #[structopt(long = "kill-grace", help = "Milliseconds commands get to exit after SIGTERM before they are killed, overriding killGraceMs")]
+    kill_grace_ms: Option<u64>,
+
+    #[structopt(long = "retries", help = "Times a failed or timed out command is retried per host, overriding retries")]
+    retries: Option<u32>,
+
+    #[structopt(long = "retry-on", help = "Comma separated exit codes and timeout that are retried, overriding retryOn")]
+    retry_on: Option<RetryOn>,
+
+    #[structopt(long = "until", help = "Re-runs the command on each host until it succeeds, as with until")]
+    until: bool,
+
+    #[structopt(long = "until-interval", help = "Milliseconds between re-runs until success, implying --until")]
+    until_interval_ms: Option<u64>,
+
+    #[structopt(long = "until-max-attempts", help = "Attempts per host until success, implying --until")]
+    until_max_attempts: Option<u32>,
+
+    #[structopt(long = "watch", help = "Repeats the whole run on this interval until interrupted, e.g. 30s or 5m")]
+    watch: Option<Interval>,
+
+    #[structopt(long = "daemon", help = "Runs on the cron schedule of the config until interrupted")]
+    daemon: bool,
+
+    #[structopt(long = "cron", help = "Cron schedule of daemon mode, e.g. '*/15 * * * *', overriding cron")]
+    cron: Option<String>,
+
+    #[structopt(long = "report-dir", help = "Directory receiving the run state of each cycle in watch and daemon modes, overriding reportDir")]
+    report_dir: Option<String>,
+
+    #[structopt(long = "report-json", help = "Path to JSON report of how each host ended, overriding reportJson")]
+    report_json: Option<String>,
+
+    #[structopt(long = "report-junit", help = "Path to JUnit XML report with each host as a test case, overriding reportJunit")]
+    report_junit: Option<String>,
+
+    #[structopt(long = "report-md", help = "Path to Markdown report of the run, to paste into tickets and comments, overriding reportMd")]
+    report_md: Option<String>,
+
+    #[structopt(long = "log-dir", help = "Directory receiving the full output of each host as <host>.log, overriding logDir")]
+    log_dir: Option<String>,
+
+    #[structopt(long = "state", help = "Path to run state file recording how each host ended, overriding stateFile")]
+    state_path: Option<String>,
+
+    #[structopt(long = "resume", help = "Path to run state file of an earlier run, whose succeeded hosts are skipped")]
+    resume_path: Option<String>,
+
+    #[structopt(long = "hosts", help = "Comma separated hostnames, added to the config hostnames")]
+    hostnames: Vec<String>,
+
+    #[structopt(long = "exclude", help = "Comma separated hostnames or glob patterns to leave out, added to the config exclude")]
+    exclude: Vec<String>,
+
+    #[structopt(long = "limit", help = "Comma separated glob patterns restricting the run to the matching hosts, or a regular expression prefixed with ~, those prefixed with ! leaving out the hosts they match")]
+    limits: Vec<String>,
+
+    #[structopt(help = "Command to run, overriding cmdToRun (pass it after --)")]
+    cmd_args: Vec<String>,
+
+    #[structopt(long = "resolve-ip", help = "Resolves every host to set {ip}, to an address of version any, v4 or v6, overriding resolveIp")]
+    resolve_ip: Option<IpVersion>,
+
+    #[structopt(long = "script", help = "Path to script rendered for each host, overriding scriptPath")]
+    script_path: Option<String>,
+
+    #[structopt(long = "hosts-stdin", help = "Reads hostnames line by line from stdin instead of the config file")]
+    hosts_stdin: bool,
+
+    #[structopt(long = "hosts-file", help = "Path to file with one hostname per line, added to the config hostnames")]
+    hosts_file: Option<String>,
+
+    #[structopt(short = "i", long = "inventory", help = "Path to Ansible style INI inventory, whose hosts are added to the config hostnames or matched by its glob patterns")]
+    inventory_path: Option<String>,
+
+    #[structopt(short = "g", long = "group", help = "Host group of the config or inventory to run against, may be repeated (defaults to all)")]
+    groups: Vec<String>,
+
+    #[structopt(long = "items-csv", help = "Path to CSV file with one item per row, whose columns become command variables")]
+    items_csv: Option<String>,
+
+    #[structopt(long = "items-jsonl", help = "Path to JSON Lines file with one object of command variables per line")]
+    items_jsonl: Option<String>,
+
+    #[structopt(long = "items-range", help = "Numbers START:END[:STEP] to run as items, END included, overriding itemsRange")]
+    items_range: Option<ItemRange>,
+}
+
+/// Picks the path given on the command line, or else the one given in the
+/// config, which is relative to the config file.
+fn source_path(main_arg_map: &MainArgMap, cli_path: &Option<String>, config_path: &Option<String>) -> Option<PathBuf> {
+    match (cli_path.as_ref(), config_path.as_ref(), main_arg_map.config_path.as_ref()) {
+        (Some(path), _, _) => Some(PathBuf::from(path)),
+        (None, Some(path), Some(config_file)) => Some(config::resolve_path(config_file, path)),
+        (None, Some(path), None) => Some(PathBuf::from(path)),
+        (None, None, _) => None,
+    }
+}
+
+/// Splits comma separated arguments into patterns.
+fn split_patterns(args: &[String]) -> Vec<String> {
+    args.iter()
+        .flat_map(|arg| arg.split(','))
+        .map(|pattern| pattern.trim())
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| pattern.to_owned())
+        .collect()
+}
+
+/// Variables of each_cmd that the commands inherit, all of them if every
+/// one is allowed and none denied.
+fn inherited_env(config: &Config) -> Option<Arc<Vec<(String, String)>>> {
+    let default_allow: Vec<String> = config::DEFAULT_ENV_ALLOW.iter().map(|&pattern| pattern.to_owned()).collect();
+    let allow = config.env_allow.as_ref().unwrap_or(&default_allow);
+
+    if allow.iter().any(|pattern| pattern == "*") && config.env_deny.is_empty() {
+        return None;
+    }
+
+    let inherited = env::vars()
+        .filter(|(key, _)| allow.iter().any(|pattern| hosts::glob_match(pattern, key)))
+        .filter(|(key, _)| !config.env_deny.iter().any(|pattern| hosts::glob_match(pattern, key)))
+        .collect();
+
+    Some(Arc::new(inherited))
+}
+
+/// Replaces config values with those given on the command line.
+fn apply_overrides(main_arg_map: &MainArgMap, config: &mut Config) {
+    if let Some(ref cmd_to_run) = main_arg_map.cmd_to_run {
+        config.cmd_to_run = CmdToRun::Single(Cmd::Shell(cmd_to_run.clone()));
+    }
+
+    if !main_arg_map.cmd_args.is_empty() {
+        config.cmd_to_run = CmdToRun::Single(Cmd::Shell(main_arg_map.cmd_args.join(" ")));
+    }
+
+    for hostnames in &main_arg_map.hostnames {
+        config.hostnames.extend(hostnames.split(',')
+            .map(|hostname| hostname.trim())
+            .filter(|hostname| !hostname.is_empty())
+            .map(|hostname| HostEntry::Name(hostname.to_owned())));
+    }
+
+    config.exclude.extend(split_patterns(&main_arg_map.exclude));
+
+    if let Some(ref hostname_tag) = main_arg_map.hostname_tag {
+        config.hostname_tag = hostname_tag.clone();
+    }
+
+    if main_arg_map.shell_quote {
+        config.shell_quote = true;
+    }
+
+    if main_arg_map.host_env {
+        config.host_env = true;
+    }
+
+    if let Some(thread_count) = main_arg_map.thread_count {
+        config.thread_count = thread_count;
+    }
+
+    if main_arg_map.max_concurrency.is_some() {
+        config.max_concurrency = main_arg_map.max_concurrency;
+    }
+
+    if main_arg_map.max_starts_per_second.is_some() {
+        config.max_starts_per_second = main_arg_map.max_starts_per_second;
+    }
+
+    if main_arg_map.start_jitter_ms.is_some() {
+        config.start_jitter_ms = main_arg_map.start_jitter_ms;
+    }
+
+    if main_arg_map.batch_size.is_some() {
+        config.batch_size = main_arg_map.batch_size.clone();
+    }
+
+    if main_arg_map.fail_on.is_some() {
+        config.fail_on = main_arg_map.fail_on.clone();
+    }
+
+    if main_arg_map.batch_require_success {
+        config.batch_require_success = true;
+    }
+
+    if main_arg_map.fail_fast {
+        config.fail_fast = true;
+    }
+
+    if main_arg_map.shuffle {
+        config.shuffle = true;
+    }
+
+    if main_arg_map.stream {
+        config.stream = true;
+    }
+
+    if main_arg_map.progress {
+        config.progress = true;
+    }
+
+    if main_arg_map.quiet {
+        config.quiet = true;
+    }
+
+    if main_arg_map.verbose > 0 {
+        config.verbosity = main_arg_map.verbose;
+    }
+
+    if main_arg_map.collate {
+        config.collate = true;
+    }
+
+    if main_arg_map.output.is_some() {
+        config.output = main_arg_map.output;
+    }
+
+    if main_arg_map.color.is_some() {
+        config.color = main_arg_map.color;
+    }
+
+    if main_arg_map.output_order.is_some() {
+        config.output_order = main_arg_map.output_order;
+    }
+
+    if main_arg_map.max_failures.is_some() {
+        config.max_failures = main_arg_map.max_failures;
+    }
+
+    if main_arg_map.max_fail_percentage.is_some() {
+        config.max_fail_percentage = main_arg_map.max_fail_percentage;
+    }
+
+    if main_arg_map.timeout_ms.is_some() {
+        config.timeout_ms = main_arg_map.timeout_ms;
+    }
+
+    if main_arg_map.idle_timeout_ms.is_some() {
+        config.idle_timeout_ms = main_arg_map.idle_timeout_ms;
+    }
+
+    for env_var in &main_arg_map.env {
+        config.env.insert(env_var.key.clone(), env_var.value.clone());
+    }
+
+    if !main_arg_map.env_allow.is_empty() {
+        config.env_allow = Some(split_patterns(&main_arg_map.env_allow));
+    }
+
+    config.env_deny.extend(split_patterns(&main_arg_map.env_deny));
+
+    if main_arg_map.run_as.is_some() {
+        config.run_as = main_arg_map.run_as.clone();
+    }
+
+    if main_arg_map.cwd.is_some() {
+        config.cwd = main_arg_map.cwd.clone();
+    }
+
+    if main_arg_map.max_total_duration_ms.is_some() {
+        config.max_total_duration_ms = main_arg_map.max_total_duration_ms;
+    }
+
+    if let Some(kill_grace_ms) = main_arg_map.kill_grace_ms {
+        config.kill_grace_ms = kill_grace_ms;
+    }
+
+    if let Some(retries) = main_arg_map.retries {
+        config.retries = retries;
+    }
+
+    if main_arg_map.retry_on.is_some() {
+        config.retry_on = main_arg_map.retry_on.clone();
+    }
+
+    if main_arg_map.cron.is_some() {
+        config.cron = main_arg_map.cron.clone();
+    }
+
+    if main_arg_map.until || main_arg_map.until_interval_ms.is_some() || main_arg_map.until_max_attempts.is_some() {
+        let until = config.until.get_or_insert_with(Until::default);
+
+        if let Some(interval_ms) = main_arg_map.until_interval_ms {
+            until.interval_ms = interval_ms;
+        }
+
+        if main_arg_map.until_max_attempts.is_some() {
+            until.max_attempts = main_arg_map.until_max_attempts;
+        }
+    }
+}
+
+/// Gathers the listed hosts from every configured source, then expands
+/// ranges and braces and resolves glob patterns against the inventory.
+fn resolve_listed_hosts(main_arg_map: &MainArgMap, config: &Config) -> Result<Vec<Host>> {
+    let hosts = if main_arg_map.hosts_stdin {
+        // the piped hostnames stand in for the configured ones, whose
+        // sources are then left alone
+        hosts::from_stdin()?.into_iter().map(Host::new).collect()
+    } else {
+        let mut hosts: Vec<Host> = config.hostnames.iter().map(Host::from).collect();
+        let hosts_file = source_path(main_arg_map, &main_arg_map.hosts_file, &config.hosts_file);
+
+        if let Some(hosts_file) = hosts_file {
+            hosts.extend(hosts::from_file(&hosts_file)?.into_iter().map(Host::new));
+        }
+
+        if let Some(ref hosts_command) = config.hosts_command {
+            hosts.extend(hosts::from_command(hosts_command)?.into_iter().map(Host::new));
+        }
+
+        hosts
+    };
+
+    let mut hosts = hosts::expand_all(&hosts)?;
+    let inventory = load_inventory(main_arg_map, config)?;
+
+    let all = vec!["all".to_owned()];
+
+    let groups = if main_arg_map.groups.is_empty() {
+        &all
+    } else {
+        &main_arg_map.groups
+    };
+
+    let mut group_hosts = group_hosts(groups, config, inventory.as_ref())?;
+
+    // glob patterns pick from the group hosts, otherwise all of the selected
+    // group hosts are added
+    if let Some(pattern) = hosts.iter().find(|host| hosts::is_glob(&host.name)) {
+        if inventory.is_none() && config.groups.is_empty() {
+            bail!("Hostname pattern '{}' requires an inventory or host groups to match against", pattern.name);
+        }
+
+        let known: Vec<String> = group_hosts.into_iter().map(|host| host.name).collect();
+        hosts = hosts::resolve_globs(&hosts, &known)?;
+    } else {
+        hosts.append(&mut group_hosts);
+    }
+
+    Ok(hosts)
+}
+
+fn load_inventory(main_arg_map: &MainArgMap, config: &Config) -> Result<Option<Inventory>> {
+    let inventory_path = source_path(main_arg_map, &main_arg_map.inventory_path, &config.inventory);
+
+    match inventory_path {
+        Some(inventory_path) => Inventory::from_file(inventory_path).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Lists the hosts of the groups, looked up in both the config groups and
+/// the inventory.
+fn group_hosts(groups: &[String], config: &Config, inventory: Option<&Inventory>) -> Result<Vec<Host>> {
+    let mut hosts = Vec::new();
+
+    for group in groups {
+        let in_inventory = inventory.is_some_and(|inventory| inventory.has_group(group));
+
+        if group == "all" {
+            for entries in config.groups.values() {
+                hosts.extend(entries.iter().map(Host::from));
+            }
+        } else if let Some(entries) = config.groups.get(group) {
+            hosts.extend(entries.iter().map(Host::from));
+        } else if !in_inventory {
+            bail!(ErrorKind::UnknownGroup(group.clone()));
+        }
+
+        if let (true, Some(inventory)) = (in_inventory, inventory) {
+            hosts.extend(inventory.hosts(&[group])?.into_iter().map(Host::new));
+        }
+    }
+
+    // hosts may be in more than one of the groups
+    let mut hosts = hosts::expand_all(&hosts)?;
+    let mut seen = HashSet::new();
+    hosts.retain(|host| seen.insert(host.name.clone()));
+
+    Ok(hosts)
+}
+
+/// Builds the hosts to run against, from the resolved hostnames followed by
+/// the dynamic host sources and then the CSV, JSON Lines and range items,
+/// leaving out the excluded ones and those outside of the limit. Every host
+/// is then repeated for each matrix combination, and given the global
+/// variables.
+fn resolve_hosts(main_arg_map: &MainArgMap, config: &Config) -> Result<Vec<Host>> {
+    let mut hosts = resolve_listed_hosts(main_arg_map, config)?;
+
+    if let Some(ref ec2) = config.ec2 {
+        let mut ec2_hosts = ec2.hosts()?;
+        hosts.append(&mut ec2_hosts);
+    }
+
+    if let Some(ref kubernetes) = config.kubernetes {
+        let mut pod_hosts = kubernetes.hosts()?;
+        hosts.append(&mut pod_hosts);
+    }
+
+    if let Some(ref consul) = config.consul {
+        let mut consul_hosts = consul.hosts()?;
+        hosts.append(&mut consul_hosts);
+    }
+
+    if let Some(ref srv) = config.srv {
+        let mut srv_hosts = srv.hosts()?;
+        hosts.append(&mut srv_hosts);
+    }
+
+    if let Some(ref etc_hosts) = config.etc_hosts {
+        let mut etc_hosts_hosts = etc_hosts.hosts()?;
+        hosts.append(&mut etc_hosts_hosts);
+    }
+
+    if let Some(ref ssh_config) = config.ssh_config {
+        let mut ssh_config_hosts = ssh_config.hosts()?;
+        hosts.append(&mut ssh_config_hosts);
+    }
+
+    if let Some(ref terraform) = config.terraform {
+        let mut terraform_hosts = terraform.hosts()?;
+        hosts.append(&mut terraform_hosts);
+    }
+
+    if let Some(ref mdns) = config.mdns {
+        let mut mdns_hosts = mdns.hosts()?;
+        hosts.append(&mut mdns_hosts);
+    }
+
+    let items_csv = source_path(main_arg_map, &main_arg_map.items_csv, &config.items_csv);
+
+    if let Some(items_csv) = items_csv {
+        let mut csv_hosts = hosts::from_csv_file(items_csv)?;
+        hosts.append(&mut csv_hosts);
+    }
+
+    let items_jsonl = source_path(main_arg_map, &main_arg_map.items_jsonl, &config.items_jsonl);
+
+    if let Some(items_jsonl) = items_jsonl {
+        let mut jsonl_hosts = hosts::from_json_lines_file(items_jsonl)?;
+        hosts.append(&mut jsonl_hosts);
+    }
+
+    if let Some(items_range) = main_arg_map.items_range.as_ref().or(config.items_range.as_ref()) {
+        let mut range_hosts = hosts::from_range(items_range)?;
+        hosts.append(&mut range_hosts);
+    }
+
+    let excluded: Vec<Host> = config.exclude.iter().map(Host::new).collect();
+    let excluded = hosts::expand_all(&excluded)?;
+
+    hosts.retain(|host| !excluded.iter().any(|excluded| hosts::glob_match(&excluded.name, &host.name)));
+
+    if !main_arg_map.limits.is_empty() {
+        let mut patterns = Vec::new();
+
+        for limit in &main_arg_map.limits {
+            // a regular expression may itself contain commas
+            if limit.starts_with('~') || limit.starts_with("!~") {
+                patterns.push(limit.clone());
+            } else {
+                patterns.extend(limit.split(',')
+                    .map(|pattern| pattern.trim())
+                    .filter(|pattern| !pattern.is_empty())
+                    .map(|pattern| pattern.to_owned()));
+            }
+        }
+
+        hosts = hosts::limit(hosts, &patterns)?;
+    }
+
+    let mut hosts = hosts::apply_matrix(hosts, &config.matrix)?;
+    let mut global_vars = BTreeMap::new();
+
+    for (key, value) in &config.vars {
+        hosts::flatten_json(key, value, &mut global_vars);
+    }
+
+    for host in &mut hosts {
+        for (key, value) in &global_vars {
+            host.vars.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Loads the config with the command line overrides applied. Kept apart
+/// from the run itself so that long running modes can reload the config
+/// between runs.
+fn load_config(main_arg_map: &MainArgMap) -> Result<Config> {
+    let mut config = match main_arg_map.config_path {
+        Some(ref config_path) => config::load(config_path, main_arg_map.format)?,
+        None => Config::default(),
+    };
+
+    apply_overrides(main_arg_map, &mut config);
+
+    let has_script = main_arg_map.script_path.is_some() || config.script_path.is_some();
+
+    if !config.steps.is_empty() {
+        if config.pipe_into.is_some() {
+            bail!("Steps run in place of the command, so pipeInto cannot be set as well");
+        }
+
+        if !config.cmd_to_run.commands().is_empty() || has_script {
+            bail!("Steps run in place of the command, so neither cmdToRun nor a script can be set as well");
+        }
+    } else if config.cmd_to_run.commands().is_empty() && !has_script {
+        bail!("No command to run, set cmdToRun or steps in the config or pass it after --");
+    }
+
+    if has_script && config.stdin_template.is_some() {
+        bail!("A script is piped into the command, so stdinTemplate cannot be set as well");
+    }
+
+    if config.max_starts_per_second.is_some_and(|starts| !(starts > 0.0 && starts.is_finite())) {
+        bail!("maxStartsPerSecond must be a positive number");
+    }
+
+    // so that an invalid threshold is not only found once the run is over
+    if let Some(ref fail_on) = config.fail_on {
+        fail_on.fails(0, 0)?;
+    }
+
+    Ok(config)
+}
+
+/// Environment variables describing `host` to its commands in host env mode,
+/// with each host variable as `EACH_CMD_VAR_<NAME>`, uppercased and with
+/// characters other than letters and digits replaced by `_`.
+fn host_env(host: &Host, position: Position) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("EACH_CMD_HOST".to_owned(), host.addr().to_owned()),
+        ("EACH_CMD_NAME".to_owned(), host.name.clone()),
+        ("EACH_CMD_INDEX".to_owned(), position.index.to_string()),
+        ("EACH_CMD_TOTAL".to_owned(), position.total.to_string()),
+    ];
+
+    for (key, value) in &host.vars {
+        let key: String = key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+
+        env.push((format!("EACH_CMD_VAR_{}", key), value.clone()));
+    }
+
+    env
+}
+
+/// Leaves out the hosts that do not meet the global or their own `when`
+/// condition, reporting each of them as skipped.
+fn select_hosts(hosts: Vec<Host>, config: &Config) -> Result<Vec<Host>> {
+    let total = hosts.len();
+    let mut selected = Vec::with_capacity(total);
+
+    for (index, host) in hosts.into_iter().enumerate() {
+        let position = Position { index, total };
+        let mut unmet = None;
+
+        for when in config.when.iter().chain(host.when.iter()) {
+            let met = template::check(when, &host, position)
+                .chain_err(|| format!("Unable to evaluate condition '{}' for {}", when, host.name))?;
+
+            if !met {
+                unmet = Some(when.clone());
+                break;
+            }
+        }
+
+        match unmet {
+            Some(_) if verbosity::quiet() => {},
+            Some(when) => println!("Skipped {}: condition '{}' is not met", host.name, when),
+            None => selected.push(host),
+        }
+    }
+
+    Ok(selected)
+}
+
+/// Fisher-Yates shuffle of `items`.
+fn shuffle<T>(items: &mut [T]) {
+    for index in (1..items.len()).rev() {
+        let other = (template::random_u64() % (index as u64 + 1)) as usize;
+        items.swap(index, other);
+    }
+}
+
+/// Asks a question on the terminal, or else on stdin, returning the trimmed
+/// and lowercased answer, or `None` once there is nothing left to read.
+fn ask(question: &str) -> Result<Option<String>> {
+    let _ = write!(io::stderr(), "{} ", question);
+
+    let mut answer = String::new();
+
+    let read = match File::open("/dev/tty") {
+        Ok(tty) => BufReader::new(tty).read_line(&mut answer),
+        Err(_) => io::stdin().read_line(&mut answer),
+    };
+
+    let read = read.chain_err(|| "Unable to read the answer")?;
+
+    Ok(Some(answer.trim().to_lowercase()).filter(|_| read > 0))
+}
+
+/// Asks a yes or no question, defaulting to no.
+fn confirm(question: &str) -> Result<bool> {
+    let answer = ask(&format!("{} [y/N]", question))?;
+    Ok(matches!(answer.as_deref(), Some("y") | Some("yes")))
+}
+
+/// In step mode, what to do with the next host.
+enum Step {
+    Run,
+    Skip,
+    RunAll,
+    Quit,
+}
+
+fn ask_step(host: &Host, job: &Job) -> Result<Step> {
+    for cmd in &job.cmds {
+        println!("Command for {}: {}", host.name, secrets::redact(&cmd.to_string()));
+    }
+
+    loop {
+        let step = match ask(&format!("Run on {}? [y]es, [n]o, [a]ll, [q]uit", host.name))?.as_deref() {
+            Some("y") | Some("yes") => Step::Run,
+            Some("n") | Some("no") => Step::Skip,
+            Some("a") | Some("all") => Step::RunAll,
+            Some("q") | Some("quit") | None => Step::Quit,
+            Some(_) => continue,
+        };
+
+        return Ok(step);
+    }
+}
+
+/// How a command ended along with its output, or only the former if the
+/// output was already streamed or goes to the logs.
+fn describe(output: &Output, brief: bool) -> String {
+    let status = report::exit_status(output.status);
+
+    if brief {
+        return status;
+    }
+
+    if verbosity::shows(Level::Output) {
+        let mut described = status;
+
+        for &(title, output) in &[("stdout", &output.stdout), ("stderr", &output.stderr)] {
+            let output = String::from_utf8_lossy(output);
+            described += &format!("\n--- {} ---", title);
+
+            if !output.is_empty() {
+                described += &format!("\n{}", output.strip_suffix('\n').unwrap_or(&output));
+            }
+        }
+
+        return described;
+    }
+
+    format!("{} [stdout: '{}', stderr: '{}']", status,
+        String::from_utf8_lossy(&output.stdout).trim(),
+        String::from_utf8_lossy(&output.stderr).trim())
+}
+
+/// Rendered commands of `job`, as reported, without the secrets of the
+/// config in them.
+fn commands_of(job: &Job) -> Vec<String> {
+    let steps = job.steps.iter().map(|step| format!("{}: {}", step.name, step.cmd));
+    let script = job.script.iter().map(|_| "<script>".to_owned());
+
+    job.cmds.iter().map(|cmd| cmd.to_string()).chain(steps).chain(script)
+        .map(|command| secrets::redact(&command))
+        .collect()
+}
+
+/// Prints how the job of `host` ended, returning its report.
+fn report(host: &Host, commands: Vec<String>, finished: Finished, brief: bool) -> HostReport {
+    let Finished { outcome, duration } = finished;
+    let took = duration.map(|duration| format!(" after {}", seconds(duration.as_millis() as u64))).unwrap_or_default();
+
+    let report = match outcome {
+        Outcome::Completed(output) => {
+            let (status, painted) = if output.status.success() { (HostStatus::Succeeded, Color::Green) } else { (HostStatus::Failed, Color::Red) };
+
+            if status == HostStatus::Failed || !verbosity::quiet() {
+                println!("{}: {}", color::stdout(painted, format!("Command completion on {}{}", host.name, took)), describe(&output, brief));
+            }
+
+            HostReport::new(host, commands, status).with_output(&output)
+        },
+
+        Outcome::Skipped(e) => {
+            if !verbosity::quiet() {
+                println!("Skipped {}: {}", host.name, e);
+            }
+
+            HostReport::skipped(host, commands, &e.to_string())
+        },
+
+        // counts as succeeded, so that e.g. dependent groups go on
+        Outcome::AlreadyDone(output) => {
+            if !verbosity::quiet() {
+                println!("{}, skipped the command: {}", color::stdout(Color::Green, format!("Already done on {}", host.name)), describe(&output, brief));
+            }
+
+            HostReport { reason: Some("already done".to_owned()), ..HostReport::new(host, commands, HostStatus::Succeeded).with_output(&output) }
+        },
+
+        Outcome::Failed(e) => {
+            let timed_out = matches!(*e.kind(), ErrorKind::Timeout | ErrorKind::IdleTimeout);
+            let painted = if timed_out { Color::Yellow } else { Color::Red };
+            let _ = writeln!(io::stderr(), "{}: {}", color::stderr(painted, format!("Command error on {}{}", host.name, took)), e);
+
+            HostReport { reason: Some(e.to_string()), timed_out, ..HostReport::new(host, commands, HostStatus::Failed) }
+        },
+
+        Outcome::StepFailed { step, result } => {
+            let outcome = match result {
+                Ok(output) => Outcome::Completed(output),
+                Err(e) => Outcome::Failed(e),
+            };
+
+            let report = report(host, commands, Finished { outcome, duration }, brief);
+            let _ = writeln!(io::stderr(), "{}", color::stderr(Color::Red, format!("Step {} failed on {}", step, host.name)));
+
+            HostReport { status: HostStatus::Failed, failed_step: Some(step), ..report }
+        },
+    };
+
+    report.with_duration(duration)
+}
+
+/// Opens the state of the resumed run, or else a fresh one if a state file
+/// is set.
+fn open_state(main_arg_map: &MainArgMap, config: &Config) -> Result<Option<RunState>> {
+    if let Some(ref resume_path) = main_arg_map.resume_path {
+        return RunState::load(resume_path).map(Some);
+    }
+
+    Ok(source_path(main_arg_map, &main_arg_map.state_path, &config.state_file).map(RunState::new))
+}
+
+/// Orders the groups of `groupDependsOn`, if any, by the hosts they have.
+fn group_stages(main_arg_map: &MainArgMap, config: &Config) -> Result<Option<Stages>> {
+    if config.group_depends_on.is_empty() {
+        return Ok(None);
+    }
+
+    let inventory = load_inventory(main_arg_map, config)?;
+    let mut members = BTreeMap::new();
+
+    for (group, dependencies) in &config.group_depends_on {
+        for group in iter::once(group).chain(dependencies) {
+            if !members.contains_key(group) {
+                let hosts = group_hosts(slice::from_ref(group), config, inventory.as_ref())?;
+                members.insert(group.clone(), hosts.into_iter().map(|host| host.name).collect());
+            }
+        }
+    }
+
+    Stages::new(&config.group_depends_on, members).map(Some)
+}
+
+/// Counts of how the hosts of a run ended.
+#[derive(Default)]
+struct Summary {
+    succeeded: usize,
+    failed: usize,
+
+    /// Failed hosts that timed out, counted among the failed ones too.
+    timed_out: usize,
+    skipped: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} succeeded, {} failed ({} timed out), {} skipped", self.succeeded, self.failed, self.timed_out, self.skipped)
+    }
+}
+
+/// Prints each output once, after the hosts that it is the output of.
+fn print_collated(reports: &[HostReport]) {
+    for collated in report::collate(reports) {
+        let count = collated.hosts.len();
+        println!("==> {} host{}: {}", count, if count == 1 { "" } else { "s" }, collated.hosts.join(", "));
+
+        for &(title, output) in &[("stdout", collated.stdout), ("stderr", collated.stderr)] {
+            if !output.is_empty() {
+                println!("--- {} ---\n{}", title, output.strip_suffix('\n').unwrap_or(output));
+            }
+        }
+    }
+}
+
+/// `duration_ms` in seconds, e.g. 1.250s.
+fn seconds(duration_ms: u64) -> String {
+    format!("{:.3}s", duration_ms as f64 / 1000.0)
+}
+
+/// Prints the counts of how the hosts ended, the duration of the run and of
+/// the hosts, the slowest hosts and those that failed.
+fn print_summary(summary: &Summary, duration: Duration, reports: &[HostReport]) {
+    // only the counts that are not zero stand out
+    let count = |painted: Color, count: usize| color::stdout(painted, count).enabled_if(count > 0);
+
+    println!("Summary:");
+    println!("  Succeeded: {}", count(Color::Green, summary.succeeded));
+    println!("  Failed:    {}", count(Color::Red, summary.failed - summary.timed_out));
+    println!("  Timed out: {}", count(Color::Yellow, summary.timed_out));
+    println!("  Skipped:   {}", summary.skipped);
+    println!("  Duration:  {:.3}s", duration.as_secs_f64());
+
+    if let Some(durations) = DurationStats::of(reports) {
+        println!("Host durations: min {}, median {}, p95 {}, max {}",
+            seconds(durations.min_ms), seconds(durations.median_ms), seconds(durations.p95_ms), seconds(durations.max_ms));
+
+        println!("Slowest hosts:");
+
+        for report in report::slowest(reports, SLOWEST_HOSTS) {
+            println!("  {}: {}", report.name, seconds(report.duration_ms.unwrap_or(0)));
+        }
+    }
+
+    let failed: Vec<_> = reports.iter().filter(|report| report.status == HostStatus::Failed).collect();
+
+    if !failed.is_empty() {
+        println!("Failed hosts:");
+
+        for report in failed {
+            let painted = if report.timed_out { Color::Yellow } else { Color::Red };
+            println!("  {}: {}", color::stdout(painted, &report.name), report.failure());
+        }
+    }
+}
+
+/// How the hosts of the run ended so far.
+struct Progress {
+    state: Option<RunState>,
+    stages: Option<Stages>,
 
-#[macro_use]
-extern crate serde_derive;
-extern crate serde_json;
-extern crate structopt;
+    /// Ordered groups with a host that did not succeed.
+    unsucceeded: HashSet<String>,
+    summary: Summary,
+    reports: Vec<HostReport>,
 
-#[macro_use]
-extern crate structopt_derive;
-extern crate tokio_timer;
+    /// Directory receiving the log of each host that ran.
+    log_dir: Option<PathBuf>,
+}
 
-use std::fs::File;
-use futures::Future;
-use futures_cpupool::CpuPool;
-use std::io::{self, Read, Write};
-use std::process::{self, Command, Output};
-use std::time::Duration;
-use structopt::StructOpt;
-use tokio_timer::Timer;
+impl Progress {
+    /// Records the status of the host, a state that cannot be written only
+    /// being warned about so that the run goes on.
+    fn record(&mut self, host: &Host, key: &str, report: HostReport) {
+        let status = report.status;
+        let timed_out = report.timed_out;
 
-mod errors {
-    error_chain! {
-        errors {
-            CommandLaunch {
-                description("command launch error")
-                display("command launch error")
+        events::emit(&Event::Finished {
+            host: &host.name,
+            status,
+            exit_code: report.exit_code,
+            signal: report.signal,
+            duration_ms: report.duration_ms,
+            reason: report.reason.as_deref(),
+        });
+
+        // skipped hosts have no output to keep
+        if let Some(log_dir) = self.log_dir.as_ref().filter(|_| status != HostStatus::Skipped) {
+            if let Err(e) = report::write_log(log_dir, key, &report) {
+                let _ = writeln!(io::stderr(), "Warning: {}", e);
             }
-            Timeout {
-                description("execution timeout")
-                display("execution timeout")
+        }
+
+        self.reports.push(report);
+
+        match status {
+            HostStatus::Succeeded => self.summary.succeeded += 1,
+            HostStatus::Failed => self.summary.failed += 1,
+            HostStatus::Skipped => self.summary.skipped += 1,
+        }
+
+        if timed_out {
+            self.summary.timed_out += 1;
+        }
+
+        if let Some(ref mut state) = self.state {
+            if let Err(e) = state.record(key, status) {
+                let _ = writeln!(io::stderr(), "Warning: {}", e);
             }
         }
+
+        if let Some(stages) = self.stages.as_ref().filter(|_| status != HostStatus::Succeeded) {
+            self.unsucceeded.extend(stages.groups(&host.name).map(|group| group.to_owned()));
+        }
+    }
+
+    /// Prints and records that the host is skipped for `reason`.
+    fn skip(&mut self, host: &Host, key: &str, job: &Job, reason: &str) {
+        let _hidden = meter::hide();
+        meter::finished(false);
+
+        if !verbosity::quiet() {
+            println!("Skipped {}: {}", host.name, reason);
+        }
+
+        self.record(host, key, HostReport::skipped(host, commands_of(job), reason));
+    }
+
+    /// Group that the host depends on and that did not succeed, if any.
+    fn blocker(&self, host: &Host) -> Option<String> {
+        self.stages.as_ref()
+            .and_then(|stages| stages.blocker(&host.name, &self.unsucceeded))
+            .map(|group| group.to_owned())
     }
 }
 
-use errors::*;
+/// Host of a spawned job, with its key in the state and its commands.
+type Spawned<'a> = (&'a Host, String, Vec<String>);
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Config {
-    hostnames: Vec<String>,
-    cmd_to_run: String,
-    hostname_tag: String,
-    thread_count: usize,
-    timeout_ms: u64,
+/// Lines printed by `--dry-run` for what would be run, with the decrypted
+/// secrets in them redacted.
+fn dry_run_lines(config: &Config, unresolved: &[(Host, String, Error)], hosts: &[Host], jobs: &[Job]) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for (host, _, e) in unresolved {
+        lines.push(format!("Would fail on {}: {}", host.name, e));
+    }
+
+    if let Some(ref before_all) = config.before_all {
+        lines.push(format!("Would run before all hosts: {}", before_all));
+    }
+
+    for (host, job) in hosts.iter().zip(jobs) {
+        if let Some(ref cwd) = job.cwd {
+            lines.push(format!("Would run on {} in {}", host.name, cwd));
+        }
+
+        if let Some(ref check) = job.check {
+            lines.push(format!("Would check on {}: {}", host.name, check));
+        }
+
+        if let Some(ref pre) = job.pre {
+            lines.push(format!("Would run before on {}: {}", host.name, pre));
+        }
+
+        for cmd in &job.cmds {
+            lines.push(format!("Would run on {}: {}", host.name, cmd));
+        }
+
+        if let Some(ref pipe_into) = job.pipe_into {
+            lines.push(format!("Would pipe output on {} into: {}", host.name, pipe_into));
+        }
+
+        for step in &job.steps {
+            lines.push(format!("Would run step {} on {}: {}", step.name, host.name, step.cmd));
+        }
+
+        if let Some(ref post) = job.post {
+            lines.push(format!("Would run after on {}: {}", host.name, post));
+        }
+
+        if let Some(ref script) = job.script {
+            lines.push(format!("Would run script on {}:\n{}", host.name, script.trim_end()));
+        }
+
+        if let Some(ref stdin) = job.stdin {
+            lines.push(format!("Would pipe into stdin on {}:\n{}", host.name, stdin.trim_end()));
+        }
+    }
+
+    if let Some(ref after_all) = config.after_all {
+        lines.push(format!("Would run after all hosts: {}", after_all));
+    }
+
+    lines.iter().map(|line| secrets::redact(line)).collect()
 }
 
-#[derive(StructOpt, Debug)]
-#[structopt(name = "Each Command Program", about = "Runs command on given list of hostnames.")]
-struct MainArgMap {
-    #[structopt(short = "c", long = "config", help = "Path to config file")]
-    config_path: String,
+/// Resolves the hosts afresh and runs the command on each of them.
+fn run_once(main_arg_map: &MainArgMap, config: &Config, state: Option<RunState>) -> Result<Summary> {
+    let started = Instant::now();
+    let mut hosts = select_hosts(resolve_hosts(main_arg_map, config)?, config)?;
+    let mut keys = state::host_keys(&hosts);
+    let mut unresolved = Vec::new();
+
+    // only resolves the hosts that are run on, each host that does not
+    // resolve failing without stopping the others
+    if let Some(version) = main_arg_map.resolve_ip.or(config.resolve_ip) {
+        let errors = hosts::resolve_ips(&mut hosts, version);
+        let mut resolved = (Vec::new(), Vec::new());
+
+        for ((host, key), error) in hosts.into_iter().zip(keys).zip(errors) {
+            match error {
+                Some(e) => unresolved.push((host, key, e)),
+                None => {
+                    resolved.0.push(host);
+                    resolved.1.push(key);
+                },
+            }
+        }
+
+        (hosts, keys) = resolved;
+    }
+
+    let mut unmentioned = HashSet::new();
+
+    // commands run in a directory or environment of their host are not the
+    // same everywhere
+    let varies_by_host = config.cwd.iter().chain(config.env.values())
+        .any(|value| template::mentions_host(value, &config.hostname_tag));
+
+    for host in &hosts {
+        let cmds = match host.cmd {
+            Some(ref cmd_to_run) => cmd_to_run.commands(),
+            None => config.cmd_to_run.commands().into_iter().chain(config.steps.iter().map(|step| &step.cmd)).collect(),
+        };
+
+        for cmd in cmds {
+            let mentioned = varies_by_host || cmd.parts().iter().any(|part| template::mentions_host(part, &config.hostname_tag));
+
+            if !config.host_env && !mentioned && unmentioned.insert(cmd.to_string()) {
+                let _ = writeln!(io::stderr(), "Warning: hostname tag '{}' does not appear in command '{}'", config.hostname_tag, secrets::redact(&cmd.to_string()));
+            }
+        }
+    }
+
+    // renders every command up front, so that template errors stop the run
+    // before anything is executed
+    let mut jobs = Vec::with_capacity(hosts.len());
+
+    let script = match source_path(main_arg_map, &main_arg_map.script_path, &config.script_path) {
+        Some(script_path) => Some(hosts::read_file(&script_path, "script")?),
+        None => None,
+    };
+
+    let options = template::Options {
+        tag: config.hostname_tag.clone(),
+        shell_quote: config.shell_quote,
+    };
+
+    let argv_options = template::Options { shell_quote: false, ..options.clone() };
+    let throttle = config.max_starts_per_second.map(|starts| Arc::new(Throttle::per_second(starts)));
+
+    // events carry the output lines of the commands as they arrive
+    let streamed = config.stream || events::enabled();
+    let inherited_env = inherited_env(config);
+
+    let run_as = match config.run_as {
+        Some(ref user) => Some(Arc::new(RunAs::lookup(user)?)),
+        None => None,
+    };
+
+    for (index, host) in hosts.iter().enumerate() {
+        let position = Position { index, total: hosts.len() };
+        let cmd_to_run = host.cmd.as_ref().unwrap_or(&config.cmd_to_run);
+
+        let mut job = Job {
+            timeout: host.timeout_ms.or(config.timeout_ms).map(Duration::from_millis),
+            idle_timeout: config.idle_timeout_ms.map(Duration::from_millis),
+            stream_prefix: Some(host.name.clone()).filter(|_| streamed),
+            kill_grace: Duration::from_millis(config.kill_grace_ms),
+            throttle: throttle.clone(),
+            inherited_env: inherited_env.clone(),
+            resource_limits: config.resource_limits,
+            run_as: run_as.clone(),
+            ..Job::default()
+        };
+
+        if let Some(cwd) = host.cwd.as_ref().or(config.cwd.as_ref()) {
+            let cwd = template::render(cwd, host, position, &argv_options)
+                .chain_err(|| format!("Unable to render working directory for {}", host.name))?;
+
+            job.cwd = Some(cwd);
+        }
+
+        // in host env mode the commands take the host from the environment
+        // and are run as they are
+        let render = |cmd: &Cmd| -> Result<Cmd> {
+            if config.host_env {
+                return Ok(cmd.clone());
+            }
+
+            // arguments reach the program as they are, without a shell to
+            // quote for
+            let options = match *cmd {
+                Cmd::Shell(_) => &options,
+                Cmd::Argv { .. } => &argv_options,
+            };
+
+            cmd.try_map(|part| template::render(part, host, position, options))
+                .chain_err(|| format!("Unable to render command for {}", host.name))
+        };
+
+        job.cmds = cmd_to_run.commands().into_iter().map(render).collect::<Result<_>>()?;
+        job.check = config.check_cmd.as_ref().map(render).transpose()?;
+        job.pre = config.pre_cmd.as_ref().map(render).transpose()?;
+        job.post = config.post_cmd.as_ref().map(render).transpose()?;
+        job.pipe_into = config.pipe_into.as_ref().map(render).transpose()?;
+
+        // hosts with a command of their own run it instead of the steps
+        if host.cmd.is_none() {
+            for step in &config.steps {
+                job.steps.push(exec::Step {
+                    name: step.name.clone(),
+                    cmd: render(&step.cmd).chain_err(|| format!("Unable to render step {}", step.name))?,
+                    timeout: step.timeout_ms.map(Duration::from_millis),
+                    retries: step.retries,
+                });
+            }
+        }
+
+        if config.host_env {
+            job.env = host_env(host, position);
+        }
+
+        // set after the host env variables, which they may override
+        for (key, value) in config.env.iter().filter(|&(key, _)| !host.env.contains_key(key)).chain(&host.env) {
+            let value = template::render(value, host, position, &argv_options)
+                .chain_err(|| format!("Unable to render environment variable {} for {}", key, host.name))?;
+
+            job.env.push((key.clone(), value));
+        }
+
+        // the script is pushed through the command if there is one, such as
+        // ssh, and run locally otherwise
+        if let Some(ref script) = script {
+            let script = template::render(script, host, position, &options)
+                .chain_err(|| format!("Unable to render script for {}", host.name))?;
+
+            if job.cmds.is_empty() {
+                job.script = Some(script);
+            } else {
+                job.stdin = Some(script);
+            }
+        }
+
+        if let Some(ref stdin_template) = config.stdin_template {
+            let stdin = template::render(stdin_template, host, position, &options)
+                .chain_err(|| format!("Unable to render stdin for {}", host.name))?;
+
+            job.stdin = Some(stdin);
+        }
+
+        jobs.push(job);
+    }
+
+    // leaves out the hosts that already succeeded in the resumed run
+    let mut resumed = Vec::new();
+
+    if let Some(ref state) = state {
+        let mut remaining = (Vec::new(), Vec::new(), Vec::new());
+
+        for ((host, job), key) in hosts.into_iter().zip(jobs).zip(keys) {
+            if state.succeeded(&key) {
+                let reason = format!("already succeeded in {}", state.path().display());
+
+                if !verbosity::quiet() {
+                    println!("Skipped {}: {}", host.name, reason);
+                }
+
+                resumed.push(HostReport::skipped(&host, commands_of(&job), &reason));
+            } else {
+                remaining.0.push(host);
+                remaining.1.push(job);
+                remaining.2.push(key);
+            }
+        }
+
+        (hosts, jobs, keys) = remaining;
+    }
+
+    // so that the same hosts do not always go first, within their stage as
+    // the ordering below keeps the shuffled order
+    if config.shuffle {
+        let mut shuffled: Vec<((Host, Job), String)> = hosts.into_iter().zip(jobs).zip(keys).collect();
+        shuffle(&mut shuffled);
+
+        let pairs: Vec<(Host, Job)>;
+        (pairs, keys) = shuffled.into_iter().unzip();
+        (hosts, jobs) = pairs.into_iter().unzip();
+    }
+
+    // hosts of the ordered groups run after those of the groups they depend
+    // on, each stage of them in its own batches
+    let stages = group_stages(main_arg_map, config)?;
+    let mut stage_ends = vec![hosts.len()];
+
+    if let Some(ref stages) = stages {
+        let mut ordered: Vec<((Host, Job), String)> = hosts.into_iter().zip(jobs).zip(keys).collect();
+        ordered.sort_by_key(|&((ref host, _), _)| stages.stage(&host.name));
+
+        let pairs: Vec<(Host, Job)>;
+        (pairs, keys) = ordered.into_iter().unzip();
+        (hosts, jobs) = pairs.into_iter().unzip();
+
+        stage_ends = (1..hosts.len())
+            .filter(|&index| stages.stage(&hosts[index - 1].name) != stages.stage(&hosts[index].name))
+            .chain(iter::once(hosts.len()))
+            .collect();
+    }
+
+    if main_arg_map.dry_run {
+        for line in dry_run_lines(config, &unresolved, &hosts, &jobs) {
+            println!("{}", line);
+        }
+
+        return Ok(Summary::default());
+    }
+
+    let log_dir = source_path(main_arg_map, &main_arg_map.log_dir, &config.log_dir);
+
+    if let Some(ref log_dir) = log_dir {
+        fs::create_dir_all(log_dir)
+            .chain_err(|| format!("Unable to create log directory {}", log_dir.display()))?;
+    }
+
+    if let Some(ref before_all) = config.before_all {
+        let output = run_hook(config, before_all, Vec::new(), None)
+            .chain_err(|| "Unable to run beforeAll")?;
+
+        if !output.status.success() {
+            bail!("beforeAll failed with {}, not running on any host", output.status);
+        }
+    }
+
+    // executes the command for each given hostname, in batches if set and
+    // after the canary hosts if any
+    let runner = Runner::new(config, hosts.len());
+    let canary = main_arg_map.canary.unwrap_or(0).min(stage_ends[0]);
+
+    let batch_size = match config.batch_size {
+        Some(ref batch_size) => batch_size.of(hosts.len() - canary)?,
+        None => hosts.len(),
+    };
+
+    let mut batches: Vec<&[Host]> = Vec::new();
+
+    if canary > 0 {
+        batches.push(&hosts[..canary]);
+    }
+
+    let mut start = canary;
+
+    for end in stage_ends {
+        batches.extend(hosts[start..end].chunks(batch_size.max(1)));
+        start = end;
+    }
+
+    // the canary is not counted as a batch
+    let first = if canary > 0 { 1 } else { 0 };
+
+    // the full output is left to the logs if kept, or collated
+    let brief = streamed || log_dir.is_some() || config.collate;
+
+    let mut jobs = jobs.into_iter().zip(keys);
+    let mut progress = Progress {
+        state,
+        stages,
+        unsucceeded: HashSet::new(),
+        summary: Summary { skipped: resumed.len(), ..Summary::default() },
+        reports: resumed,
+        log_dir,
+    };
+    for (host, key, e) in unresolved {
+        let report = report(&host, Vec::new(), Finished { outcome: Outcome::Failed(e), duration: None }, brief);
+        progress.record(&host, &key, report);
+    }
+
+    let mut halted = None;
+    let mut stepping = main_arg_map.step;
+
+    // the prompts of stepped hosts would be drawn over
+    if config.progress && !stepping {
+        meter::start(hosts.len());
+    }
+
+    for (index, batch) in batches.iter().enumerate() {
+        let batch_jobs: Vec<(Job, String)> = jobs.by_ref().take(batch.len()).collect();
+
+        if let Some(reason) = halted {
+            for (host, (job, key)) in batch.iter().zip(&batch_jobs) {
+                progress.skip(host, key, job, reason);
+            }
+
+            continue;
+        }
+
+        let names: Vec<&str> = batch.iter().map(|host| host.name.as_str()).collect();
+
+        let quiet = verbosity::quiet();
+
+        if !quiet && canary > 0 && index == 0 {
+            println!("Running canary: {}", names.join(", "));
+        } else if !quiet && batches.len() - first > 1 {
+            println!("Running batch {} of {}: {}", index + 1 - first, batches.len() - first, names.join(", "));
+        }
+
+        let mut running = Vec::with_capacity(batch.len());
+        let mut succeeded = true;
+
+        for ((job, key), host) in batch_jobs.into_iter().zip(batch.iter()) {
+            if let Some(reason) = halted {
+                progress.skip(host, &key, &job, reason);
+                continue;
+            }
+
+            if let Some(group) = progress.blocker(host) {
+                progress.skip(host, &key, &job, &format!("group {} did not succeed", group));
+                continue;
+            }
+
+            // stepped hosts run one at a time, each once confirmed
+            if stepping {
+                match ask_step(host, &job)? {
+                    Step::Run => {
+                        let commands = commands_of(&job);
+                        let report = report(host, commands, runner.spawn(&host.name, job).wait(), brief);
+                        succeeded &= report.status == HostStatus::Succeeded;
+                        progress.record(host, &key, report);
+                        continue;
+                    },
+                    Step::Skip => {
+                        progress.skip(host, &key, &job, "declined at the prompt");
+                        continue;
+                    },
+                    Step::RunAll => stepping = false,
+                    Step::Quit => {
+                        halted = Some("quit at the prompt");
+                        progress.skip(host, &key, &job, "quit at the prompt");
+                        continue;
+                    },
+                }
+            }
+
+            let commands = commands_of(&job);
+            running.push(((host, key, commands), runner.spawn(&host.name, job)));
+        }
+
+        let finished: Box<dyn Iterator<Item = (Spawned, Finished)>> = match config.output_order {
+            Some(OutputOrder::Completion) => Box::new(runner::wait_completed(running)),
+            Some(OutputOrder::Input) | None => Box::new(running.into_iter().map(|(tag, running)| (tag, running.wait()))),
+        };
+
+        for ((host, key, commands), finished) in finished {
+            let _hidden = meter::hide();
+            let report = report(host, commands, finished, brief);
+            succeeded &= report.status == HostStatus::Succeeded;
+            progress.record(host, &key, report);
+        }
+
+        if halted.is_some() {
+            continue;
+        }
+
+        let remaining = hosts.len() - batches[..index + 1].iter().map(|batch| batch.len()).sum::<usize>();
+
+        if canary > 0 && index == 0 && remaining > 0 {
+            if !succeeded {
+                halted = Some("the canary failed");
+            } else if !main_arg_map.yes && !confirm(&format!("Continue with the remaining {} hosts?", remaining))? {
+                halted = Some("not confirmed after the canary");
+            }
+        } else if config.batch_require_success && !succeeded {
+            halted = Some("a previous batch failed");
+        }
+    }
+
+    meter::finish();
+
+    if config.collate {
+        print_collated(&progress.reports);
+    }
+
+    let run_report = RunReport {
+        succeeded: progress.summary.succeeded,
+        failed: progress.summary.failed,
+        skipped: progress.summary.skipped,
+        durations: DurationStats::of(&progress.reports),
+        hosts: &progress.reports,
+    };
+
+    if let Some(report_json) = source_path(main_arg_map, &main_arg_map.report_json, &config.report_json) {
+        report::write_json(&report_json, &run_report)?;
+    }
+
+    if let Some(report_junit) = source_path(main_arg_map, &main_arg_map.report_junit, &config.report_junit) {
+        report::write_junit(&report_junit, &run_report)?;
+    }
+
+    if let Some(report_md) = source_path(main_arg_map, &main_arg_map.report_md, &config.report_md) {
+        report::write_markdown(&report_md, &run_report)?;
+    }
+
+    // the hosts are done either way, so a failure is only reported
+    if let Some(ref after_all) = config.after_all {
+        let summary = &progress.summary;
+
+        let env = vec![
+            ("EACH_CMD_SUCCEEDED".to_owned(), summary.succeeded.to_string()),
+            ("EACH_CMD_FAILED".to_owned(), summary.failed.to_string()),
+            ("EACH_CMD_TIMED_OUT".to_owned(), summary.timed_out.to_string()),
+            ("EACH_CMD_SKIPPED".to_owned(), summary.skipped.to_string()),
+        ];
+
+        match run_hook(config, after_all, env, Some(format!("{}\n", summary))) {
+            Ok(ref output) if output.status.success() => {},
+            Ok(output) => { let _ = writeln!(io::stderr(), "Command afterAll failed with {}", output.status); },
+            Err(e) => { let _ = writeln!(io::stderr(), "Unable to run afterAll: {}", e); },
+        }
+    }
+
+    print_summary(&progress.summary, started.elapsed(), &progress.reports);
+    Ok(progress.summary)
 }
 
-fn run_cmd(cmd: &str) -> Result<Output> {
-    if cfg!(target_os = "windows") {
-        Command::new("cmd")
-            .args(&["/C", cmd])
-            .output()
-    } else {
-        Command::new("sh")
-            .args(&["-c", cmd])
-            .output()
+/// Runs `cmd` locally, outside of any host, printing what it outputs.
+fn run_hook(config: &Config, cmd: &Cmd, env: Vec<(String, String)>, stdin: Option<String>) -> Result<Output> {
+    let job = Job {
+        cmds: vec![cmd.clone()],
+        env,
+        stdin,
+        timeout: config.timeout_ms.map(Duration::from_millis),
+        kill_grace: Duration::from_millis(config.kill_grace_ms),
+        inherited_env: inherited_env(config),
+        ..Job::default()
+    };
+
+    let output = exec::start_job(job).wait()?;
+
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    let _ = write!(io::stderr(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    Ok(output)
+}
+
+/// How the run repeats, if it does.
+enum Repeat {
+    Every(Duration),
+    Cron(Schedule),
+}
+
+/// State of a repeated run, a fresh report in the report directory if
+/// there is one.
+fn cycle_state(main_arg_map: &MainArgMap, config: &Config) -> Result<Option<RunState>> {
+    let report_dir = match source_path(main_arg_map, &main_arg_map.report_dir, &config.report_dir) {
+        Some(report_dir) => report_dir,
+        None => return open_state(main_arg_map, config),
+    };
+
+    fs::create_dir_all(&report_dir)
+        .chain_err(|| format!("Unable to create report directory {}", report_dir.display()))?;
+
+    let name = format!("run-{}.json", cron::timestamp(SystemTime::now())?);
+    Ok(Some(RunState::new(report_dir.join(name))))
+}
+
+/// When the files that `config` was loaded from were last modified, none if
+/// any of them is downloaded, as there is no telling whether that changed
+/// without downloading it again.
+fn config_changed_at(config: &Config) -> Option<Vec<SystemTime>> {
+    config.sources.iter()
+        .map(|source| if config::is_url(&source.to_string_lossy()) {
+            None
+        } else {
+            // a file gone missing is left to the reload to report
+            Some(fs::metadata(source).and_then(|metadata| metadata.modified()).unwrap_or(UNIX_EPOCH))
+        })
+        .collect()
+}
+
+/// Sleeps until `done` holds or the run is cancelled.
+fn wait_until<F: Fn() -> bool>(done: F) {
+    while !exec::cancelled() && !done() {
+        thread::sleep(WAIT_POLL_INTERVAL);
     }
-    .chain_err(|| ErrorKind::CommandLaunch)
 }
 
 fn run() -> Result<()> {
     // reads the configuration
     let main_arg_map = MainArgMap::from_args();
+    let config = load_config(&main_arg_map)?;
+
+    if config.output == Some(OutputFormat::Ndjson) {
+        events::enable();
+    }
+
+    color::enable(config.color.unwrap_or(ColorMode::Auto));
+    verbosity::set(if config.quiet { Level::Quiet } else { Level::verbose(config.verbosity) });
+
+    exec::cancel_on_signals();
+
+    let repeat = if main_arg_map.daemon {
+        match config.cron {
+            Some(ref cron) => Repeat::Cron(cron.parse()?),
+            None => bail!("Daemon mode requires a cron schedule"),
+        }
+    } else if let Some(Interval(interval)) = main_arg_map.watch {
+        Repeat::Every(interval)
+    } else {
+        let summary = run_once(&main_arg_map, &config, open_state(&main_arg_map, &config)?)?;
+
+        if let Some(kind) = exec::stopped() {
+            bail!(kind);
+        }
 
-    let config_content = {
-        let mut config_file = File::open(&main_arg_map.config_path)
-            .chain_err(|| format!("Unable to open config file at {}", main_arg_map.config_path))?;
+        let total = summary.succeeded + summary.failed;
+        let fail_on = config.fail_on.clone().unwrap_or_else(|| FailOn::Text("any".to_owned()));
 
-        let mut buf = String::new();
-        let _ = config_file.read_to_string(&mut buf)
-            .chain_err(|| "Unable to read config file into string")?;
+        if fail_on.fails(summary.failed, total)? {
+            bail!(ErrorKind::HostsFailed(summary.failed, total));
+        }
 
-        buf
+        return Ok(());
     };
-    
-    let config: Config = serde_json::from_str(&config_content)
-        .chain_err(|| "Unable to parse config content into structure!")?;
-
-    // executes the command for each given hostname
-    let pool = CpuPool::new(config.thread_count);
-    let timeout = Duration::from_millis(config.timeout_ms);
-
-    let exec_futs: Vec<_> = config.hostnames.iter()
-        .map(|hostname| {
-            let hostname = hostname.to_owned();
-            let cmd_to_run = config.cmd_to_run.replace(&config.hostname_tag, &hostname);
-
-            // timeout + action
-            let timer = Timer::default();
-
-            let action_fut = pool.spawn_fn(move || {
-                println!("Running command: {}", cmd_to_run);
-                run_cmd(&cmd_to_run)
-            });
-
-            timer.sleep(timeout)
-                .then(|_| bail!(ErrorKind::Timeout))
-                .select(action_fut)
-                .map(|(win, _)| win)
-        })
-        .collect();
 
-    let stderr = &mut io::stderr();
+    // repeats the run until cancelled, each cycle with the config reloaded
+    // once it changed and the hosts resolved afresh
+    let mut config = config;
+    let mut changed_at = config_changed_at(&config);
 
-    for exec_fut in exec_futs.into_iter() {
-        match exec_fut.wait() {
-            Ok(output) => {
-                println!("Command completion: [stdout: '{}', stderr: '{}']",
-                    String::from_utf8_lossy(&output.stdout).trim(),
-                    String::from_utf8_lossy(&output.stderr).trim());
-            },
+    for cycle in 1.. {
+        if let Repeat::Cron(ref schedule) = repeat {
+            let next = schedule.next_after(SystemTime::now())?;
+            wait_until(|| SystemTime::now() >= next);
+
+            if exec::cancelled() {
+                break;
+            }
+        }
+
+        let started = Instant::now();
+
+        if cycle > 1 && main_arg_map.config_path.is_some() {
+            let now_changed_at = config_changed_at(&config);
+
+            if now_changed_at.is_none() || now_changed_at != changed_at {
+                // a config broken while editing it leaves the runs as they were
+                match load_config(&main_arg_map) {
+                    Ok(reloaded) => {
+                        changed_at = config_changed_at(&reloaded);
+                        config = reloaded;
+                    },
+                    Err(e) => {
+                        changed_at = now_changed_at;
+                        let _ = writeln!(io::stderr(), "Cycle {} keeps the last config, unable to reload it: {}", cycle, e);
+                    },
+                }
+            }
+        }
 
-            Err((e, _)) => {
-                let _ = writeln!(stderr, "Command error: {}", e);
+        let summary = cycle_state(&main_arg_map, &config)
+            .and_then(|state| run_once(&main_arg_map, &config, state));
+
+        match summary {
+            Ok(summary) => println!("Cycle {}: {}", cycle, summary),
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "Cycle {} failed: {}", cycle, e);
             },
         }
+
+        // a failure only stops the rest of its own cycle
+        exec::reset();
+
+        if let Repeat::Every(interval) = repeat {
+            wait_until(|| started.elapsed() >= interval);
+        }
+
+        if exec::cancelled() {
+            break;
+        }
     }
 
-    Ok(())
+    bail!(ErrorKind::Cancelled)
 }
 
 fn main() {
-    match run() {
+    let result = run();
+    meter::finish();
+
+    match result {
         Ok(_) => {
-            println!("Program completed!");
+            if !verbosity::quiet() {
+                println!("Program completed!");
+            }
+
             process::exit(0)
         },
         Err(ref e) => {
@@ -151,3 +1796,33 @@ fn main() {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dry_run_lines_redact_secrets() {
+        secrets::remember("hunter2");
+
+        let config: Config = serde_json::from_str(r#"{"beforeAll": "login hunter2", "afterAll": "logout hunter2"}"#).unwrap();
+        let job = Job {
+            cmds: vec![Cmd::Shell("deploy --token hunter2".to_owned())],
+            check: Some(Cmd::Shell("check hunter2".to_owned())),
+            pre: Some(Cmd::Shell("pre hunter2".to_owned())),
+            post: Some(Cmd::Shell("post hunter2".to_owned())),
+            pipe_into: Some(Cmd::Shell("pipe hunter2".to_owned())),
+            steps: vec![exec::Step { name: "migrate".to_owned(), cmd: Cmd::Shell("migrate hunter2".to_owned()), timeout: None, retries: None }],
+            cwd: Some("/srv/hunter2".to_owned()),
+            script: Some("echo hunter2\n".to_owned()),
+            stdin: Some("hunter2\n".to_owned()),
+            ..Job::default()
+        };
+
+        let lines = dry_run_lines(&config, &[], &[Host::new("web-1")], &[job]);
+
+        assert_eq!(lines.len(), 11);
+        assert!(lines.iter().all(|line| !line.contains("hunter2")), "{:?}", lines);
+        assert!(lines.contains(&"Would run on web-1: deploy --token ***".to_owned()));
+    }
+}
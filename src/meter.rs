@@ -0,0 +1,173 @@
+//! Progress of the run shown on stderr while the commands run, redrawn in
+//! place on a terminal and printed as a line now and then otherwise.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the meter is redrawn on a terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often a line of progress is printed when stderr is not a terminal.
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Meter of the current run, only set while one is shown.
+static METER: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Whether the meter is on screen, held while drawing or printing over it.
+static DRAWN: Mutex<bool> = Mutex::new(false);
+
+struct Meter {
+    total: usize,
+    finished: AtomicUsize,
+    failed: AtomicUsize,
+    started: Instant,
+
+    /// Whether the meter is redrawn in place, stderr being a terminal.
+    live: bool,
+    done: AtomicBool,
+}
+
+struct Running {
+    meter: Arc<Meter>,
+    drawer: JoinHandle<()>,
+}
+
+/// Leaves the meter off the screen while held, so that lines printed in the
+/// meantime do not mix with it.
+pub struct Hidden {
+    _drawn: MutexGuard<'static, bool>,
+}
+
+impl Meter {
+    fn draw(&self) {
+        let finished = self.finished.load(Ordering::SeqCst);
+        let failed = self.failed.load(Ordering::SeqCst);
+
+        // the hosts left take as long as the finished ones did on average
+        let eta = if finished > 0 && finished < self.total {
+            let left = (self.total - finished) as f64 / finished as f64;
+            format_duration(Duration::from_secs_f64(self.started.elapsed().as_secs_f64() * left))
+        } else {
+            "-".to_owned()
+        };
+
+        let text = format!("[{}/{}] {} failed, ETA {}", finished, self.total, failed, eta);
+        let mut drawn = drawn();
+        let stderr = &mut io::stderr();
+
+        if self.live {
+            let _ = write!(stderr, "\r\x1b[K{}", text);
+            *drawn = true;
+        } else {
+            let _ = writeln!(stderr, "Progress: {}", text);
+        }
+
+        let _ = stderr.flush();
+    }
+}
+
+/// Starts showing the progress of a run on `total` hosts.
+pub fn start(total: usize) {
+    let meter = Arc::new(Meter {
+        total,
+        finished: AtomicUsize::new(0),
+        failed: AtomicUsize::new(0),
+        started: Instant::now(),
+        live: is_terminal(),
+        done: AtomicBool::new(false),
+    });
+
+    let drawer = {
+        let meter = meter.clone();
+
+        thread::spawn(move || {
+            let interval = if meter.live { REDRAW_INTERVAL } else { LINE_INTERVAL };
+            let mut next = Instant::now() + interval;
+
+            while !meter.done.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(50));
+
+                if Instant::now() >= next {
+                    meter.draw();
+                    next = Instant::now() + interval;
+                }
+            }
+        })
+    };
+
+    finish();
+    *lock() = Some(Running { meter, drawer });
+}
+
+/// Stops showing the progress, taking the meter off the screen.
+pub fn finish() {
+    let running = match lock().take() {
+        Some(running) => running,
+        None => return,
+    };
+
+    running.meter.done.store(true, Ordering::SeqCst);
+    let _ = running.drawer.join();
+
+    clear(&mut drawn());
+}
+
+/// Counts a host as finished, failed or not.
+pub fn finished(failed: bool) {
+    if let Some(ref running) = *lock() {
+        running.meter.finished.fetch_add(1, Ordering::SeqCst);
+
+        if failed {
+            running.meter.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Takes the meter off the screen until the returned guard is dropped.
+pub fn hide() -> Hidden {
+    let mut drawn = drawn();
+    clear(&mut drawn);
+    Hidden { _drawn: drawn }
+}
+
+fn clear(drawn: &mut bool) {
+    if *drawn {
+        let stderr = &mut io::stderr();
+        let _ = write!(stderr, "\r\x1b[K");
+        let _ = stderr.flush();
+        *drawn = false;
+    }
+}
+
+fn lock() -> MutexGuard<'static, Option<Running>> {
+    METER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn drawn() -> MutexGuard<'static, bool> {
+    DRAWN.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// `duration` rounded to the second, e.g. 1h02m, 3m20s or 12s.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m{:02}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h{:02}m", hours, minutes),
+    }
+}
+
+#[cfg(unix)]
+fn is_terminal() -> bool {
+    use libc;
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+fn is_terminal() -> bool {
+    false
+}
@@ -0,0 +1,467 @@
+//! Watches over every running command from a single thread, feeding their
+//! input, collecting their output and stopping them once past their time
+//! limits or on cancellation, so that waiting on a command takes no thread
+//! of its own.
+
+use errors::*;
+use events::{self, Event};
+use exec;
+use futures::sync::oneshot;
+use std::process::{Child, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use meter;
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::process::{ChildStderr, ChildStdin, ChildStdout, ExitStatus};
+#[cfg(unix)]
+use std::sync::OnceLock;
+#[cfg(unix)]
+use std::sync::mpsc;
+
+/// Interval at which running commands are checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// When and how a command is stopped.
+pub struct Limits {
+    /// Whether the command leads a process group, which is stopped with it.
+    pub own_group: bool,
+    pub timeout_at: Option<Instant>,
+
+    /// Time the command may go without output, only watched on Unix.
+    pub idle_timeout: Option<Duration>,
+    pub deadline: Option<Instant>,
+
+    /// Whether the command keeps running when the run is cancelled or
+    /// aborted.
+    pub ignore_stop: bool,
+
+    /// Time given to the command to exit after SIGTERM before it is killed.
+    pub kill_grace: Duration,
+}
+
+/// Output of the command once it exited and closed its output pipes, or
+/// the error it was stopped with.
+pub type Done = oneshot::Receiver<Result<Output>>;
+
+impl Limits {
+    /// Error that the command is to be stopped with, if any, given when it
+    /// last output something.
+    fn expired(&self, last_output: Instant) -> Option<ErrorKind> {
+        let now = Instant::now();
+
+        if self.timeout_at.is_some_and(|timeout_at| now >= timeout_at) {
+            Some(ErrorKind::Timeout)
+        } else if self.idle_timeout.is_some_and(|idle_timeout| now >= last_output + idle_timeout) {
+            Some(ErrorKind::IdleTimeout)
+        } else if self.deadline.is_some_and(|deadline| now >= deadline) {
+            Some(ErrorKind::DeadlineExceeded)
+        } else if self.ignore_stop {
+            None
+        } else {
+            exec::interrupted()
+        }
+    }
+}
+
+#[cfg(unix)]
+struct Watched {
+    child: Child,
+    limits: Limits,
+
+    stdin: Option<ChildStdin>,
+    input: Vec<u8>,
+    written: usize,
+
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+    out: Vec<u8>,
+    err: Vec<u8>,
+
+    status: Option<ExitStatus>,
+    last_output: Instant,
+    streamed: Option<Streamed>,
+
+    /// Why the command is being stopped, and when it gets killed.
+    stopping: Option<(ErrorKind, Instant)>,
+    done: oneshot::Sender<Result<Output>>,
+}
+
+/// Output lines printed as they arrive, the last one of each pipe waiting
+/// for its end.
+#[cfg(unix)]
+struct Streamed {
+    prefix: String,
+    out_line: Vec<u8>,
+    err_line: Vec<u8>,
+}
+
+/// Hands the spawned `child` over to the watching thread, which writes
+/// `input` into its stdin and collects its stdout and stderr, also printing
+/// them line by line after `[prefix]` if set.
+#[cfg(unix)]
+pub fn watch(mut child: Child, input: Option<Vec<u8>>, prefix: Option<String>, limits: Limits) -> Done {
+    static WATCHER: OnceLock<mpsc::Sender<Watched>> = OnceLock::new();
+
+    let (done, receiver) = oneshot::channel();
+
+    let watched = Watched {
+        stdin: child.stdin.take(),
+        input: input.unwrap_or_default(),
+        written: 0,
+        stdout: child.stdout.take(),
+        stderr: child.stderr.take(),
+        out: Vec::new(),
+        err: Vec::new(),
+        status: None,
+        last_output: Instant::now(),
+        streamed: prefix.map(|prefix| Streamed { prefix, out_line: Vec::new(), err_line: Vec::new() }),
+        stopping: None,
+        child,
+        limits,
+        done,
+    };
+
+    for fd in watched.fds() {
+        set_nonblocking(fd);
+    }
+
+    let watcher = WATCHER.get_or_init(|| {
+        let (sender, commands) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("each_cmd-watcher".to_owned())
+            .spawn(move || run_watcher(commands))
+            .expect("Unable to start the command watcher thread");
+
+        sender
+    });
+
+    // the receiver only ends up cancelled if the watcher is gone
+    let _ = watcher.send(watched);
+    receiver
+}
+
+#[cfg(unix)]
+fn run_watcher(commands: mpsc::Receiver<Watched>) {
+    let mut watched: Vec<Watched> = Vec::new();
+
+    loop {
+        // sleeps until there is something to watch
+        if watched.is_empty() {
+            match commands.recv() {
+                Ok(command) => watched.push(command),
+                Err(_) => return,
+            }
+        }
+
+        watched.extend(commands.try_iter());
+        wait_for_pipes(&watched);
+
+        let mut index = 0;
+
+        while index < watched.len() {
+            match watched[index].step() {
+                Some(result) => {
+                    let mut finished = watched.swap_remove(index);
+
+                    // prints the rest of the output before the result is
+                    // reported
+                    finished.streamed = None;
+                    let _ = finished.done.send(result);
+                },
+                None => index += 1,
+            }
+        }
+    }
+}
+
+/// Waits until a pipe is ready, for one poll interval at most.
+#[cfg(unix)]
+fn wait_for_pipes(watched: &[Watched]) {
+    let mut fds: Vec<libc::pollfd> = Vec::new();
+
+    for command in watched {
+        if let Some(ref stdin) = command.stdin {
+            fds.push(libc::pollfd { fd: stdin.as_raw_fd(), events: libc::POLLOUT, revents: 0 });
+        }
+
+        for fd in command.stdout.iter().map(AsRawFd::as_raw_fd).chain(command.stderr.iter().map(AsRawFd::as_raw_fd)) {
+            fds.push(libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+        }
+    }
+
+    unsafe {
+        libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_INTERVAL.as_millis() as libc::c_int);
+    }
+}
+
+#[cfg(unix)]
+fn set_nonblocking(fd: libc::c_int) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
+
+#[cfg(unix)]
+impl Watched {
+    fn fds(&self) -> Vec<libc::c_int> {
+        let stdin = self.stdin.iter().map(AsRawFd::as_raw_fd);
+        let stdout = self.stdout.iter().map(AsRawFd::as_raw_fd);
+        let stderr = self.stderr.iter().map(AsRawFd::as_raw_fd);
+
+        stdin.chain(stdout).chain(stderr).collect()
+    }
+
+    /// Moves the pipes along and checks on the command, returning how it
+    /// ended once it did.
+    fn step(&mut self) -> Option<Result<Output>> {
+        self.write_input();
+
+        let (out_start, err_start) = (self.out.len(), self.err.len());
+        let read = read_available(&mut self.stdout, &mut self.out) | read_available(&mut self.stderr, &mut self.err);
+
+        if read {
+            self.last_output = Instant::now();
+        }
+
+        if let Some(ref mut streamed) = self.streamed {
+            streamed.print(&self.out[out_start..], &self.err[err_start..]);
+        }
+
+        if self.status.is_none() {
+            match self.child.try_wait() {
+                Ok(status) => self.status = status,
+                Err(e) => return Some(Err(e).chain_err(|| "Unable to wait for command")),
+            }
+        }
+
+        // commands may exit without reading their input, which is not an
+        // error
+        if self.status.is_some() {
+            self.stdin = None;
+        }
+
+        if let Some((_, kill_at)) = self.stopping {
+            if self.status.is_none() && Instant::now() < kill_at {
+                return None;
+            }
+
+            // also gets rid of whatever is left of the group once its
+            // leader exited, a lone command only while it runs
+            self.signal(libc::SIGKILL);
+            let _ = self.child.wait();
+
+            return self.stopping.take().map(|(kind, _)| Err(kind.into()));
+        }
+
+        if let (Some(status), None, None) = (self.status, self.stdout.as_ref(), self.stderr.as_ref()) {
+            return Some(Ok(Output {
+                status,
+                stdout: mem::take(&mut self.out),
+                stderr: mem::take(&mut self.err),
+            }));
+        }
+
+        if let Some(kind) = self.limits.expired(self.last_output) {
+            if let (Some(streamed), ErrorKind::Timeout | ErrorKind::IdleTimeout) = (self.streamed.as_ref(), &kind) {
+                events::emit(&Event::TimedOut { host: &streamed.prefix, reason: kind.to_string() });
+            }
+
+            self.signal(libc::SIGTERM);
+            self.stopping = Some((kind, Instant::now() + self.limits.kill_grace));
+        }
+
+        None
+    }
+
+    fn write_input(&mut self) {
+        let closed = match self.stdin {
+            Some(ref mut stdin) => loop {
+                if self.written == self.input.len() {
+                    break true;
+                }
+
+                match stdin.write(&self.input[self.written..]) {
+                    Ok(count) => self.written += count,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break true,
+                }
+            },
+            None => false,
+        };
+
+        // closing stdin lets the command see the end of its input
+        if closed {
+            self.stdin = None;
+        }
+    }
+
+    /// Sends `signal` to the whole process group if the command leads one.
+    /// A command of no group of its own is only signalled until it was
+    /// waited for, after which its pid may belong to another process.
+    fn signal(&self, signal: libc::c_int) {
+        let pid = self.child.id() as libc::pid_t;
+
+        let target = match (self.limits.own_group, self.status) {
+            (true, _) => -pid,
+            (false, None) => pid,
+            (false, Some(_)) => return,
+        };
+
+        unsafe {
+            libc::kill(target, signal);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Streamed {
+    fn print(&mut self, out: &[u8], err: &[u8]) {
+        print_lines(&self.prefix, &mut self.out_line, out, false);
+        print_lines(&self.prefix, &mut self.err_line, err, true);
+    }
+}
+
+/// Prints the whole lines of `line` once `data` is added to it, keeping the
+/// rest in it. Lines are printed after `[prefix]`, or as events of the host
+/// named by `prefix` if they are enabled.
+#[cfg(unix)]
+fn print_lines(prefix: &str, line: &mut Vec<u8>, data: &[u8], stderr: bool) {
+    line.extend_from_slice(data);
+    let _hidden = meter::hide();
+
+    let mut start = 0;
+
+    while let Some(end) = line[start..].iter().position(|&byte| byte == b'\n') {
+        let whole = &line[start..start + end + 1];
+        start += end + 1;
+
+        if events::enabled() {
+            let text = String::from_utf8_lossy(whole);
+            let text = text.trim_end_matches(['\r', '\n']);
+
+            events::emit(&if stderr {
+                Event::Stderr { host: prefix, line: text }
+            } else {
+                Event::Stdout { host: prefix, line: text }
+            });
+        } else if stderr {
+            let mut writer = io::stderr();
+            let _ = write!(writer, "[{}] ", prefix);
+            let _ = writer.write_all(whole);
+        } else {
+            let mut writer = io::stdout();
+            let _ = write!(writer, "[{}] ", prefix);
+            let _ = writer.write_all(whole);
+        }
+    }
+
+    line.drain(..start);
+}
+
+/// Prints the last lines, which did not end, once the command is done.
+#[cfg(unix)]
+impl Drop for Streamed {
+    fn drop(&mut self) {
+        if !self.out_line.is_empty() {
+            print_lines(&self.prefix, &mut self.out_line, b"\n", false);
+        }
+
+        if !self.err_line.is_empty() {
+            print_lines(&self.prefix, &mut self.err_line, b"\n", true);
+        }
+    }
+}
+
+/// Reads what is available from `pipe` into `buffer`, dropping the pipe once
+/// it is closed. Returns whether anything was read.
+#[cfg(unix)]
+fn read_available<R: Read>(pipe: &mut Option<R>, buffer: &mut Vec<u8>) -> bool {
+    let mut chunk = [0; 8192];
+    let start = buffer.len();
+
+    let closed = match *pipe {
+        Some(ref mut reader) => loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => break true,
+                Ok(count) => buffer.extend_from_slice(&chunk[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break true,
+            }
+        },
+        None => false,
+    };
+
+    if closed {
+        *pipe = None;
+    }
+
+    buffer.len() > start
+}
+
+/// Waits on `child` from a thread of its own, where pipes cannot be polled
+/// and the output is not streamed.
+#[cfg(not(unix))]
+pub fn watch(mut child: Child, input: Option<Vec<u8>>, _prefix: Option<String>, limits: Limits) -> Done {
+    use std::io::{Read, Write};
+
+    let (done, receiver) = oneshot::channel();
+
+    thread::spawn(move || {
+        // writes and reads from other threads, so that a command filling up
+        // its output pipes before reading all of its input cannot deadlock
+        if let Some(mut stdin) = child.stdin.take() {
+            let input = input.unwrap_or_default();
+            thread::spawn(move || stdin.write_all(&input));
+        }
+
+        let read = |pipe: Option<Box<dyn Read + Send>>| pipe.map(|mut pipe| thread::spawn(move || {
+            let mut content = Vec::new();
+            let _ = pipe.read_to_end(&mut content);
+            content
+        }));
+
+        let stdout = read(child.stdout.take().map(|pipe| Box::new(pipe) as Box<dyn Read + Send>));
+        let stderr = read(child.stderr.take().map(|pipe| Box::new(pipe) as Box<dyn Read + Send>));
+
+        let result = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    let collect = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+                        reader.and_then(|reader| reader.join().ok()).unwrap_or_default()
+                    };
+
+                    break Ok(Output { status, stdout: collect(stdout), stderr: collect(stderr) });
+                },
+                Ok(None) => {},
+                Err(e) => break Err(e).chain_err(|| "Unable to wait for command"),
+            }
+
+            // no output is seen before the command exits
+            if let Some(kind) = limits.expired(Instant::now()) {
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(kind.into());
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        let _ = done.send(result);
+    });
+
+    receiver
+}
@@ -0,0 +1,355 @@
+//! Minimal backtracking regular expressions, enough for selecting hosts by
+//! name.
+//!
+//! Supports literals, `.`, character classes (`[a-z]`, `[^0-9]`), the `\d`,
+//! `\w` and `\s` shorthands, groups, alternation with `|`, the `^` and `$`
+//! anchors and the `*`, `+`, `?` and `{n,m}` quantifiers.
+
+use errors::*;
+
+#[derive(Debug)]
+pub struct Regex {
+    alternatives: Vec<Vec<Node>>,
+}
+
+#[derive(Debug)]
+struct Node {
+    atom: Atom,
+    min: usize,
+    max: usize,
+}
+
+#[derive(Debug)]
+enum Atom {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Group(Vec<Vec<Node>>),
+    Start,
+    End,
+}
+
+#[derive(Debug)]
+enum ClassItem {
+    Range(char, char),
+    Digit,
+    Word,
+    Space,
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(from, to) => from <= c && c <= to,
+            ClassItem::Digit => c.is_ascii_digit(),
+            ClassItem::Word => c.is_alphanumeric() || c == '_',
+            ClassItem::Space => c.is_whitespace(),
+        }
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0 };
+
+        let alternatives = parser.parse_alternatives()
+            .chain_err(|| format!("Invalid regular expression '{}'", pattern))?;
+
+        if parser.pos < parser.chars.len() {
+            bail!("Invalid regular expression '{}': unmatched ')'", pattern);
+        }
+
+        Ok(Regex { alternatives })
+    }
+
+    /// Checks whether the pattern matches anywhere in `text`.
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+
+        (0..text.len() + 1).any(|start| {
+            match_alternatives(&self.alternatives, &text, start, &mut |_| true)
+        })
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            },
+            None => bail!("unexpected end of pattern"),
+        }
+    }
+
+    fn parse_alternatives(&mut self) -> Result<Vec<Vec<Node>>> {
+        let mut alternatives = vec![self.parse_sequence()?];
+
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.parse_sequence()?);
+        }
+
+        Ok(alternatives)
+    }
+
+    fn parse_sequence(&mut self) -> Result<Vec<Node>> {
+        let mut nodes = Vec::new();
+
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+
+            let atom = self.parse_atom()?;
+            let (min, max) = self.parse_quantifier()?;
+
+            if min != 1 || max != 1 {
+                if let Atom::Start | Atom::End = atom {
+                    bail!("anchor at position {} cannot be repeated", self.pos);
+                }
+            }
+
+            nodes.push(Node { atom, min, max });
+        }
+
+        Ok(nodes)
+    }
+
+    fn parse_atom(&mut self) -> Result<Atom> {
+        let atom = match self.next()? {
+            '.' => Atom::Any,
+            '^' => Atom::Start,
+            '$' => Atom::End,
+            '(' => {
+                // non-capturing groups mean the same here
+                if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                }
+
+                let alternatives = self.parse_alternatives()?;
+
+                if self.peek() != Some(')') {
+                    bail!("unclosed group");
+                }
+
+                self.pos += 1;
+                Atom::Group(alternatives)
+            },
+            '[' => self.parse_class()?,
+            '\\' => match self.parse_escape()? {
+                ClassItem::Range(from, _) => Atom::Char(from),
+                item => Atom::Class(vec![item], false),
+            },
+            c @ '*' | c @ '+' | c @ '?' => bail!("nothing to repeat before '{}' at position {}", c, self.pos),
+            c => Atom::Char(c),
+        };
+
+        Ok(atom)
+    }
+
+    fn parse_escape(&mut self) -> Result<ClassItem> {
+        let item = match self.next()? {
+            'd' => ClassItem::Digit,
+            'w' => ClassItem::Word,
+            's' => ClassItem::Space,
+            'n' => ClassItem::Range('\n', '\n'),
+            't' => ClassItem::Range('\t', '\t'),
+            c => ClassItem::Range(c, c),
+        };
+
+        Ok(item)
+    }
+
+    fn parse_class(&mut self) -> Result<Atom> {
+        let negated = self.peek() == Some('^');
+
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut items = Vec::new();
+
+        loop {
+            let c = match self.next() {
+                Ok(c) => c,
+                Err(_) => bail!("unclosed character class"),
+            };
+
+            // a leading ']' is taken literally
+            if c == ']' && !items.is_empty() {
+                break;
+            }
+
+            let item = if c == '\\' {
+                self.parse_escape()?
+            } else {
+                ClassItem::Range(c, c)
+            };
+
+            let is_range = self.peek() == Some('-')
+                && self.chars.get(self.pos + 1).is_some_and(|&c| c != ']');
+
+            match item {
+                ClassItem::Range(from, _) if is_range => {
+                    self.pos += 1;
+
+                    let to = match self.next()? {
+                        '\\' => match self.parse_escape()? {
+                            ClassItem::Range(to, _) => to,
+                            _ => bail!("invalid range end in character class"),
+                        },
+                        to => to,
+                    };
+
+                    if to < from {
+                        bail!("range '{}-{}' is out of order", from, to);
+                    }
+
+                    items.push(ClassItem::Range(from, to));
+                },
+                item => items.push(item),
+            }
+        }
+
+        Ok(Atom::Class(items, negated))
+    }
+
+    fn parse_quantifier(&mut self) -> Result<(usize, usize)> {
+        let bounds = match self.peek() {
+            Some('*') => (0, usize::MAX),
+            Some('+') => (1, usize::MAX),
+            Some('?') => (0, 1),
+            Some('{') => return self.parse_bounds(),
+            _ => return Ok((1, 1)),
+        };
+
+        self.pos += 1;
+        self.skip_lazy();
+        Ok(bounds)
+    }
+
+    fn parse_bounds(&mut self) -> Result<(usize, usize)> {
+        let close = match self.chars[self.pos..].iter().position(|&c| c == '}') {
+            Some(close) => self.pos + close,
+            None => bail!("unclosed repetition bounds"),
+        };
+
+        let bounds: String = self.chars[self.pos + 1..close].iter().collect();
+        let mut parts = bounds.splitn(2, ',');
+
+        let parse = |part: &str| part.trim().parse::<usize>()
+            .chain_err(|| format!("invalid repetition bounds '{{{}}}'", bounds));
+
+        let min = parse(parts.next().unwrap_or(""))?;
+
+        let max = match parts.next() {
+            None => min,
+            Some(max) if max.trim().is_empty() => usize::MAX,
+            Some(max) => parse(max)?,
+        };
+
+        if max < min {
+            bail!("invalid repetition bounds '{{{}}}'", bounds);
+        }
+
+        self.pos = close + 1;
+        self.skip_lazy();
+        Ok((min, max))
+    }
+
+    /// Laziness makes no difference when only checking for a match.
+    fn skip_lazy(&mut self) {
+        if self.peek() == Some('?') {
+            self.pos += 1;
+        }
+    }
+}
+
+fn match_alternatives(alternatives: &[Vec<Node>], text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    alternatives.iter().any(|nodes| match_sequence(nodes, text, pos, k))
+}
+
+fn match_sequence(nodes: &[Node], text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    match nodes.split_first() {
+        None => k(pos),
+        Some((node, rest)) => match_repeat(node, rest, 0, text, pos, k),
+    }
+}
+
+/// Matches `node` greedily, having already matched it `count` times, and
+/// then the `rest` of the sequence.
+fn match_repeat(node: &Node, rest: &[Node], count: usize, text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    if count < node.max {
+        let matched = match_atom(&node.atom, text, pos, &mut |next| {
+            // stops repeating matches of nothing, which would never end
+            (next != pos || count < node.min) && match_repeat(node, rest, count + 1, text, next, k)
+        });
+
+        if matched {
+            return true;
+        }
+    }
+
+    count >= node.min && match_sequence(rest, text, pos, k)
+}
+
+fn match_atom(atom: &Atom, text: &[char], pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    match *atom {
+        Atom::Char(c) => text.get(pos) == Some(&c) && k(pos + 1),
+        Atom::Any => pos < text.len() && k(pos + 1),
+        Atom::Class(ref items, negated) => match text.get(pos) {
+            Some(&c) => items.iter().any(|item| item.matches(c)) != negated && k(pos + 1),
+            None => false,
+        },
+        Atom::Group(ref alternatives) => match_alternatives(alternatives, text, pos, k),
+        Atom::Start => pos == 0 && k(pos),
+        Atom::End => pos == text.len() && k(pos),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn matches_anywhere_unless_anchored() {
+        assert!(matches("web", "east-web-1"));
+        assert!(matches("^web-\\d+$", "web-12"));
+        assert!(!matches("^web-\\d+$", "web-12a"));
+        assert!(!matches("^web", "east-web"));
+    }
+
+    #[test]
+    fn matches_classes_groups_and_quantifiers() {
+        assert!(matches("^[a-c][^0-9]\\w\\s.$", "bx_ !"));
+        assert!(!matches("^[a-c]", "d"));
+        assert!(matches("^(web|db)-(eu|us)$", "db-us"));
+        assert!(!matches("^(web|db)-(eu|us)$", "db-asia"));
+        assert!(matches("^a{2,3}b?c*d+$", "aaacccd"));
+        assert!(!matches("^a{2,3}$", "aaaa"));
+        assert!(matches("^x\\.y$", "x.y"));
+        assert!(!matches("^x\\.y$", "xzy"));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        for pattern in &["(web", "web)", "[a-", "a{2", "*a", "\\"] {
+            assert!(Regex::new(pattern).is_err(), "{:?} should not compile", pattern);
+        }
+    }
+}
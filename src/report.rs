@@ -0,0 +1,460 @@
+//! Report of how each host of a run ended, written for other tools to read.
+
+use errors::*;
+use hosts::Host;
+use serde_json;
+use state::HostStatus;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::time::Duration;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HostReport {
+    pub name: String,
+
+    /// Rendered commands of the host.
+    pub commands: Vec<String>,
+    pub status: HostStatus,
+
+    /// Absent if the command did not exit by itself, e.g. when killed.
+    pub exit_code: Option<i32>,
+
+    /// Signal that killed the command, if one did.
+    pub signal: Option<i32>,
+
+    /// Whether the command was killed for running or staying silent too
+    /// long.
+    pub timed_out: bool,
+    pub duration_ms: Option<u64>,
+    pub stdout: String,
+    pub stderr: String,
+
+    /// Why the host failed without an exit code, or was skipped.
+    pub reason: Option<String>,
+    pub failed_step: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunReport<'a> {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+
+    /// Absent if no host ran.
+    pub durations: Option<DurationStats>,
+    pub hosts: &'a [HostReport],
+}
+
+/// Output shared by hosts, byte for byte.
+pub struct Collated<'a> {
+    pub hosts: Vec<&'a str>,
+    pub stdout: &'a str,
+    pub stderr: &'a str,
+}
+
+/// How long the hosts that ran took, to spot the stragglers.
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DurationStats {
+    pub min_ms: u64,
+    pub median_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+impl HostReport {
+    pub fn new(host: &Host, commands: Vec<String>, status: HostStatus) -> HostReport {
+        HostReport {
+            name: host.name.clone(),
+            commands,
+            status,
+            exit_code: None,
+            signal: None,
+            timed_out: false,
+            duration_ms: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            reason: None,
+            failed_step: None,
+        }
+    }
+
+    /// Report of a host that did not run.
+    pub fn skipped(host: &Host, commands: Vec<String>, reason: &str) -> HostReport {
+        HostReport { reason: Some(reason.to_owned()), ..HostReport::new(host, commands, HostStatus::Skipped) }
+    }
+
+    pub fn with_output(mut self, output: &Output) -> HostReport {
+        self.exit_code = output.status.code();
+        self.signal = signal(output.status);
+        self.stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        self.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Option<Duration>) -> HostReport {
+        self.duration_ms = duration.map(|duration| duration.as_millis() as u64);
+        self
+    }
+
+    /// Why the host did not succeed, along with the step that failed if any.
+    pub fn failure(&self) -> String {
+        let message = match self.reason {
+            Some(ref reason) => reason.clone(),
+            None => describe_exit(self.exit_code, self.signal).unwrap_or_else(|| "failed".to_owned()),
+        };
+
+        match self.failed_step {
+            Some(ref step) => format!("step {}: {}", step, message),
+            None => message,
+        }
+    }
+}
+
+impl DurationStats {
+    /// Statistics of the durations of `hosts`, leaving out those that did
+    /// not run.
+    pub fn of(hosts: &[HostReport]) -> Option<DurationStats> {
+        let mut durations: Vec<u64> = hosts.iter().filter_map(|host| host.duration_ms).collect();
+        durations.sort_unstable();
+
+        if durations.is_empty() {
+            return None;
+        }
+
+        // nearest rank, so that each is the duration of a host
+        let percentile = |percent: usize| durations[(durations.len() * percent).div_ceil(100).max(1) - 1];
+
+        Some(DurationStats {
+            min_ms: durations[0],
+            median_ms: percentile(50),
+            p95_ms: percentile(95),
+            max_ms: durations[durations.len() - 1],
+        })
+    }
+}
+
+/// Hosts of `hosts` that ran the longest, at most `count` of them, the
+/// slowest first.
+pub fn slowest(hosts: &[HostReport], count: usize) -> Vec<&HostReport> {
+    let mut ran: Vec<&HostReport> = hosts.iter().filter(|host| host.duration_ms.is_some()).collect();
+    ran.sort_by_key(|host| cmp::Reverse(host.duration_ms));
+    ran.truncate(count);
+    ran
+}
+
+/// Hosts of `hosts` whose command ended grouped by their output, those of
+/// the most hosts first.
+pub fn collate(hosts: &[HostReport]) -> Vec<Collated<'_>> {
+    let mut collated: Vec<Collated> = Vec::new();
+    let mut indices = HashMap::new();
+
+    for host in hosts.iter().filter(|host| host.exit_code.is_some() || host.signal.is_some()) {
+        let index = *indices.entry((host.stdout.as_str(), host.stderr.as_str())).or_insert_with(|| {
+            collated.push(Collated { hosts: Vec::new(), stdout: &host.stdout, stderr: &host.stderr });
+            collated.len() - 1
+        });
+
+        collated[index].hosts.push(&host.name);
+    }
+
+    // stable, so that equally shared outputs stay in the order of the hosts
+    collated.sort_by_key(|collated| cmp::Reverse(collated.hosts.len()));
+    collated
+}
+
+/// How a command ended, e.g. exit code 1 or killed by signal 9.
+pub fn exit_status(status: ExitStatus) -> String {
+    describe_exit(status.code(), signal(status)).unwrap_or_else(|| status.to_string())
+}
+
+fn describe_exit(exit_code: Option<i32>, signal: Option<i32>) -> Option<String> {
+    match (exit_code, signal) {
+        (Some(exit_code), _) => Some(format!("exit code {}", exit_code)),
+        (None, Some(signal)) => Some(format!("killed by signal {}", signal)),
+        (None, None) => None,
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Writes `report` as JSON to `path`, replacing the file if any.
+pub fn write_json(path: &Path, report: &RunReport) -> Result<()> {
+    let content = serde_json::to_string_pretty(report)
+        .chain_err(|| "Unable to serialize run report")?;
+
+    write(path, &content)
+}
+
+/// Writes `report` as JUnit XML to `path`, each host being a test case, for
+/// CI servers to show.
+pub fn write_junit(path: &Path, report: &RunReport) -> Result<()> {
+    let seconds = |duration_ms: Option<u64>| duration_ms.unwrap_or(0) as f64 / 1000.0;
+    let total: u64 = report.hosts.iter().filter_map(|host| host.duration_ms).sum();
+
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuite name=\"each_cmd\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        report.hosts.len(), report.failed, report.skipped, seconds(Some(total)));
+
+    for host in report.hosts {
+        xml += &format!("  <testcase name=\"{}\" classname=\"each_cmd\" time=\"{:.3}\">\n", escape(&host.name), seconds(host.duration_ms));
+
+        let message = host.failure();
+
+        match host.status {
+            HostStatus::Failed => xml += &format!("    <failure message=\"{}\"/>\n", escape(&message)),
+            HostStatus::Skipped => xml += &format!("    <skipped message=\"{}\"/>\n", escape(&message)),
+            HostStatus::Succeeded => {},
+        }
+
+        if !host.stdout.is_empty() {
+            xml += &format!("    <system-out>{}</system-out>\n", escape(&host.stdout));
+        }
+
+        if !host.stderr.is_empty() {
+            xml += &format!("    <system-err>{}</system-err>\n", escape(&host.stderr));
+        }
+
+        xml += "  </testcase>\n";
+    }
+
+    xml += "</testsuite>\n";
+    write(path, &xml)
+}
+
+/// File name for the log of the host of `key`, kept within the directory
+/// whatever the key holds. Other bytes than letters, digits, `.`, `-` and `_`
+/// are escaped as `%XX`, as is a leading `.`, so that distinct keys never
+/// share a log.
+fn log_name(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+
+    for (i, byte) in key.bytes().enumerate() {
+        match byte {
+            b'.' if i == 0 => name += "%2E",
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => name.push(byte as char),
+            _ => name += &format!("%{:02X}", byte),
+        }
+    }
+
+    name
+}
+
+/// Writes the full output of the host of `key` along with how it ended to
+/// its own log file in `dir`.
+pub fn write_log(dir: &Path, key: &str, report: &HostReport) -> Result<()> {
+    let path = dir.join(format!("{}.log", log_name(key)));
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_owned());
+
+    let status = match report.status {
+        HostStatus::Succeeded => "succeeded",
+        HostStatus::Failed => "failed",
+        HostStatus::Skipped => "skipped",
+    };
+
+    let mut log = format!("host: {}\n", report.name);
+
+    for command in &report.commands {
+        log += &format!("command: {}\n", command);
+    }
+
+    log += &format!("status: {}\nexit code: {}\nsignal: {}\nduration: {}\nreason: {}\nfailed step: {}\n",
+        status,
+        optional(report.exit_code.map(|exit_code| exit_code.to_string())),
+        optional(report.signal.map(|signal| signal.to_string())),
+        optional(report.duration_ms.map(|duration_ms| format!("{} ms", duration_ms))),
+        optional(report.reason.clone()),
+        optional(report.failed_step.clone()));
+
+    for &(title, output) in &[("stdout", &report.stdout), ("stderr", &report.stderr)] {
+        log += &format!("--- {} ---\n{}", title, output);
+
+        if !output.is_empty() && !output.ends_with('\n') {
+            log += "\n";
+        }
+    }
+
+    File::create(&path)
+        .and_then(|mut file| file.write_all(log.as_bytes()))
+        .chain_err(|| format!("Unable to write log of {} to {}", report.name, path.display()))
+}
+
+/// Writes `report` as Markdown to `path`, with tables of the counts and of
+/// the failed hosts and the output of each host collapsed, to be pasted
+/// into tickets and comments.
+pub fn write_markdown(path: &Path, report: &RunReport) -> Result<()> {
+    let seconds = |duration_ms: u64| format!("{:.3}s", duration_ms as f64 / 1000.0);
+    let timed_out = report.hosts.iter().filter(|host| host.timed_out).count();
+
+    let mut md = "# each_cmd run\n\n| Succeeded | Failed | Timed out | Skipped |\n| --- | --- | --- | --- |\n".to_owned();
+    md += &format!("| {} | {} | {} | {} |\n", report.succeeded, report.failed - timed_out, timed_out, report.skipped);
+
+    if let Some(durations) = report.durations {
+        md += &format!("\nHost durations: min {}, median {}, p95 {}, max {}\n",
+            seconds(durations.min_ms), seconds(durations.median_ms), seconds(durations.p95_ms), seconds(durations.max_ms));
+    }
+
+    let failed: Vec<&HostReport> = report.hosts.iter().filter(|host| host.status == HostStatus::Failed).collect();
+
+    if !failed.is_empty() {
+        md += "\n## Failures\n\n| Host | Reason | Duration |\n| --- | --- | --- |\n";
+
+        for host in failed {
+            md += &format!("| {} | {} | {} |\n", cell(&host.name), cell(&host.failure()), host.duration_ms.map(seconds).unwrap_or_default());
+        }
+    }
+
+    md += "\n## Output\n";
+
+    for host in report.hosts {
+        let status = match host.status {
+            HostStatus::Succeeded => "succeeded",
+            HostStatus::Failed => "failed",
+            HostStatus::Skipped => "skipped",
+        };
+
+        md += &format!("\n<details>\n<summary>{}: {}</summary>\n", escape(&host.name), status);
+
+        if host.stdout.is_empty() && host.stderr.is_empty() {
+            md += "\nNo output.\n";
+        }
+
+        for &(title, output) in &[("stdout", &host.stdout), ("stderr", &host.stderr)] {
+            if output.is_empty() {
+                continue;
+            }
+
+            // a fence longer than any backtick run of the output
+            let fence = "`".repeat(longest_run(output, '`').max(2) + 1);
+            md += &format!("\n{}:\n\n{}\n{}\n{}\n", title, fence, output.strip_suffix('\n').unwrap_or(output), fence);
+        }
+
+        md += "\n</details>\n";
+    }
+
+    write(path, &md)
+}
+
+/// `text` as a single line of a Markdown table cell.
+fn cell(text: &str) -> String {
+    text.replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|other| other != c).map(|run| run.len()).max().unwrap_or(0)
+}
+
+/// Escapes `text` for XML text and attributes, leaving out the control
+/// characters that XML cannot hold.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {},
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn write(path: &Path, content: &str) -> Result<()> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .chain_err(|| format!("Unable to write run report to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_name_keeps_distinct_keys_apart() {
+        let names: Vec<String> = ["web/1", "web:1", "web_1", "web%2F1", "web-1#2", "../etc", "h\u{f6}st"].iter()
+            .map(|key| log_name(key))
+            .collect();
+
+        assert_eq!(names, vec!["web%2F1", "web%3A1", "web_1", "web%252F1", "web-1%232", "%2E.%2Fetc", "h%C3%B6st"]);
+    }
+
+    fn failed_host() -> HostReport {
+        HostReport {
+            reason: Some("exit code 1 <\"&'>".to_owned()),
+            stdout: "ok & <done>\n".to_owned(),
+            stderr: "bell \u{7} here".to_owned(),
+            ..HostReport::new(&Host::new("web<1>"), Vec::new(), HostStatus::Failed)
+        }
+    }
+
+    /// Content that `write` gives for `hosts`.
+    fn written(write: fn(&Path, &RunReport) -> Result<()>, extension: &str, hosts: &[HostReport]) -> String {
+        let report = RunReport { succeeded: 0, failed: 1, skipped: 0, durations: None, hosts };
+        let path = ::std::env::temp_dir().join(format!("each_cmd-report-{}.{}", ::std::process::id(), extension));
+
+        write(&path, &report).unwrap();
+        let content = ::std::fs::read_to_string(&path).unwrap();
+        let _ = ::std::fs::remove_file(&path);
+
+        content
+    }
+
+    #[test]
+    fn escape_handles_markup_and_control_characters() {
+        assert_eq!(escape("<a href=\"x\">'&'</a>"), "&lt;a href=&quot;x&quot;&gt;&apos;&amp;&apos;&lt;/a&gt;");
+        assert_eq!(escape("tab\tline\r\nnul\u{0}esc\u{1b}"), "tab\tline\r\nnulesc");
+    }
+
+    #[test]
+    fn junit_escapes_names_messages_and_output() {
+        let xml = written(write_junit, "xml", &[failed_host()]);
+
+        assert!(xml.contains("<testcase name=\"web&lt;1&gt;\""), "{}", xml);
+        assert!(xml.contains("<failure message=\"exit code 1 &lt;&quot;&amp;&apos;&gt;\"/>"), "{}", xml);
+        assert!(xml.contains("<system-out>ok &amp; &lt;done&gt;\n</system-out>"), "{}", xml);
+        assert!(xml.contains("<system-err>bell  here</system-err>"), "{}", xml);
+    }
+
+    #[test]
+    fn cell_keeps_tables_on_one_line() {
+        assert_eq!(cell("a|b\r\nc"), "a\\|b  c");
+    }
+
+    #[test]
+    fn markdown_escapes_cells_summaries_and_fences() {
+        let host = HostReport {
+            name: "web|1<x>".to_owned(),
+            stdout: "```\ncode\n````\n".to_owned(),
+            ..failed_host()
+        };
+
+        let md = written(write_markdown, "md", &[host]);
+
+        assert!(md.contains("| web\\|1<x> | exit code 1 <\"&'> |"), "{}", md);
+        assert!(md.contains("<summary>web|1&lt;x&gt;: failed</summary>"), "{}", md);
+
+        // a fence longer than the longest backtick run of the output
+        assert!(md.contains("stdout:\n\n`````\n```\ncode\n````\n`````\n"), "{}", md);
+        assert!(md.contains("stderr:\n\n```\nbell \u{7} here\n```\n"), "{}", md);
+    }
+}
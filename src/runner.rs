@@ -0,0 +1,756 @@
+//! Scheduling of jobs on the worker pool, with their start delays, retries
+//! and the deadline of the whole run.
+
+use config::{Backoff, Config, RetryOn, Until};
+use errors::*;
+use exec::{self, Job, JobFuture, Step};
+use meter;
+use futures::{Future, Stream};
+use futures::future::{self, Either, Loop};
+use futures::stream::FuturesUnordered;
+use futures::sync::oneshot;
+use futures_cpupool::{CpuFuture, CpuPool};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use template;
+use verbosity;
+use tokio_timer::{self, Timer};
+
+/// Longest timer, well above the default wheel span of a few minutes.
+const MAX_TIMER_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+pub struct Runner {
+    pool: CpuPool,
+    permits: Arc<Permits>,
+    timer: Timer,
+    deadline: Option<Instant>,
+    retries: u32,
+    backoff: Option<Backoff>,
+    retry_on: Option<RetryOn>,
+    until: Option<Until>,
+    start_jitter_ms: Option<u64>,
+    tally: Arc<Tally>,
+}
+
+/// Count of the failed jobs, stopping the run once there are too many.
+struct Tally {
+    fail_fast: bool,
+
+    /// Failures after which no more jobs start.
+    max_failures: Option<usize>,
+    failures: AtomicUsize,
+}
+
+impl Tally {
+    fn record(&self, result: &Result<Output>) {
+        if !is_failure(result) {
+            return;
+        }
+
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if self.fail_fast {
+            verbosity::trace(|| "Stopping every command on the first failure".to_owned());
+            exec::abort();
+        } else if self.max_failures.is_some_and(|max_failures| failures >= max_failures) {
+            verbosity::trace(|| format!("Starting no more commands after {} failures", failures));
+            exec::halt();
+        }
+    }
+}
+
+/// Limit on the jobs running at once, independent of the worker threads
+/// since running jobs do not hold on to one.
+struct Permits {
+    state: Mutex<PermitState>,
+}
+
+struct PermitState {
+    available: usize,
+
+    /// Jobs waiting for a permit, in order.
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Permission for one job to run, handed over to the next waiting job once
+/// dropped.
+struct Permit {
+    permits: Option<Arc<Permits>>,
+}
+
+impl Permits {
+    fn new(count: usize) -> Arc<Permits> {
+        Arc::new(Permits {
+            state: Mutex::new(PermitState { available: count.max(1), waiting: VecDeque::new() }),
+        })
+    }
+
+    /// Permit for the job of the host `name`, once one is free.
+    fn acquire(permits: &Arc<Permits>, name: &str) -> Box<dyn Future<Item = Permit, Error = Error> + Send> {
+        let mut state = permits.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if state.available > 0 {
+            state.available -= 1;
+            return Box::new(future::ok(Permit { permits: Some(permits.clone()) }));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        state.waiting.push_back(sender);
+
+        let waiting = state.waiting.len();
+        drop(state);
+
+        verbosity::trace(|| format!("{} waits for a free slot, {} waiting", name, waiting));
+        Box::new(receiver.map_err(|_| "Job permits dropped".into()))
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let permits = match self.permits.take() {
+            Some(permits) => permits,
+            None => return,
+        };
+
+        let mut state = permits.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        // skips the jobs that stopped waiting, e.g. at the deadline, the
+        // permit coming back without being released again
+        while let Some(sender) = state.waiting.pop_front() {
+            match sender.send(Permit { permits: Some(permits.clone()) }) {
+                Ok(()) => return,
+                Err(mut permit) => permit.permits = None,
+            }
+        }
+
+        state.available += 1;
+    }
+}
+
+/// A job started by the runner.
+pub struct Running {
+    fut: CpuFuture<Output, Error>,
+    track: Arc<Track>,
+}
+
+/// How far a job got, besides its result.
+#[derive(Default)]
+struct Track {
+    started: AtomicBool,
+
+    /// When the job first started and when it ended.
+    started_at: Mutex<Option<Instant>>,
+    finished_at: Mutex<Option<Instant>>,
+
+    /// Step that the job stopped at, if it has steps.
+    failed_step: Mutex<Option<String>>,
+
+    /// Whether the check found the job already done, skipping it.
+    already_done: AtomicBool,
+}
+
+/// How the job of a host ended, and how long it ran for if it started.
+pub struct Finished {
+    pub outcome: Outcome,
+    pub duration: Option<Duration>,
+}
+
+/// How the job of a host ended.
+pub enum Outcome {
+    Completed(Output),
+    Failed(Error),
+
+    /// The job never started, e.g. past the deadline.
+    Skipped(Error),
+
+    /// The check succeeded, with its output, so the job was not run.
+    AlreadyDone(Output),
+
+    /// A step of the job failed, either running or with the error.
+    StepFailed { step: String, result: Result<Output> },
+}
+
+impl Runner {
+    /// Starts the clock of the run deadline, for a run on `total` hosts.
+    pub fn new(config: &Config, total: usize) -> Runner {
+        let timer = tokio_timer::wheel()
+            .tick_duration(Duration::from_millis(10))
+            .max_timeout(MAX_TIMER_DURATION)
+            .build();
+
+        let deadline = config.max_total_duration_ms
+            .map(|max_total_duration_ms| Instant::now() + Duration::from_millis(max_total_duration_ms));
+
+        let max_failures = failure_limit(config.max_failures, config.max_fail_percentage, total);
+
+        Runner {
+            pool: CpuPool::new(config.thread_count),
+            permits: Permits::new(config.max_concurrency.unwrap_or(config.thread_count)),
+            timer,
+            deadline,
+            retries: config.retries,
+            backoff: config.backoff.clone(),
+            retry_on: config.retry_on.clone(),
+            until: config.until.clone(),
+            start_jitter_ms: config.start_jitter_ms,
+            tally: Arc::new(Tally {
+                fail_fast: config.fail_fast,
+                max_failures,
+                failures: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Starts running `job` for the host `name` in the background.
+    pub fn spawn(&self, name: &str, mut job: Job) -> Running {
+        job.deadline = self.deadline;
+
+        let attempts = Attempts {
+            name: name.to_owned(),
+            permits: Some(self.permits.clone()),
+            timer: self.timer.clone(),
+            backoff: self.backoff.clone(),
+            retry_on: self.retry_on.clone(),
+            until: self.until.clone(),
+            tally: self.tally.clone(),
+            track: Arc::new(Track::default()),
+        };
+
+        let track = attempts.track.clone();
+        let retries = self.retries;
+
+        let start_delay = self.start_jitter_ms
+            .map(|start_jitter_ms| Duration::from_millis(template::random_u64() % (start_jitter_ms + 1)));
+
+        if let Some(start_delay) = start_delay {
+            verbosity::trace(|| format!("Delaying the start on {} by {} ms", name, start_delay.as_millis()));
+        }
+
+        // a job with a post command stops waiting at the deadline on its
+        // own, so that the post command still runs
+        let own_deadline = job.post.is_some();
+
+        let retry = move || -> Box<dyn Future<Item = Output, Error = Error> + Send> {
+            let check = match job.check {
+                Some(ref check) => job.hook(check, false),
+                None => return run_job(attempts, job, retries),
+            };
+
+            let checked = within_deadline(&attempts.timer, job.deadline, attempts.clone().check(check));
+
+            // the job only runs if the check does not find it done
+            Box::new(checked.and_then(move |done| match done {
+                Some(output) => {
+                    attempts.track.already_done.store(true, Ordering::SeqCst);
+                    Either::A(future::ok(output))
+                },
+                None => Either::B(run_job(attempts, job, retries)),
+            }))
+        };
+
+        let finish_track = track.clone();
+
+        let run_fut = match start_delay {
+            Some(start_delay) => Either::A(self.timer.sleep(start_delay).then(move |_| retry())),
+            None => Either::B(retry()),
+        };
+
+        // stops waiting on the host, retries included, at the deadline
+        let exec_fut = if own_deadline {
+            Either::A(run_fut)
+        } else {
+            Either::B(within_deadline(&self.timer, self.deadline, run_fut))
+        };
+
+        // a host that never started is not counted as failed
+        let exec_fut = exec_fut.then(move |result| {
+            *lock(&finish_track.finished_at) = Some(Instant::now());
+            meter::finished(finish_track.started.load(Ordering::SeqCst) && is_failure(&result));
+            result
+        });
+
+        // driven on the pool, so that retries and delays of every host make
+        // progress while the results are waited on in order
+        Running { fut: self.pool.spawn(exec_fut), track }
+    }
+}
+
+/// `fut`, given up on with `DeadlineExceeded` once `deadline` is reached.
+fn within_deadline<F>(timer: &Timer, deadline: Option<Instant>, fut: F) -> Box<dyn Future<Item = F::Item, Error = Error> + Send>
+where
+    F: Future<Error = Error> + Send + 'static,
+    F::Item: Send + 'static,
+{
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Box::new(fut),
+    };
+
+    let deadline_fut = timer.sleep(deadline.saturating_duration_since(Instant::now()))
+        .then(|_| bail!(ErrorKind::DeadlineExceeded));
+
+    Box::new(fut
+        .select(deadline_fut)
+        .map(|(win, _)| win)
+        .map_err(|(e, _)| e))
+}
+
+/// What the attempts at a job of one host share.
+#[derive(Clone)]
+struct Attempts {
+    name: String,
+
+    /// Where permits come from, none being needed if the attempts run
+    /// within one already held.
+    permits: Option<Arc<Permits>>,
+    timer: Timer,
+    backoff: Option<Backoff>,
+    retry_on: Option<RetryOn>,
+    until: Option<Until>,
+    tally: Arc<Tally>,
+    track: Arc<Track>,
+}
+
+impl Attempts {
+    fn permit(&self) -> Box<dyn Future<Item = Permit, Error = Error> + Send> {
+        match self.permits {
+            Some(ref permits) => Permits::acquire(permits, &self.name),
+            None => Box::new(future::ok(Permit { permits: None })),
+        }
+    }
+
+    /// Runs `job` until it succeeds or runs out of `retries`, starting once
+    /// polled.
+    fn run(self, job: Job, retries: u32) -> Box<dyn Future<Item = Output, Error = Error> + Send> {
+        Box::new(future::lazy(move || future::loop_fn(0, move |attempt| {
+            let permit = self.permit();
+            let Attempts { name, timer, backoff, retry_on, until, tally, track, .. } = self.clone();
+            let job = job.clone();
+
+            let delay = match (until.as_ref(), backoff.as_ref()) {
+                (Some(until), _) => Duration::from_millis(until.interval_ms),
+                (None, Some(backoff)) => backoff.delay(attempt),
+                (None, None) => Duration::from_millis(0),
+            };
+
+            let polling = until.is_some();
+
+            permit.and_then(move |permit| {
+                future::lazy(move || attempt_job(job, &track)).flatten().then(move |result| {
+                    // polling goes on through any failure
+                    let retry = match until {
+                        Some(ref until) if until.max_attempts.is_none_or(|max_attempts| attempt + 1 < max_attempts) => {
+                            retry_reason(&result, None)
+                        },
+                        None if attempt < retries => retry_reason(&result, retry_on.as_ref()),
+                        _ => None,
+                    };
+
+                    // tallies the failure before the next job gets the permit
+                    if retry.is_none() {
+                        tally.record(&result);
+                    }
+
+                    drop(permit);
+                    Ok::<_, Error>((result, retry))
+                })
+            })
+            .then(move |attempted| {
+                let (result, retry) = match attempted {
+                    Ok(attempted) => attempted,
+                    Err(e) => (Err(e), None),
+                };
+
+                match retry {
+                    Some(reason) => {
+                        if delay > Duration::from_millis(0) {
+                            verbosity::trace(|| format!("Waiting {} ms before attempt {} on {}", delay.as_millis(), attempt + 2, name));
+                        }
+
+                        if polling {
+                            let _ = writeln!(io::stderr(), "Command not successful yet on {} (attempt {}): {}",
+                                name, attempt + 1, reason);
+                        } else {
+                            let _ = writeln!(io::stderr(), "Retrying command on {} ({} of {}) after failure: {}",
+                                name, attempt + 1, retries, reason);
+                        }
+
+                        Either::A(timer.sleep(delay)
+                            .then(move |_| Ok(Loop::Continue(attempt + 1))))
+                    },
+                    None => Either::B(future::result(result.map(Loop::Break))),
+                }
+            })
+        })))
+    }
+}
+
+impl Attempts {
+    /// Runs the check of a job once, giving back its output if it succeeded.
+    /// Failing the check is no failure of the job.
+    fn check(self, check: Job) -> Box<dyn Future<Item = Option<Output>, Error = Error> + Send> {
+        Box::new(future::lazy(move || self.permit().and_then(move |permit| {
+            future::lazy(move || attempt_job(check, &self.track)).flatten().map(move |output| {
+                drop(permit);
+                Some(output).filter(|output| output.status.success())
+            })
+        })))
+    }
+}
+
+/// Runs the steps or the commands of `job`, between its pre and post
+/// commands if it has any. The post command runs however the others ended,
+/// once the pre command started, and even if the run is stopped or past its
+/// deadline.
+fn run_job(attempts: Attempts, job: Job, retries: u32) -> Box<dyn Future<Item = Output, Error = Error> + Send> {
+    if job.pre.is_none() && job.post.is_none() {
+        return if job.steps.is_empty() { attempts.run(job, retries) } else { run_steps(attempts, job, retries) };
+    }
+
+    let pre = job.pre.as_ref().map(|pre| job.hook(pre, false));
+    let post = job.post.as_ref().map(|post| Job { deadline: None, ..job.hook(post, true) });
+    let deadline = if post.is_some() { job.deadline } else { None };
+    let main = Job { pre: None, post: None, ..job };
+
+    let Attempts { timer, tally, track, .. } = attempts.clone();
+
+    let fail = move |track: &Track, name: &str, result: &Result<Output>| {
+        tally.record(result);
+        *lock(&track.failed_step) = Some(name.to_owned());
+    };
+
+    // the permit is held from the pre command to the post command, so that
+    // e.g. only as many hosts as may run at once are out of a load balancer
+    let ran = attempts.permit().and_then({
+        let track = track.clone();
+        let fail = fail.clone();
+
+        move |permit| {
+            let within = Attempts { permits: None, ..attempts };
+
+            let pre_fut = match pre {
+                Some(pre) => {
+                    let track = track.clone();
+                    Either::A(future::lazy(move || attempt_job(pre, &track)).flatten().map(Some))
+                },
+                None => Either::B(future::ok(None)),
+            };
+
+            pre_fut.then(move |pre_result| -> Box<dyn Future<Item = Result<Output>, Error = Error> + Send> {
+                match pre_result {
+                    Ok(None) => Box::new(run_job(within, main, retries).then(Ok)),
+                    Ok(Some(output)) => {
+                        if output.status.success() {
+                            return Box::new(run_job(within, main, retries).then(move |result| Ok(result.map(|main| merge(output, main)))));
+                        }
+
+                        let failed = Ok(output);
+                        fail(&track, "preCmd", &failed);
+                        Box::new(future::ok(failed))
+                    },
+                    Err(e) => {
+                        let failed = Err(e);
+                        fail(&track, "preCmd", &failed);
+                        Box::new(future::ok(failed))
+                    },
+                }
+            })
+            .map(move |result| (result, Some(permit)))
+        }
+    });
+
+    // only the commands before the post command are given up on at the
+    // deadline, the post command running without one
+    let ran = within_deadline(&timer, deadline, ran).or_else(|e| Ok((Err(e), None)));
+
+    Box::new(ran.and_then(move |(result, permit)| match post {
+        Some(post) if track.started.load(Ordering::SeqCst) => {
+            Either::A(future::lazy(move || exec::start_job(post)).then(move |post_result| {
+                drop(permit);
+
+                let succeeded = result.as_ref().map(|output| output.status.success()).unwrap_or(false);
+
+                let result = match (result, post_result) {
+                    (Ok(output), Ok(post_output)) => {
+                        let status = output.status;
+                        let post_failed = !post_output.status.success();
+                        let mut merged = merge(output, post_output);
+
+                        // keeps the status of the failure before the post
+                        // command
+                        if !succeeded {
+                            merged.status = status;
+                        }
+
+                        let merged = Ok(merged);
+
+                        if succeeded && post_failed {
+                            fail(&track, "postCmd", &merged);
+                        }
+
+                        merged
+                    },
+                    (Ok(_), Err(e)) if succeeded => {
+                        let failed = Err(e);
+                        fail(&track, "postCmd", &failed);
+                        failed
+                    },
+                    (result, _) => result,
+                };
+
+                Ok(result)
+            }))
+        },
+        _ => {
+            drop(permit);
+            Either::B(future::ok(result))
+        },
+    })
+    .and_then(future::result))
+}
+
+/// Output of `first` followed by that of `then`, with the status of `then`.
+fn merge(mut first: Output, then: Output) -> Output {
+    first.stdout.extend(then.stdout);
+    first.stderr.extend(then.stderr);
+    first.status = then.status;
+    first
+}
+
+/// Runs the steps of `job` one after the other, each with its own timeout
+/// and retries, combining their output. The first step to fail ends the
+/// job and is tracked as the failed step.
+fn run_steps(attempts: Attempts, job: Job, retries: u32) -> Box<dyn Future<Item = Output, Error = Error> + Send> {
+    Box::new(future::loop_fn((0, None), move |(index, combined): (usize, Option<Output>)| {
+        let Step { name, cmd, timeout, retries: step_retries } = job.steps[index].clone();
+        let last = index + 1 == job.steps.len();
+        let track = attempts.track.clone();
+
+        let step_job = Job {
+            cmds: vec![cmd],
+            steps: Vec::new(),
+            timeout: timeout.or(job.timeout),
+            ..job.clone()
+        };
+
+        attempts.clone().run(step_job, step_retries.unwrap_or(retries)).then(move |result| {
+            let failed = result.as_ref().map(|output| !output.status.success()).unwrap_or(true);
+
+            if failed {
+                *lock(&track.failed_step) = Some(name);
+            }
+
+            let output = result?;
+
+            let combined = match combined {
+                None => output,
+                Some(mut combined) => {
+                    combined.stdout.extend(output.stdout);
+                    combined.stderr.extend(output.stderr);
+                    combined.status = output.status;
+                    combined
+                },
+            };
+
+            if failed || last {
+                Ok(Loop::Break(combined))
+            } else {
+                Ok(Loop::Continue((index + 1, Some(combined))))
+            }
+        })
+    }))
+}
+
+impl Running {
+    pub fn wait(self) -> Finished {
+        finish(self.fut.wait(), &self.track)
+    }
+}
+
+/// Waits on every job of `running`, giving back the outcome of each along
+/// with its tag as soon as it finishes.
+pub fn wait_completed<T>(running: Vec<(T, Running)>) -> impl Iterator<Item = (T, Finished)> {
+    let finished: FuturesUnordered<_> = running.into_iter()
+        .map(|(tag, running)| {
+            let Running { fut, track } = running;
+            fut.then(move |result| Ok::<_, ()>((tag, finish(result, &track))))
+        })
+        .collect();
+
+    // the futures never fail
+    finished.wait().filter_map(|finished| finished.ok())
+}
+
+fn finish(result: Result<Output>, track: &Track) -> Finished {
+    let duration = match (*lock(&track.started_at), *lock(&track.finished_at)) {
+        (Some(started_at), Some(finished_at)) => Some(finished_at.saturating_duration_since(started_at)),
+        _ => None,
+    };
+
+    Finished { outcome: outcome(result, track), duration }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> ::std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn outcome(result: Result<Output>, track: &Track) -> Outcome {
+    let skipped = match result {
+        Err(ref e) => {
+            let stopped = matches!(*e.kind(), ErrorKind::DeadlineExceeded | ErrorKind::Cancelled | ErrorKind::Aborted | ErrorKind::TooManyFailures);
+            stopped && !track.started.load(Ordering::SeqCst)
+        },
+        Ok(_) => false,
+    };
+
+    match (result, lock(&track.failed_step).take()) {
+        (Err(e), _) if skipped => Outcome::Skipped(e),
+        (Ok(output), _) if track.already_done.load(Ordering::SeqCst) => Outcome::AlreadyDone(output),
+        (result, Some(step)) => Outcome::StepFailed { step, result },
+        (Ok(output), None) => Outcome::Completed(output),
+        (Err(e), None) => Outcome::Failed(e),
+    }
+}
+
+/// Number of failures that stops a run on `total` hosts, the lower of
+/// `max_failures` and of the count past `max_fail_percentage` of the hosts.
+fn failure_limit(max_failures: Option<usize>, max_fail_percentage: Option<f64>, total: usize) -> Option<usize> {
+    // more than the percentage is too many
+    let max_failed_share = max_fail_percentage
+        .map(|percentage| (total as f64 * percentage / 100.0).floor() as usize + 1);
+
+    match (max_failures, max_failed_share) {
+        (Some(count), Some(share)) => Some(count.min(share)),
+        (count, share) => count.or(share),
+    }
+}
+
+/// Whether the job failed, rather than being stopped along with the run.
+fn is_failure(result: &Result<Output>) -> bool {
+    match *result {
+        Ok(ref output) => !output.status.success(),
+        Err(ref e) => !matches!(*e.kind(), ErrorKind::Cancelled | ErrorKind::Aborted | ErrorKind::TooManyFailures),
+    }
+}
+
+/// Why the failed attempt is worth retrying, if it is. Without `retry_on`
+/// any failure is.
+fn retry_reason(result: &Result<Output>, retry_on: Option<&RetryOn>) -> Option<String> {
+    match *result {
+        Ok(ref output) if !output.status.success() => {
+            let retryable = retry_on.is_none_or(|retry_on| {
+                output.status.code().is_some_and(|code| retry_on.exit_codes.contains(&code))
+            });
+
+            Some(output.status.to_string()).filter(|_| retryable)
+        },
+        Ok(_) => None,
+        Err(ref e) => {
+            let retryable = match *e.kind() {
+                ErrorKind::DeadlineExceeded | ErrorKind::Cancelled | ErrorKind::Aborted | ErrorKind::TooManyFailures => false,
+                ErrorKind::Timeout | ErrorKind::IdleTimeout => retry_on.is_none_or(|retry_on| retry_on.timeout),
+                _ => retry_on.is_none(),
+            };
+
+            Some(e.to_string()).filter(|_| retryable)
+        },
+    }
+}
+
+/// Starts the job once the run is not paused, unless past its deadline or
+/// once the run stopped. `track` records whether and when it started.
+fn attempt_job(job: Job, track: &Track) -> Result<JobFuture> {
+    exec::wait_while_paused();
+
+    if job.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        bail!(ErrorKind::DeadlineExceeded);
+    }
+
+    if let Some(kind) = exec::stopped() {
+        bail!(kind);
+    }
+
+    track.started.store(true, Ordering::SeqCst);
+    lock(&track.started_at).get_or_insert_with(Instant::now);
+
+    Ok(exec::start_job(job))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_ms: Option<u64>, jitter: bool) -> Backoff {
+        Backoff { initial_ms: 100, multiplier: 3.0, max_ms, jitter }
+    }
+
+    #[test]
+    fn backoff_grows_up_to_its_maximum() {
+        let delays: Vec<u128> = (0..4).map(|attempt| backoff(Some(1000), false).delay(attempt).as_millis()).collect();
+        assert_eq!(delays, vec![100, 300, 900, 1000]);
+
+        assert_eq!(backoff(None, false).delay(4), Duration::from_millis(8100));
+    }
+
+    #[test]
+    fn backoff_jitter_keeps_half_to_all_of_the_delay() {
+        for attempt in 0..20 {
+            let delay = backoff(Some(1000), true).delay(attempt % 4).as_millis();
+            let full = backoff(Some(1000), false).delay(attempt % 4).as_millis();
+
+            assert!(full / 2 <= delay && delay <= full, "{} is not within half of {}", delay, full);
+        }
+    }
+
+    #[cfg(unix)]
+    fn exited(code: i32) -> Result<Output> {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        Ok(Output { status: ExitStatus::from_raw(code << 8), stdout: Vec::new(), stderr: Vec::new() })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retry_reason_follows_retry_on() {
+        let retry_on: RetryOn = "255,timeout".parse().unwrap();
+
+        assert!(retry_reason(&exited(0), None).is_none());
+        assert!(retry_reason(&exited(1), None).is_some());
+        assert!(retry_reason(&exited(255), Some(&retry_on)).is_some());
+        assert!(retry_reason(&exited(1), Some(&retry_on)).is_none());
+
+        let timeout = || Err(Error::from(ErrorKind::Timeout));
+        assert!(retry_reason(&timeout(), Some(&retry_on)).is_some());
+        assert!(retry_reason(&timeout(), Some(&"255".parse().unwrap())).is_none());
+
+        let other = || Err(Error::from("Unable to spawn"));
+        assert!(retry_reason(&other(), None).is_some());
+        assert!(retry_reason(&other(), Some(&retry_on)).is_none());
+    }
+
+    #[test]
+    fn retry_reason_never_retries_stopped_runs() {
+        for kind in [ErrorKind::DeadlineExceeded, ErrorKind::Cancelled, ErrorKind::Aborted, ErrorKind::TooManyFailures] {
+            assert!(retry_reason(&Err(Error::from(kind)), None).is_none());
+        }
+    }
+
+    #[test]
+    fn failure_limit_turns_percentages_into_counts() {
+        // more than the percentage of the hosts is too many
+        assert_eq!(failure_limit(None, Some(25.0), 10), Some(3));
+        assert_eq!(failure_limit(None, Some(20.0), 10), Some(3));
+        assert_eq!(failure_limit(None, Some(0.0), 10), Some(1));
+        assert_eq!(failure_limit(None, Some(100.0), 10), Some(11));
+
+        assert_eq!(failure_limit(Some(2), Some(50.0), 10), Some(2));
+        assert_eq!(failure_limit(Some(8), Some(50.0), 10), Some(6));
+        assert_eq!(failure_limit(Some(4), None, 10), Some(4));
+        assert_eq!(failure_limit(None, None, 10), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Decryption of sealed config values, so that tokens used in commands need
+//! not be kept in plain text.
+//!
+//! A sealed value is a string of the form `ENC[<scheme>:<ciphertext>]`:
+//!
+//! - `age`: base64 of an age encrypted message, decrypted with the identity
+//!   file in `EACH_CMD_SECRET_KEY_FILE`.
+//! - `aes`: the single line base64 output of
+//!   `openssl enc -aes-256-cbc -pbkdf2 -a -A`, decrypted with the passphrase
+//!   in `EACH_CMD_SECRET_PASSPHRASE` or else the first line of the file in
+//!   `EACH_CMD_SECRET_KEY_FILE`.
+//!
+//! Decryption shells out to the `age` and `openssl` tools.
+
+use errors::*;
+use serde_json::Value;
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use template;
+
+const KEY_FILE_VAR: &str = "EACH_CMD_SECRET_KEY_FILE";
+const PASSPHRASE_VAR: &str = "EACH_CMD_SECRET_PASSPHRASE";
+
+/// Stands in for the secrets in what is reported.
+const REDACTED: &str = "***";
+
+/// Plain texts of the secrets decrypted so far, as they may appear in the
+/// rendered commands.
+static DECRYPTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Replaces every sealed string in `value` with its plain text.
+pub fn decrypt_all(value: &mut Value) -> Result<()> {
+    match *value {
+        Value::String(ref mut s) => if let Some(sealed) = sealed(s) {
+            *s = decrypt(sealed)?;
+            remember(s);
+        },
+        Value::Array(ref mut items) => for item in items {
+            decrypt_all(item)?;
+        },
+        Value::Object(ref mut map) => for (_, item) in map.iter_mut() {
+            decrypt_all(item)?;
+        },
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// `text` with every decrypted secret in it replaced by `***`, e.g. for the
+/// commands written to reports and logs.
+pub fn redact(text: &str) -> String {
+    let decrypted = DECRYPTED.lock().unwrap_or_else(|e| e.into_inner());
+
+    decrypted.iter().fold(text.to_owned(), |text, secret| text.replace(secret.as_str(), REDACTED))
+}
+
+/// Keeps `secret` out of what is reported from now on.
+pub fn remember(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+
+    let mut decrypted = DECRYPTED.lock().unwrap_or_else(|e| e.into_inner());
+
+    // quoted for the shell, a secret with quotes in it no longer appears as
+    // it is
+    for form in [template::shell_quote(secret), secret.to_owned()] {
+        if !decrypted.contains(&form) {
+            decrypted.push(form);
+        }
+    }
+
+    // longer secrets first, so that none is left partly in sight by a
+    // shorter one that it contains
+    decrypted.sort_by_key(|secret| ::std::cmp::Reverse(secret.len()));
+}
+
+fn sealed(s: &str) -> Option<&str> {
+    s.trim().strip_prefix("ENC[").and_then(|s| s.strip_suffix(']'))
+}
+
+fn decrypt(sealed: &str) -> Result<String> {
+    let mut parts = sealed.splitn(2, ':');
+    let scheme = parts.next().unwrap_or("").trim();
+    let ciphertext = parts.next().unwrap_or("").trim();
+
+    let plaintext = match scheme {
+        "age" => {
+            let key_file = key_var(KEY_FILE_VAR, scheme)?;
+            let ciphertext = base64_decode(ciphertext)?;
+
+            run_with_input("age", &["--decrypt", "-i", &key_file], &ciphertext)?
+        },
+
+        "aes" => {
+            let pass = match env::var(PASSPHRASE_VAR) {
+                Ok(_) => format!("env:{}", PASSPHRASE_VAR),
+                Err(_) => format!("file:{}", key_var(KEY_FILE_VAR, scheme)?),
+            };
+
+            let input = format!("{}\n", ciphertext);
+
+            run_with_input("openssl", &["enc", "-d", "-aes-256-cbc", "-pbkdf2", "-a", "-A", "-pass", &pass], input.as_bytes())?
+        },
+
+        _ => bail!("Unknown secret scheme '{}', expected age or aes", scheme),
+    };
+
+    let plaintext = String::from_utf8(plaintext)
+        .chain_err(|| "Decrypted secret is not valid UTF-8")?;
+
+    // tolerates the newline left by encrypting the output of echo
+    Ok(plaintext.strip_suffix('\n').unwrap_or(&plaintext).to_owned())
+}
+
+fn key_var(name: &str, scheme: &str) -> Result<String> {
+    env::var(name)
+        .chain_err(|| format!("{} must be set to decrypt {} secrets in the config", name, scheme))
+}
+
+fn run_with_input(program: &str, args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .chain_err(|| format!("Unable to launch '{}', is it installed and on the PATH?", program))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)
+            .chain_err(|| format!("Unable to write secret into '{}'", program))?;
+    }
+
+    let output = child.wait_with_output()
+        .chain_err(|| format!("Unable to wait for '{}'", program))?;
+
+    if !output.status.success() {
+        bail!("Unable to decrypt secret with '{}' ({}): {}",
+            program, output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(output.stdout)
+}
+
+fn base64_decode(input: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(input.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in input.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let sextet = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 26,
+            '0'..='9' => c as u32 - '0' as u32 + 52,
+            '+' | '-' => 62,
+            '/' | '_' => 63,
+            _ => bail!("Invalid base64 character '{}' in secret", c),
+        };
+
+        buffer = (buffer << 6) | sextet;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_hides_plain_and_quoted_secrets() {
+        remember("it's");
+        remember("");
+
+        assert_eq!(redact(&format!("login it's && login {}", template::shell_quote("it's"))), "login *** && login ***");
+        assert_eq!(redact("nothing to hide"), "nothing to hide");
+    }
+}
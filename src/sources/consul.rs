@@ -0,0 +1,161 @@
+//! Discovers healthy service instances through the Consul health API, which
+//! is queried with `curl`.
+
+use errors::*;
+use hosts::Host;
+use serde_json::{self, Value};
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+fn default_passing() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsulSource {
+    pub service: String,
+
+    /// Consul HTTP address, defaulting to `CONSUL_HTTP_ADDR` and then to
+    /// `http://127.0.0.1:8500`.
+    pub address: Option<String>,
+
+    /// Only instances registered with this tag are used.
+    pub tag: Option<String>,
+    pub datacenter: Option<String>,
+
+    /// ACL token, defaulting to `CONSUL_HTTP_TOKEN`.
+    pub token: Option<String>,
+
+    /// Whether to only use instances whose health checks are all passing.
+    #[serde(default = "default_passing")]
+    pub passing_only: bool,
+}
+
+impl ConsulSource {
+    fn url(&self) -> String {
+        let address = self.address.clone()
+            .or_else(|| env::var("CONSUL_HTTP_ADDR").ok())
+            .unwrap_or_else(|| "http://127.0.0.1:8500".to_owned());
+
+        let address = if address.contains("://") {
+            address
+        } else {
+            format!("http://{}", address)
+        };
+
+        let mut query = Vec::new();
+
+        if self.passing_only {
+            query.push("passing=true".to_owned());
+        }
+
+        if let Some(ref tag) = self.tag {
+            query.push(format!("tag={}", percent_encode(tag)));
+        }
+
+        if let Some(ref datacenter) = self.datacenter {
+            query.push(format!("dc={}", percent_encode(datacenter)));
+        }
+
+        format!("{}/v1/health/service/{}?{}", address.trim_end_matches('/'), percent_encode(&self.service), query.join("&"))
+    }
+
+    /// Body of the health API response. The token is passed to curl on its
+    /// stdin, keeping it out of the process list and of error messages.
+    fn query(&self) -> Result<String> {
+        let token = self.token.clone().or_else(|| env::var("CONSUL_HTTP_TOKEN").ok());
+
+        let mut command = Command::new("curl");
+        command.args(["--silent", "--show-error", "--fail"])
+            .arg(self.url())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if token.is_some() {
+            command.args(["--header", "@-"]);
+        }
+
+        let mut child = command.spawn()
+            .chain_err(|| "Unable to launch 'curl', is it installed and on the PATH?")?;
+
+        if let (Some(mut stdin), Some(token)) = (child.stdin.take(), token) {
+            writeln!(stdin, "X-Consul-Token: {}", token)
+                .chain_err(|| "Unable to pass the Consul token to curl")?;
+        }
+
+        let output = child.wait_with_output()
+            .chain_err(|| "Unable to wait for curl")?;
+
+        if !output.status.success() {
+            bail!("curl failed with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Lists the service instances. Besides the hostname, each host carries
+    /// `node`, `address`, `port` and `service_id` variables.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let stdout = self.query()
+            .chain_err(|| format!("Unable to query Consul for service '{}'", self.service))?;
+
+        let entries: Vec<Value> = serde_json::from_str(&stdout)
+            .chain_err(|| "Unable to parse Consul health response")?;
+
+        let hosts = entries.iter()
+            .map(|entry| {
+                let text = |value: &Value| value.as_str().unwrap_or("").to_owned();
+
+                // the service address is optional, in which case the node
+                // address is the one to use
+                let address = match text(&entry["Service"]["Address"]) {
+                    ref address if address.is_empty() => text(&entry["Node"]["Address"]),
+                    address => address,
+                };
+
+                let mut host = Host::new(address.clone());
+                host.vars.insert("node".into(), text(&entry["Node"]["Node"]));
+                host.vars.insert("address".into(), address);
+                host.vars.insert("port".into(), entry["Service"]["Port"].to_string());
+                host.vars.insert("service_id".into(), text(&entry["Service"]["ID"]));
+                host
+            })
+            .collect();
+
+        Ok(hosts)
+    }
+}
+
+/// `text` with every byte but the unreserved characters of RFC 3986
+/// percent-encoded, to be used as a path segment or query value.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_encodes_service_tag_and_datacenter() {
+        let source = ConsulSource {
+            service: "web/api".to_owned(),
+            address: Some("consul:8500".to_owned()),
+            tag: Some("v1&passing=false".to_owned()),
+            datacenter: Some("eu west".to_owned()),
+            token: None,
+            passing_only: true,
+        };
+
+        assert_eq!(source.url(),
+            "http://consul:8500/v1/health/service/web%2Fapi?passing=true&tag=v1%26passing%3Dfalse&dc=eu%20west");
+    }
+}
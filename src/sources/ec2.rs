@@ -0,0 +1,120 @@
+//! Discovers running EC2 instances through `aws ec2 describe-instances`.
+
+use errors::*;
+use hosts::Host;
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use super::run_program;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Ec2Address {
+    #[default]
+    PrivateDns,
+    PublicDns,
+    PrivateIp,
+    PublicIp,
+}
+
+impl Ec2Address {
+    fn field(&self) -> &'static str {
+        match *self {
+            Ec2Address::PrivateDns => "PrivateDnsName",
+            Ec2Address::PublicDns => "PublicDnsName",
+            Ec2Address::PrivateIp => "PrivateIpAddress",
+            Ec2Address::PublicIp => "PublicIpAddress",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Ec2Source {
+    pub region: Option<String>,
+    pub profile: Option<String>,
+
+    /// Tag values to match, e.g. `{ "Role": "web" }`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    /// Raw `describe-instances` filters, e.g.
+    /// `{ "instance-type": ["t3.large"] }`.
+    #[serde(default)]
+    pub filters: BTreeMap<String, Vec<String>>,
+
+    /// Instance address used as the hostname.
+    #[serde(default)]
+    pub address: Ec2Address,
+}
+
+impl Ec2Source {
+    fn args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec!["ec2".into(), "describe-instances".into(), "--output".into(), "json".into()];
+
+        if let Some(ref region) = self.region {
+            args.push("--region".into());
+            args.push(region.clone());
+        }
+
+        if let Some(ref profile) = self.profile {
+            args.push("--profile".into());
+            args.push(profile.clone());
+        }
+
+        args.push("--filters".into());
+        args.push("Name=instance-state-name,Values=running".into());
+
+        for (key, value) in &self.tags {
+            args.push(format!("Name=tag:{},Values={}", key, value));
+        }
+
+        for (name, values) in &self.filters {
+            args.push(format!("Name={},Values={}", name, values.join(",")));
+        }
+
+        args
+    }
+
+    /// Lists the matching instances. Besides the hostname, each host carries
+    /// `instance_id`, `private_ip`, `public_ip` and `tag.<Key>` variables.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let stdout = run_program("aws", &self.args())
+            .chain_err(|| "Unable to describe EC2 instances")?;
+
+        let described: Value = serde_json::from_str(&stdout)
+            .chain_err(|| "Unable to parse EC2 instance description")?;
+
+        let instances = described["Reservations"].as_array().into_iter()
+            .flat_map(|reservations| reservations.iter())
+            .flat_map(|reservation| reservation["Instances"].as_array().into_iter().flat_map(|instances| instances.iter()));
+
+        let mut hosts = Vec::new();
+
+        for instance in instances {
+            let text = |field: &str| instance[field].as_str().unwrap_or("").to_owned();
+            let instance_id = text("InstanceId");
+            let address = text(self.address.field());
+
+            if address.is_empty() {
+                let _ = writeln!(io::stderr(), "Skipping EC2 instance {} without {}", instance_id, self.address.field());
+                continue;
+            }
+
+            let mut host = Host::new(address);
+            host.vars.insert("instance_id".into(), instance_id);
+            host.vars.insert("private_ip".into(), text("PrivateIpAddress"));
+            host.vars.insert("public_ip".into(), text("PublicIpAddress"));
+
+            for tag in instance["Tags"].as_array().into_iter().flat_map(|tags| tags.iter()) {
+                if let (Some(key), Some(value)) = (tag["Key"].as_str(), tag["Value"].as_str()) {
+                    host.vars.insert(format!("tag.{}", key), value.to_owned());
+                }
+            }
+
+            hosts.push(host);
+        }
+
+        Ok(hosts)
+    }
+}
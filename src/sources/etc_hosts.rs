@@ -0,0 +1,108 @@
+//! Reads hosts from a file in `/etc/hosts` format.
+
+use errors::*;
+use hosts::{self, Host};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+
+fn default_path() -> String {
+    "/etc/hosts".to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EtcHostsSource {
+    #[serde(default = "default_path")]
+    pub path: String,
+
+    /// Glob pattern that one of the entry names must match.
+    pub pattern: Option<String>,
+}
+
+impl EtcHostsSource {
+    /// Lists one host per entry, named by its canonical (first) name.
+    /// Loopback and multicast entries are left out, and so are those with
+    /// invalid or scoped addresses, with a warning. Besides the hostname,
+    /// each host carries `address` and space separated `aliases` variables.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let mut content = String::new();
+
+        File::open(&self.path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .chain_err(|| format!("Unable to read hosts file at {}", self.path))?;
+
+        Ok(self.parse(&content))
+    }
+
+    fn parse(&self, content: &str) -> Vec<Host> {
+        let mut found = Vec::new();
+
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+
+            let (address, names): (&str, Vec<&str>) = match fields.next() {
+                Some(address) => (address, fields.collect()),
+                None => continue,
+            };
+
+            if names.is_empty() {
+                continue;
+            }
+
+            // scoped addresses such as fe80::1%lo0 are left out along with
+            // the unparsable ones
+            let ip = match address.parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => {
+                    let _ = writeln!(io::stderr(), "Skipping entry {} with invalid address '{}' in hosts file {}", names[0], address, self.path);
+                    continue;
+                },
+            };
+
+            if ip.is_loopback() || ip.is_multicast() {
+                continue;
+            }
+
+            if let Some(ref pattern) = self.pattern {
+                if !names.iter().any(|name| hosts::glob_match(pattern, name)) {
+                    continue;
+                }
+            }
+
+            let mut host = Host::new(names[0]);
+            host.vars.insert("address".into(), address.to_owned());
+            host.vars.insert("aliases".into(), names[1..].join(" "));
+            found.push(host);
+        }
+
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_special_and_invalid_entries() {
+        let source = EtcHostsSource { path: default_path(), pattern: None };
+        let hosts = source.parse("127.0.0.1 localhost\n::1 localhost ip6-localhost\nfe80::1%lo0 localhost\n\
+            ff02::1 ip6-allnodes\n10.0.0.300 typo\n10.0.0.5 web-1.internal web-1 # primary\n10.0.0.6\n");
+
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name, "web-1.internal");
+        assert_eq!(hosts[0].vars["address"], "10.0.0.5");
+        assert_eq!(hosts[0].vars["aliases"], "web-1");
+    }
+
+    #[test]
+    fn parse_matches_any_name_against_the_pattern() {
+        let source = EtcHostsSource { path: default_path(), pattern: Some("db-*".to_owned()) };
+        let hosts = source.parse("10.0.0.5 web-1.internal\n10.0.0.7 primary.internal db-1\n");
+
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name, "primary.internal");
+    }
+}
@@ -0,0 +1,128 @@
+//! Discovers running pods through `kubectl get pods`.
+
+use errors::*;
+use hosts::Host;
+use serde_json::{self, Value};
+use std::io::{self, Write};
+use super::run_program;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PodAddress {
+    #[default]
+    Name,
+    Ip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct KubernetesSource {
+    /// Namespace to list pods from, or all namespaces if absent.
+    pub namespace: Option<String>,
+
+    /// Label selector, e.g. `app=web,tier!=canary`.
+    pub selector: Option<String>,
+
+    /// kubeconfig context to use instead of the current one.
+    pub context: Option<String>,
+
+    /// Pod attribute used as the hostname.
+    #[serde(default)]
+    pub address: PodAddress,
+}
+
+impl KubernetesSource {
+    fn args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec!["get".into(), "pods".into(), "--output".into(), "json".into()];
+
+        match self.namespace {
+            Some(ref namespace) => {
+                args.push("--namespace".into());
+                args.push(namespace.clone());
+            },
+            None => args.push("--all-namespaces".into()),
+        }
+
+        if let Some(ref selector) = self.selector {
+            args.push("--selector".into());
+            args.push(selector.clone());
+        }
+
+        if let Some(ref context) = self.context {
+            args.push("--context".into());
+            args.push(context.clone());
+        }
+
+        args
+    }
+
+    /// Lists the running pods, leaving out those without an IP when they are
+    /// named by it. Besides the hostname, each host carries `pod_name`,
+    /// `pod_ip`, `namespace` and `node` variables.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let stdout = run_program("kubectl", &self.args())
+            .chain_err(|| "Unable to list Kubernetes pods")?;
+
+        let listed: Value = serde_json::from_str(&stdout)
+            .chain_err(|| "Unable to parse Kubernetes pod list")?;
+
+        Ok(self.running_pods(&listed))
+    }
+
+    fn running_pods(&self, listed: &Value) -> Vec<Host> {
+        let pods = listed["items"].as_array().into_iter()
+            .flat_map(|items| items.iter())
+            .filter(|pod| pod["status"]["phase"] == "Running");
+
+        let mut hosts = Vec::new();
+
+        for pod in pods {
+            let text = |value: &Value| value.as_str().unwrap_or("").to_owned();
+            let pod_name = text(&pod["metadata"]["name"]);
+            let pod_ip = text(&pod["status"]["podIP"]);
+
+            if self.address == PodAddress::Ip && pod_ip.is_empty() {
+                let _ = writeln!(io::stderr(), "Skipping Kubernetes pod {} without status.podIP", pod_name);
+                continue;
+            }
+
+            let mut host = Host::new(match self.address {
+                PodAddress::Name => pod_name.clone(),
+                PodAddress::Ip => pod_ip.clone(),
+            });
+
+            host.vars.insert("pod_name".into(), pod_name);
+            host.vars.insert("pod_ip".into(), pod_ip);
+            host.vars.insert("namespace".into(), text(&pod["metadata"]["namespace"]));
+            host.vars.insert("node".into(), text(&pod["spec"]["nodeName"]));
+            hosts.push(host);
+        }
+
+        hosts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_pods_skip_pods_without_an_ip() {
+        let listed: Value = serde_json::from_str(r#"{"items": [
+            {"metadata": {"name": "web-1", "namespace": "prod"}, "spec": {"nodeName": "node-a"}, "status": {"phase": "Running", "podIP": "10.1.0.5"}},
+            {"metadata": {"name": "web-2", "namespace": "prod"}, "status": {"phase": "Running"}},
+            {"metadata": {"name": "web-3", "namespace": "prod"}, "status": {"phase": "Pending"}}
+        ]}"#).unwrap();
+
+        let by_ip = KubernetesSource { address: PodAddress::Ip, ..KubernetesSource::default() };
+        let hosts = by_ip.running_pods(&listed);
+
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].name, "10.1.0.5");
+        assert_eq!(hosts[0].vars["pod_name"], "web-1");
+        assert_eq!(hosts[0].vars["node"], "node-a");
+
+        let names: Vec<String> = KubernetesSource::default().running_pods(&listed).into_iter().map(|host| host.name).collect();
+        assert_eq!(names, vec!["web-1", "web-2"]);
+    }
+}
@@ -0,0 +1,118 @@
+//! Discovers the instances of an mDNS/DNS-SD service type on the local
+//! network through `avahi-browse`.
+
+use errors::*;
+use hosts::Host;
+use std::collections::HashSet;
+use super::run_program;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MdnsAddress {
+    #[default]
+    Ip,
+    Hostname,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MdnsSource {
+    /// Service type to browse, e.g. `_ssh._tcp`.
+    pub service: String,
+
+    /// Browsing domain, `local` if absent.
+    pub domain: Option<String>,
+
+    /// Also lists IPv6 addresses.
+    #[serde(default)]
+    pub ipv6: bool,
+
+    /// Instance address used as the hostname.
+    #[serde(default)]
+    pub address: MdnsAddress,
+}
+
+impl MdnsSource {
+    /// Lists one host per resolved service address. Besides the hostname,
+    /// each host carries `mdns_name`, `ip`, `hostname`, `port`, `interface`
+    /// and `txt` variables.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        // resolves, terminates once the cache is dumped and prints
+        // semicolon separated records
+        let mut args = vec!["--resolve".to_owned(), "--terminate".to_owned(), "--parsable".to_owned()];
+
+        if let Some(ref domain) = self.domain {
+            args.push(format!("--domain={}", domain));
+        }
+
+        args.push(self.service.clone());
+
+        let stdout = run_program("avahi-browse", &args)
+            .chain_err(|| format!("Unable to browse mDNS service '{}'", self.service))?;
+
+        let mut hosts = Vec::new();
+        let mut seen = HashSet::new();
+
+        for line in stdout.lines() {
+            let fields: Vec<String> = line.split(';').map(unescape).collect();
+
+            // only `=` records are resolved, each is
+            // =;interface;protocol;name;type;domain;hostname;address;port;txt
+            if fields.len() < 9 || fields[0] != "=" {
+                continue;
+            }
+
+            if fields[2] == "IPv6" && !self.ipv6 {
+                continue;
+            }
+
+            let name = match self.address {
+                MdnsAddress::Ip => fields[7].clone(),
+                MdnsAddress::Hostname => fields[6].clone(),
+            };
+
+            // the same instance is reported on every interface
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            let mut host = Host::new(name);
+            host.vars.insert("mdns_name".into(), fields[3].clone());
+            host.vars.insert("interface".into(), fields[1].clone());
+            host.vars.insert("hostname".into(), fields[6].clone());
+            host.vars.insert("ip".into(), fields[7].clone());
+            host.vars.insert("port".into(), fields[8].clone());
+            host.vars.insert("txt".into(), fields.get(9).cloned().unwrap_or_default());
+            hosts.push(host);
+        }
+
+        Ok(hosts)
+    }
+}
+
+/// Undoes the `\DDD` decimal escapes that avahi uses in parsable output.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let code = match bytes.get(i + 1..i + 4) {
+            Some(digits) if bytes[i] == b'\\' => String::from_utf8_lossy(digits).parse::<u8>().ok(),
+            _ => None,
+        };
+
+        match code {
+            Some(code) => {
+                out.push(code);
+                i += 4;
+            },
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,30 @@
+//! Dynamic host sources, which discover hosts at runtime from system files or
+//! by querying external systems through their command line tools.
+
+pub mod consul;
+pub mod ec2;
+pub mod etc_hosts;
+pub mod kubernetes;
+pub mod mdns;
+pub mod srv;
+pub mod ssh_config;
+pub mod terraform;
+
+use errors::*;
+use std::process::Command;
+
+/// Runs `program` with `args` directly (without a shell) and returns its
+/// stdout, failing if it cannot be launched or exits unsuccessfully.
+fn run_program(program: &str, args: &[String]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .chain_err(|| format!("Unable to launch '{}', is it installed and on the PATH?", program))?;
+
+    if !output.status.success() {
+        bail!("'{} {}' failed with {}: {}",
+            program, args.join(" "), output.status, String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,66 @@
+//! Resolves DNS SRV records through `dig`.
+
+use errors::*;
+use hosts::Host;
+use super::run_program;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SrvSource {
+    /// SRV record name, e.g. `_ssh._tcp.example.com`.
+    pub name: String,
+
+    /// DNS server to query instead of the system resolver.
+    pub nameserver: Option<String>,
+}
+
+impl SrvSource {
+    /// Lists one host per SRV target, ordered by priority and then by
+    /// descending weight. Besides the hostname, each host carries `target`,
+    /// `port`, `priority` and `weight` variables.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let mut args = vec!["+short".to_owned(), "SRV".to_owned(), self.name.clone()];
+
+        if let Some(ref nameserver) = self.nameserver {
+            args.push(format!("@{}", nameserver));
+        }
+
+        let stdout = run_program("dig", &args)
+            .chain_err(|| format!("Unable to resolve SRV record '{}'", self.name))?;
+
+        let mut records = Vec::new();
+
+        for line in stdout.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            // dig may also print CNAME targets and comments, which are not
+            // SRV records
+            if fields.len() != 4 {
+                continue;
+            }
+
+            let numbers: Vec<u32> = fields[..3].iter().filter_map(|field| field.parse().ok()).collect();
+
+            if numbers.len() != 3 {
+                continue;
+            }
+
+            records.push((numbers[0], numbers[1], numbers[2], fields[3].trim_end_matches('.').to_owned()));
+        }
+
+        records.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+        let hosts = records.into_iter()
+            .map(|(priority, weight, port, target)| {
+                let mut host = Host::new(target.clone());
+                host.vars.insert("target".into(), target);
+                host.vars.insert("port".into(), port.to_string());
+                host.vars.insert("priority".into(), priority.to_string());
+                host.vars.insert("weight".into(), weight.to_string());
+                host
+            })
+            .collect();
+
+        Ok(hosts)
+    }
+}
@@ -0,0 +1,118 @@
+//! Reads the `Host` entries of an OpenSSH client config.
+
+use errors::*;
+use hosts::{self, Host};
+use std::env;
+use std::fs::File;
+use std::io::Read;
+
+fn default_path() -> String {
+    "~/.ssh/config".to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SshConfigSource {
+    #[serde(default = "default_path")]
+    pub path: String,
+
+    /// Glob pattern that the host alias must match.
+    pub pattern: Option<String>,
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}/{}", home.trim_end_matches('/'), rest),
+        _ => path.to_owned(),
+    }
+}
+
+/// A `Host` block along with its options, or a `Match` block with no
+/// patterns, which is never applied.
+struct Block<'a> {
+    patterns: Vec<&'a str>,
+    options: Vec<(String, &'a str)>,
+}
+
+impl<'a> Block<'a> {
+    fn applies_to(&self, alias: &str) -> bool {
+        let negated = self.patterns.iter()
+            .filter_map(|pattern| pattern.strip_prefix('!'))
+            .any(|pattern| hosts::glob_match(pattern, alias));
+
+        !negated && self.patterns.iter().any(|pattern| hosts::glob_match(pattern, alias))
+    }
+}
+
+impl SshConfigSource {
+    /// Lists one host per concrete `Host` alias, skipping wildcard and
+    /// negated patterns. The options applying to each alias, including those
+    /// of matching wildcard blocks, become lowercased `ssh.<option>`
+    /// variables, e.g. `{ssh.hostname}` and `{ssh.user}`. `Include`
+    /// directives are not followed.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let path = expand_home(&self.path);
+        let mut content = String::new();
+
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .chain_err(|| format!("Unable to read SSH config at {}", path))?;
+
+        let mut blocks: Vec<Block> = Vec::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.find([' ', '\t', '=']) {
+                Some(split) => (&line[..split], line[split + 1..].trim_start_matches([' ', '\t', '=']).trim()),
+                None => (line, ""),
+            };
+
+            match key.to_lowercase().as_str() {
+                "host" => blocks.push(Block { patterns: value.split_whitespace().collect(), options: Vec::new() }),
+                "match" => blocks.push(Block { patterns: Vec::new(), options: Vec::new() }),
+
+                // options before the first block apply to every host
+                key => {
+                    if blocks.is_empty() {
+                        blocks.push(Block { patterns: vec!["*"], options: Vec::new() });
+                    }
+
+                    let block = blocks.last_mut().unwrap();
+                    block.options.push((key.to_owned(), value.trim_matches('"')));
+                },
+            }
+        }
+
+        let mut aliases: Vec<&str> = Vec::new();
+
+        for alias in blocks.iter().flat_map(|block| block.patterns.iter()) {
+            if !hosts::is_glob(alias) && !alias.starts_with('!') && !aliases.contains(alias) {
+                aliases.push(alias);
+            }
+        }
+
+        let found = aliases.into_iter()
+            .filter(|alias| self.pattern.as_ref().is_none_or(|pattern| hosts::glob_match(pattern, alias)))
+            .map(|alias| {
+                let mut host = Host::new(alias);
+
+                for block in blocks.iter().filter(|block| block.applies_to(alias)) {
+                    for &(ref key, value) in &block.options {
+                        // as in ssh, the first value given for an option wins
+                        host.vars.entry(format!("ssh.{}", key))
+                            .or_insert_with(|| value.to_owned());
+                    }
+                }
+
+                host
+            })
+            .collect();
+
+        Ok(found)
+    }
+}
@@ -0,0 +1,129 @@
+//! Extracts hosts from the resources of a Terraform state, read either from
+//! a state file or through `terraform state pull`.
+
+use errors::*;
+use hosts::{self, Host};
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use super::run_program;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TerraformSource {
+    /// Path to the state file, the state is pulled from the backend of the
+    /// working directory if absent.
+    pub state: Option<String>,
+
+    /// Terraform working directory to pull the state in.
+    pub dir: Option<String>,
+
+    /// Resource type to take hosts from, e.g. `aws_instance`.
+    pub resource_type: String,
+
+    /// Resource name to restrict to, e.g. `web` for `aws_instance.web`.
+    pub resource_name: Option<String>,
+
+    /// Dotted path of the attribute holding the address, e.g. `private_ip`
+    /// or `network_interface.0.access_config.0.nat_ip`.
+    pub attribute: String,
+}
+
+impl TerraformSource {
+    fn read_state(&self) -> Result<String> {
+        match self.state {
+            Some(ref state) => {
+                let mut content = String::new();
+
+                File::open(state)
+                    .and_then(|mut file| file.read_to_string(&mut content))
+                    .chain_err(|| format!("Unable to read Terraform state at {}", state))?;
+
+                Ok(content)
+            },
+
+            None => {
+                let mut args = Vec::new();
+
+                if let Some(ref dir) = self.dir {
+                    args.push(format!("-chdir={}", dir));
+                }
+
+                args.push("state".to_owned());
+                args.push("pull".to_owned());
+
+                run_program("terraform", &args)
+                    .chain_err(|| "Unable to pull Terraform state")
+            },
+        }
+    }
+
+    /// Lists one host per instance of the matching managed resources, named
+    /// by the attribute value. Besides the hostname, each host carries the
+    /// `resource` address and `attr.<path>` variables for its attributes.
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        let state: Value = serde_json::from_str(&self.read_state()?)
+            .chain_err(|| "Unable to parse Terraform state")?;
+
+        let resources = state["resources"].as_array().into_iter()
+            .flat_map(|resources| resources.iter())
+            .filter(|resource| resource["mode"] == "managed" && resource["type"] == self.resource_type.as_str())
+            .filter(|resource| self.resource_name.as_ref().is_none_or(|name| resource["name"] == name.as_str()));
+
+        let mut hosts = Vec::new();
+
+        for resource in resources {
+            let mut base = format!("{}.{}", self.resource_type, resource["name"].as_str().unwrap_or(""));
+
+            if let Some(module) = resource["module"].as_str() {
+                base = format!("{}.{}", module, base);
+            }
+
+            for instance in resource["instances"].as_array().into_iter().flat_map(|instances| instances.iter()) {
+                let address = match instance["index_key"] {
+                    Value::Null => base.clone(),
+                    Value::String(ref key) => format!("{}[\"{}\"]", base, key),
+                    ref key => format!("{}[{}]", base, key),
+                };
+
+                let attributes = &instance["attributes"];
+
+                let name = match lookup(attributes, &self.attribute).and_then(as_text) {
+                    Some(name) => name,
+                    None => {
+                        let _ = writeln!(io::stderr(), "Skipping Terraform resource {} without {}", address, self.attribute);
+                        continue;
+                    },
+                };
+
+                let mut vars = BTreeMap::new();
+                hosts::flatten_json("attr", attributes, &mut vars);
+                vars.remove("attr");
+                vars.insert("resource".into(), address);
+
+                hosts.push(Host { vars, ..Host::new(name) });
+            }
+        }
+
+        Ok(hosts)
+    }
+}
+
+/// Follows a dotted path through objects, with numeric segments indexing
+/// into arrays.
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, segment| match *value {
+        Value::Array(ref items) => segment.parse::<usize>().ok().and_then(|index| items.get(index)),
+        Value::Object(ref map) => map.get(segment),
+        _ => None,
+    })
+}
+
+fn as_text(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) if !s.is_empty() => Some(s.clone()),
+        Value::Number(ref n) => Some(n.to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,132 @@
+//! Ordering of host groups, where a group only starts once every group it
+//! depends on has succeeded.
+
+use errors::*;
+use std::collections::{BTreeMap, HashSet};
+
+pub struct Stages {
+    depends_on: BTreeMap<String, Vec<String>>,
+
+    /// Hostnames of each ordered group.
+    members: BTreeMap<String, HashSet<String>>,
+
+    /// Stage of each ordered group, after the stages of its dependencies.
+    stages: BTreeMap<String, usize>,
+}
+
+impl Stages {
+    /// Orders the groups, which must not depend on each other in a cycle.
+    pub fn new(depends_on: &BTreeMap<String, Vec<String>>, members: BTreeMap<String, HashSet<String>>) -> Result<Stages> {
+        let mut stages = BTreeMap::new();
+
+        for group in members.keys() {
+            stage_of(group, depends_on, &mut stages, &mut Vec::new())?;
+        }
+
+        Ok(Stages { depends_on: depends_on.clone(), members, stages })
+    }
+
+    /// Ordered groups that the host is in.
+    pub fn groups<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.members.iter()
+            .filter(move |&(_, members)| members.contains(name))
+            .map(|(group, _)| group.as_str())
+    }
+
+    /// Stage the host runs in, the latest of its groups, or the first one
+    /// if it is in none of them.
+    pub fn stage(&self, name: &str) -> usize {
+        self.groups(name).map(|group| self.stages[group]).max().unwrap_or(0)
+    }
+
+    /// Group that the host waits on and that did not succeed, if any.
+    pub fn blocker<'a>(&'a self, name: &'a str, unsucceeded: &HashSet<String>) -> Option<&'a str> {
+        self.groups(name)
+            .filter_map(|group| self.depends_on.get(group))
+            .flat_map(|dependencies| dependencies.iter())
+            .find(|dependency| unsucceeded.contains(*dependency))
+            .map(|dependency| dependency.as_str())
+    }
+}
+
+fn stage_of(group: &str, depends_on: &BTreeMap<String, Vec<String>>, stages: &mut BTreeMap<String, usize>, path: &mut Vec<String>) -> Result<usize> {
+    if let Some(&stage) = stages.get(group) {
+        return Ok(stage);
+    }
+
+    if path.iter().any(|visited| visited == group) {
+        bail!("Group dependencies form a cycle: {} -> {}", path.join(" -> "), group);
+    }
+
+    path.push(group.to_owned());
+
+    let mut stage = 0;
+
+    for dependency in depends_on.get(group).into_iter().flat_map(|dependencies| dependencies.iter()) {
+        stage = stage.max(stage_of(dependency, depends_on, stages, path)? + 1);
+    }
+
+    path.pop();
+    stages.insert(group.to_owned(), stage);
+
+    Ok(stage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depends_on(pairs: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        pairs.iter()
+            .map(|&(group, dependencies)| (group.to_owned(), dependencies.iter().map(|&dependency| dependency.to_owned()).collect()))
+            .collect()
+    }
+
+    fn members(pairs: &[(&str, &[&str])]) -> BTreeMap<String, HashSet<String>> {
+        pairs.iter()
+            .map(|&(group, hosts)| (group.to_owned(), hosts.iter().map(|&host| host.to_owned()).collect()))
+            .collect()
+    }
+
+    fn stages() -> Stages {
+        let depends_on = depends_on(&[("app", &["db"]), ("web", &["app", "cache"])]);
+        let members = members(&[("db", &["db-1"]), ("cache", &["cache-1"]), ("app", &["app-1", "both-1"]), ("web", &["web-1", "both-1"])]);
+
+        Stages::new(&depends_on, members).unwrap()
+    }
+
+    #[test]
+    fn stages_follow_dependencies() {
+        let stages = stages();
+
+        assert_eq!(stages.stage("db-1"), 0);
+        assert_eq!(stages.stage("cache-1"), 0);
+        assert_eq!(stages.stage("app-1"), 1);
+        assert_eq!(stages.stage("web-1"), 2);
+
+        // hosts in several groups run with the latest of them, and hosts in
+        // none of them first
+        assert_eq!(stages.stage("both-1"), 2);
+        assert_eq!(stages.stage("other-1"), 0);
+    }
+
+    #[test]
+    fn blocker_names_a_dependency_that_did_not_succeed() {
+        let stages = stages();
+        let unsucceeded: HashSet<String> = ["cache".to_owned()].iter().cloned().collect();
+
+        assert_eq!(stages.blocker("web-1", &unsucceeded), Some("cache"));
+        assert_eq!(stages.blocker("app-1", &unsucceeded), None);
+        assert_eq!(stages.blocker("cache-1", &unsucceeded), None);
+        assert_eq!(stages.blocker("web-1", &HashSet::new()), None);
+    }
+
+    #[test]
+    fn rejects_cyclic_dependencies() {
+        let cyclic = depends_on(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        let error = Stages::new(&cyclic, members(&[("a", &["a-1"])])).err().unwrap();
+
+        assert_eq!(error.to_string(), "Group dependencies form a cycle: a -> b -> c -> a");
+        assert!(Stages::new(&depends_on(&[("a", &["a"])]), members(&[("a", &[])])).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Run state file, recording how each host ended so that an interrupted run
+//! can be resumed without the hosts that already succeeded.
+
+use errors::*;
+use hosts::Host;
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum HostStatus {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct StateContent {
+    hosts: BTreeMap<String, HostStatus>,
+}
+
+pub struct RunState {
+    path: PathBuf,
+    content: StateContent,
+}
+
+impl RunState {
+    /// Empty state, written to `path` as hosts end.
+    pub fn new<P: AsRef<Path>>(path: P) -> RunState {
+        RunState { path: path.as_ref().to_owned(), content: StateContent::default() }
+    }
+
+    /// State of a previous run, kept up to date as the resumed hosts end.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RunState> {
+        let path = path.as_ref();
+        let mut content = String::new();
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .chain_err(|| format!("Unable to read run state at {}", path.display()))?;
+
+        let content = serde_json::from_str(&content)
+            .chain_err(|| format!("Unable to parse run state at {}", path.display()))?;
+
+        Ok(RunState { path: path.to_owned(), content })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn succeeded(&self, key: &str) -> bool {
+        self.content.hosts.get(key) == Some(&HostStatus::Succeeded)
+    }
+
+    /// Records the status of the host and rewrites the state file, through
+    /// a temporary file so that an interruption cannot leave it truncated.
+    pub fn record(&mut self, key: &str, status: HostStatus) -> Result<()> {
+        self.content.hosts.insert(key.to_owned(), status);
+
+        let content = serde_json::to_string_pretty(&self.content)
+            .chain_err(|| "Unable to serialize run state")?;
+
+        let tmp_path = self.path.with_extension("tmp");
+
+        File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .chain_err(|| format!("Unable to write run state to {}", self.path.display()))
+    }
+}
+
+/// Key of each host in the state, its name, followed by `#<n>` from the
+/// second time it repeats, e.g. for matrix combinations.
+pub fn host_keys(hosts: &[Host]) -> Vec<String> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+
+    hosts.iter()
+        .map(|host| {
+            let count = seen.entry(&host.name).or_insert(0);
+            *count += 1;
+
+            if *count == 1 {
+                host.name.clone()
+            } else {
+                format!("{}#{}", host.name, count)
+            }
+        })
+        .collect()
+}
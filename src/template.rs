@@ -0,0 +1,1041 @@
+//! Rendering of the command for each host.
+//!
+//! Commands containing `{{` or `{%` are rendered as templates:
+//!
+//! - `{{ expr }}` outputs an expression, such as a variable (`{{ host }}`,
+//!   `{{ tag.Role }}`), a quoted string or a `[...]` list.
+//! - `{% if cond %}`, `{% elif cond %}`, `{% else %}` and `{% endif %}`
+//!   choose a branch. Conditions may compare with `==`, `!=`, `<`, `<=`, `>`
+//!   and `>=` and combine with `and`, `or` and `not`. Empty strings and
+//!   `false` are false, and undefined variables are empty.
+//! - `{% for x in expr %}` ... `{% endfor %}` repeats for each item of a
+//!   list, or each whitespace separated word of a string, with `loop.index`,
+//!   `loop.index0`, `loop.first` and `loop.last` available inside.
+//! - `{# ... #}` is a comment.
+//! - `now(format)`, `uuid()` and `rand_int(low, high)` give the local time
+//!   in strftime format, a random UUID and a random integer.
+//! - `expr | filter` or `expr | filter(args)` transforms a value:
+//!   `default(value)` stands in for an undefined or empty value, `upper`,
+//!   `lower`, `trim`, `shellquote` (or `shell_quote`), `trim_domain` (keeping
+//!   the name up to the first dot) and `replace(from, to)` change text,
+//!   `split(sep)` makes a list, `join(sep)` joins one again, and `first`,
+//!   `last` and `length` inspect lists or text. `raw` outputs a value
+//!   unquoted when values are quoted for the shell.
+//!
+//! Other commands keep the plain substitution of the hostname tag and of
+//! `{var}` references.
+
+use errors::*;
+use hosts::Host;
+#[cfg(unix)]
+use libc;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Longest output of `now()`, past which its format is taken for a mistake.
+#[cfg(unix)]
+const MAX_TIME_LEN: usize = 64 * 1024;
+
+/// Place of a host within a run.
+#[derive(Clone, Copy, Debug)]
+pub struct Position {
+    /// Zero based index of the host.
+    pub index: usize,
+    pub total: usize,
+}
+
+/// How values are substituted into commands.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Replaced by the host address in plain commands.
+    pub tag: String,
+
+    /// Quotes substituted values for the shell.
+    pub shell_quote: bool,
+}
+
+fn builtins(host: &Host, position: Position) -> [(&'static str, String); 8] {
+    // IP addresses have no domain to split off
+    let (host_short, host_domain) = match host.addr().parse::<IpAddr>() {
+        Ok(_) => (host.addr(), ""),
+        Err(_) => {
+            let mut labels = host.addr().splitn(2, '.');
+            (labels.next().unwrap_or(""), labels.next().unwrap_or(""))
+        },
+    };
+
+    [
+        ("name", host.name.clone()),
+        ("addr", host.addr().to_owned()),
+        ("host", host.addr().to_owned()),
+        ("host_short", host_short.to_owned()),
+        ("host_domain", host_domain.to_owned()),
+        ("index", position.index.to_string()),
+        ("number", (position.index + 1).to_string()),
+        ("total", position.total.to_string()),
+    ]
+}
+
+/// Variables of `host` for templates, with the host variables also
+/// available as `vars.<name>`.
+fn variables(host: &Host, position: Position) -> BTreeMap<String, Val> {
+    let mut vars: BTreeMap<String, Val> = builtins(host, position).iter()
+        .map(|&(key, ref value)| (key.to_owned(), Val::Str(value.clone())))
+        .collect();
+
+    for (key, value) in &host.vars {
+        vars.insert(key.clone(), Val::Str(value.clone()));
+        vars.insert(format!("vars.{}", key), Val::Str(value.clone()));
+    }
+
+    vars
+}
+
+/// Renders `template` for `host`. Besides the host variables, `name` and
+/// `addr` give the host name and address, `host` the substituted host value,
+/// `host_short` and `host_domain` its first label and the rest, `index` and
+/// `number` its zero and one based position, and `total` the number of
+/// hosts, unless the host has variables of those names.
+pub fn render(template: &str, host: &Host, position: Position, options: &Options) -> Result<String> {
+    if !is_template(template) {
+        return Ok(render_plain(template, host, &builtins(host, position), options));
+    }
+
+    let mut vars = variables(host, position);
+    let nodes = parse(template)?;
+    let mut out = String::new();
+    render_nodes(&nodes, &mut vars, options, &mut out)?;
+
+    Ok(out)
+}
+
+/// Evaluates the condition `expr` for `host`, with the same variables as
+/// templates.
+pub fn check(expr: &str, host: &Host, position: Position) -> Result<bool> {
+    let parsed = parse_expr(expr, expr, 0)?;
+    eval_cond(&parsed, &variables(host, position))
+}
+
+fn is_template(template: &str) -> bool {
+    template.contains("{{") || template.contains("{%")
+}
+
+/// Checks whether `template` refers to the host at all, through `tag`, the
+/// `{name}`, `{addr}`, `{host}`, `{host_short}` or `{ip}` variables or
+/// template syntax.
+pub fn mentions_host(template: &str, tag: &str) -> bool {
+    is_template(template) || ["{name}", "{addr}", "{host}", "{host_short}", "{ip}", tag].iter().any(|tag| template.contains(tag))
+}
+
+/// Replaces the tag with the host address and every `{var}` with the value
+/// of the variable of that name, in a single pass so that the values put in
+/// are never substituted into themselves.
+fn render_plain(template: &str, host: &Host, builtins: &[(&str, String)], options: &Options) -> String {
+    let quote = |value: &str| if options.shell_quote {
+        shell_quote(value)
+    } else {
+        value.to_owned()
+    };
+
+    let value_of = |key: &str| host.vars.get(key).map(|value| value.as_str())
+        .or_else(|| builtins.iter().find(|&&(builtin, _)| builtin == key).map(|(_, value)| value.as_str()));
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(c) = rest.chars().next() {
+        if !options.tag.is_empty() && rest.starts_with(&options.tag) {
+            rendered += &quote(host.addr());
+            rest = &rest[options.tag.len()..];
+            continue;
+        }
+
+        if c == '{' {
+            let value = rest[1..].find('}').and_then(|end| value_of(&rest[1..end + 1]).map(|value| (end, value)));
+
+            if let Some((end, value)) = value {
+                rendered += &quote(value);
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+
+        rendered.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    rendered
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Val {
+    Str(String),
+    List(Vec<Val>),
+    Bool(bool),
+}
+
+impl Val {
+    fn is_truthy(&self) -> bool {
+        match *self {
+            Val::Str(ref s) => !s.is_empty() && s != "false",
+            Val::List(ref items) => !items.is_empty(),
+            Val::Bool(b) => b,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        match *self {
+            Val::Str(ref s) => s.clone(),
+            Val::List(ref items) => items.iter().map(|item| item.to_text()).collect::<Vec<_>>().join(" "),
+            Val::Bool(b) => b.to_string(),
+        }
+    }
+
+    fn items(&self) -> Vec<Val> {
+        match *self {
+            Val::Str(ref s) => s.split_whitespace().map(|word| Val::Str(word.to_owned())).collect(),
+            Val::List(ref items) => items.clone(),
+            Val::Bool(_) => vec![self.clone()],
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For(String, Expr, Vec<Node>),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Var(String),
+    Literal(Val),
+    List(Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(String, Box<Expr>, Box<Expr>),
+    Filter(Box<Expr>, String, Vec<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// Text or tag of a template, tags along with the offset of their contents.
+enum Piece {
+    Text(String),
+    Output(String, usize),
+    Tag(String, usize),
+}
+
+fn syntax_error<T>(template: &str, offset: usize, msg: &str) -> Result<T> {
+    let consumed = &template[..offset.min(template.len())];
+    let line = consumed.matches('\n').count() + 1;
+    let col = consumed.chars().rev().take_while(|&c| c != '\n').count() + 1;
+
+    bail!(ErrorKind::Syntax(line, col, msg.to_owned()))
+}
+
+fn split_pieces(template: &str) -> Result<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    let mut pos = 0;
+
+    while pos < template.len() {
+        let rest = &template[pos..];
+
+        let start = match ["{{", "{%", "{#"].iter().filter_map(|open| rest.find(open)).min() {
+            Some(start) => start,
+            None => {
+                pieces.push(Piece::Text(rest.to_owned()));
+                break;
+            },
+        };
+
+        if start > 0 {
+            pieces.push(Piece::Text(rest[..start].to_owned()));
+        }
+
+        let open = &rest[start..start + 2];
+
+        let close = match open {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+
+        let inner_start = pos + start + 2;
+
+        let inner_len = match template[inner_start..].find(close) {
+            Some(len) => len,
+            None => return syntax_error(template, pos + start, &format!("unclosed '{}'", open)),
+        };
+
+        let inner = template[inner_start..inner_start + inner_len].to_owned();
+
+        match open {
+            "{{" => pieces.push(Piece::Output(inner, inner_start)),
+            "{%" => pieces.push(Piece::Tag(inner, inner_start)),
+            _ => (),
+        }
+
+        pos = inner_start + inner_len + 2;
+    }
+
+    Ok(pieces)
+}
+
+fn parse(template: &str) -> Result<Vec<Node>> {
+    let pieces = split_pieces(template)?;
+    let mut pos = 0;
+    let (nodes, end) = parse_block(template, &pieces, &mut pos)?;
+
+    if let Some((keyword, offset)) = end {
+        return syntax_error(template, offset, &format!("unexpected '{}'", keyword));
+    }
+
+    Ok(nodes)
+}
+
+/// Tag that ended a block, along with the offset of its contents.
+type BlockEnd = Option<(String, usize)>;
+
+/// Parses nodes until a tag that closes or continues an enclosing block,
+/// which is returned along with its remaining contents.
+fn parse_block(template: &str, pieces: &[Piece], pos: &mut usize) -> Result<(Vec<Node>, BlockEnd)> {
+    let mut nodes = Vec::new();
+
+    while *pos < pieces.len() {
+        let piece = &pieces[*pos];
+        *pos += 1;
+
+        match *piece {
+            Piece::Text(ref text) => nodes.push(Node::Text(text.clone())),
+            Piece::Output(ref expr, offset) => nodes.push(Node::Output(parse_expr(template, expr, offset)?)),
+            Piece::Tag(ref tag, offset) => {
+                let tag = tag.trim();
+                let (keyword, rest) = match tag.find(char::is_whitespace) {
+                    Some(end) => (&tag[..end], tag[end..].trim()),
+                    None => (tag, ""),
+                };
+
+                match keyword {
+                    "if" => nodes.push(parse_if(template, pieces, pos, rest, offset)?),
+                    "for" => nodes.push(parse_for(template, pieces, pos, rest, offset)?),
+                    "elif" | "else" | "endif" | "endfor" => {
+                        return Ok((nodes, Some((tag.to_owned(), offset))));
+                    },
+                    _ => return syntax_error(template, offset, &format!("unknown tag '{}'", keyword)),
+                }
+            },
+        }
+    }
+
+    Ok((nodes, None))
+}
+
+fn parse_if(template: &str, pieces: &[Piece], pos: &mut usize, cond: &str, offset: usize) -> Result<Node> {
+    let mut branches = Vec::new();
+    let mut cond = parse_expr(template, cond, offset)?;
+
+    loop {
+        let (body, end) = parse_block(template, pieces, pos)?;
+
+        match end {
+            Some((ref tag, end_offset)) if tag.starts_with("elif") => {
+                branches.push((cond, body));
+                cond = parse_expr(template, tag["elif".len()..].trim(), end_offset)?;
+            },
+            Some((ref tag, _)) if tag == "else" => {
+                branches.push((cond, body));
+                let (otherwise, end) = parse_block(template, pieces, pos)?;
+
+                return match end {
+                    Some((ref tag, _)) if tag == "endif" => Ok(Node::If(branches, otherwise)),
+                    _ => syntax_error(template, offset, "'if' is missing its 'endif'"),
+                };
+            },
+            Some((ref tag, _)) if tag == "endif" => {
+                branches.push((cond, body));
+                return Ok(Node::If(branches, Vec::new()));
+            },
+            _ => return syntax_error(template, offset, "'if' is missing its 'endif'"),
+        }
+    }
+}
+
+fn parse_for(template: &str, pieces: &[Piece], pos: &mut usize, header: &str, offset: usize) -> Result<Node> {
+    let mut parts = header.splitn(2, " in ");
+    let var = parts.next().unwrap_or("").trim();
+
+    let iterable = match parts.next() {
+        Some(iterable) if is_identifier(var) => parse_expr(template, iterable, offset)?,
+        _ => return syntax_error(template, offset, "expected 'for <name> in <expr>'"),
+    };
+
+    let (body, end) = parse_block(template, pieces, pos)?;
+
+    match end {
+        Some((ref tag, _)) if tag == "endfor" => Ok(Node::For(var.to_owned(), iterable, body)),
+        _ => syntax_error(template, offset, "'for' is missing its 'endfor'"),
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(String),
+    Op(String),
+}
+
+/// A minus starts a number if a digit follows and it cannot be part of a
+/// name before it.
+fn is_negative_number(tokens: &[Token], next: Option<&char>) -> bool {
+    next.is_some_and(|c| c.is_ascii_digit()) && tokens.last().is_none_or(|token| matches!(*token, Token::Op(_)))
+}
+
+fn tokenize(template: &str, expr: &str, offset: usize) -> Result<Vec<Token>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+
+            loop {
+                match chars.get(i) {
+                    Some(&end) if end == c => break,
+                    Some('\\') if i + 1 < chars.len() => {
+                        s.push(chars[i + 1]);
+                        i += 2;
+                    },
+                    Some(&other) => {
+                        s.push(other);
+                        i += 1;
+                    },
+                    None => return syntax_error(template, offset, "unterminated string"),
+                }
+            }
+
+            i += 1;
+            tokens.push(Token::Str(s));
+        } else if c.is_ascii_digit() || (c == '-' && is_negative_number(&tokens, chars.get(i + 1))) {
+            let start = i;
+            i += 1;
+
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+
+            tokens.push(Token::Num(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '-') {
+                i += 1;
+            }
+
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+
+            if ["==", "!=", "<=", ">="].contains(&two.as_str()) {
+                tokens.push(Token::Op(two));
+                i += 2;
+            } else if "<>|()[],".contains(c) {
+                tokens.push(Token::Op(c.to_string()));
+                i += 1;
+            } else {
+                return syntax_error(template, offset, &format!("unexpected character '{}' in '{}'", c, expr.trim()));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_expr(template: &str, expr: &str, offset: usize) -> Result<Expr> {
+    let tokens = tokenize(template, expr, offset)?;
+    let mut parser = ExprParser { template, expr, offset, tokens, pos: 0 };
+    let parsed = parser.parse_or()?;
+
+    if parser.pos < parser.tokens.len() {
+        return parser.error("unexpected trailing input");
+    }
+
+    Ok(parsed)
+}
+
+struct ExprParser<'a> {
+    template: &'a str,
+    expr: &'a str,
+    offset: usize,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        syntax_error(self.template, self.offset, &format!("{} in '{}'", msg, self.expr.trim()))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        self.peek() == Some(&Token::Op(op.to_owned()))
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.peek() == Some(&Token::Ident(keyword.to_owned()))
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if self.is_op(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", op))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+
+        while self.is_keyword("or") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+
+        while self.is_keyword("and") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.is_keyword("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr> {
+        let left = self.parse_filtered()?;
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) if ["==", "!=", "<", "<=", ">", ">="].contains(&op.as_str()) => op.clone(),
+            _ => return Ok(left),
+        };
+
+        self.pos += 1;
+        let right = self.parse_filtered()?;
+
+        Ok(Expr::Compare(op, Box::new(left), Box::new(right)))
+    }
+
+    fn parse_filtered(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_primary()?;
+
+        while self.is_op("|") {
+            self.pos += 1;
+
+            let name = match self.peek() {
+                Some(Token::Ident(name)) => name.clone(),
+                _ => return self.error("expected a filter name after '|'"),
+            };
+
+            self.pos += 1;
+            let mut args = Vec::new();
+
+            if self.is_op("(") {
+                self.pos += 1;
+                args = self.parse_items(")")?;
+            }
+
+            expr = Expr::Filter(Box::new(expr), name, args);
+        }
+
+        Ok(expr)
+    }
+
+    /// Parses comma separated expressions up to the `close` operator.
+    fn parse_items(&mut self, close: &str) -> Result<Vec<Expr>> {
+        let mut items = Vec::new();
+
+        while !self.is_op(close) {
+            items.push(self.parse_or()?);
+
+            if !self.is_op(close) {
+                self.expect_op(",")?;
+            }
+        }
+
+        self.pos += 1;
+        Ok(items)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return self.error("expected an expression"),
+        };
+
+        self.pos += 1;
+
+        match token {
+            Token::Str(s) | Token::Num(s) => Ok(Expr::Literal(Val::Str(s))),
+            Token::Ident(ref name) if name == "true" => Ok(Expr::Literal(Val::Bool(true))),
+            Token::Ident(ref name) if name == "false" => Ok(Expr::Literal(Val::Bool(false))),
+            Token::Ident(name) => if self.is_op("(") {
+                self.pos += 1;
+                Ok(Expr::Call(name, self.parse_items(")")?))
+            } else {
+                Ok(Expr::Var(name))
+            },
+            Token::Op(ref op) if op == "(" => {
+                let expr = self.parse_or()?;
+                self.expect_op(")")?;
+                Ok(expr)
+            },
+            Token::Op(ref op) if op == "[" => Ok(Expr::List(self.parse_items("]")?)),
+            Token::Op(op) => self.error(&format!("unexpected '{}'", op)),
+        }
+    }
+}
+
+/// Evaluates `expr`, where undefined variables are an error if `strict` and
+/// empty otherwise.
+fn eval(expr: &Expr, vars: &BTreeMap<String, Val>, strict: bool) -> Result<Val> {
+    let val = match *expr {
+        Expr::Var(ref name) => match vars.get(name) {
+            Some(val) => val.clone(),
+            None if strict => bail!("Unknown template variable '{}'", name),
+            None => Val::Str(String::new()),
+        },
+        Expr::Literal(ref val) => val.clone(),
+        Expr::List(ref items) => Val::List(items.iter().map(|item| eval(item, vars, strict)).collect::<Result<_>>()?),
+        Expr::Not(ref expr) => Val::Bool(!eval_cond(expr, vars)?),
+        Expr::And(ref left, ref right) => Val::Bool(eval_cond(left, vars)? && eval_cond(right, vars)?),
+        Expr::Or(ref left, ref right) => Val::Bool(eval_cond(left, vars)? || eval_cond(right, vars)?),
+        Expr::Compare(ref op, ref left, ref right) => {
+            Val::Bool(compare(op, &eval(left, vars, strict)?, &eval(right, vars, strict)?))
+        },
+        Expr::Filter(ref input, ref name, ref args) => {
+            let args = args.iter().map(|arg| eval(arg, vars, strict)).collect::<Result<Vec<Val>>>()?;
+
+            // default stands in for undefined variables, which are otherwise
+            // an error
+            let input = match **input {
+                Expr::Var(ref var) if name == "default" && !vars.contains_key(var) => None,
+                ref input => Some(eval(input, vars, strict)?),
+            };
+
+            apply_filter(name, input, &args)?
+        },
+        Expr::Call(ref name, ref args) => {
+            let args = args.iter().map(|arg| eval(arg, vars, strict)).collect::<Result<Vec<Val>>>()?;
+            call(name, &args)?
+        },
+    };
+
+    Ok(val)
+}
+
+/// Calls the function `name`, which are `now(format)` for the local time in
+/// strftime format (ISO 8601 if absent), `uuid()` for a random UUID and
+/// `rand_int(low, high)` for a random integer from `low` to `high`.
+fn call(name: &str, args: &[Val]) -> Result<Val> {
+    let val = match (name, args.len()) {
+        ("now", 0) => Val::Str(format_now("%Y-%m-%dT%H:%M:%S%z")?),
+        ("now", 1) => Val::Str(format_now(&args[0].to_text())?),
+        ("uuid", 0) => Val::Str(uuid()),
+        ("rand_int", 2) => {
+            let bound = |arg: &Val| arg.to_text().trim().parse::<i64>()
+                .chain_err(|| format!("rand_int expects integers, got '{}'", arg.to_text()));
+
+            let (low, high) = (bound(&args[0])?, bound(&args[1])?);
+
+            if high < low {
+                bail!("rand_int expects its low bound to be at most its high bound");
+            }
+
+            // in 128 bits, as the span of the widest bounds does not fit in
+            // 64 bits
+            let span = (i128::from(high) - i128::from(low)) as u128 + 1;
+            Val::Str((i128::from(low) + (u128::from(random_u64()) % span) as i128).to_string())
+        },
+        ("now", _) | ("uuid", _) | ("rand_int", _) => bail!("Wrong number of arguments for template function '{}'", name),
+        _ => bail!("Unknown template function '{}'", name),
+    };
+
+    Ok(val)
+}
+
+#[cfg(unix)]
+fn format_now(format: &str) -> Result<String> {
+    use std::ffi::CString;
+
+    // strftime gives 0 both for a full buffer and for empty output, which
+    // the trailing space rules out
+    let spaced = CString::new(format!("{} ", format)).chain_err(|| "Time format must not contain NUL")?;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+
+    // the libc calls only write into the locals given
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            bail!("Unable to get the local time");
+        }
+    }
+
+    let mut buf = vec![0u8; 256];
+
+    loop {
+        let len = unsafe { libc::strftime(buf.as_mut_ptr() as *mut libc::c_char, buf.len(), spaced.as_ptr(), &tm) };
+
+        if len > 0 {
+            buf.truncate(len - 1);
+            return Ok(String::from_utf8_lossy(&buf).into_owned());
+        }
+
+        if buf.len() >= MAX_TIME_LEN {
+            bail!("Time format '{}' gives more than {} bytes", format, MAX_TIME_LEN);
+        }
+
+        buf.resize(buf.len() * 2, 0);
+    }
+}
+
+#[cfg(not(unix))]
+fn format_now(_format: &str) -> Result<String> {
+    bail!("now() is only supported on Unix")
+}
+
+/// Random number drawn from the randomly keyed std hasher, which is seeded
+/// by the OS.
+pub fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+/// Random (version 4) UUID.
+fn uuid() -> String {
+    let (high, low) = (random_u64(), random_u64());
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0xc << 60)) | (0x8 << 60);
+
+    format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32, (high >> 16) & 0xffff, high & 0xffff, low >> 48, low & 0xffff_ffff_ffff)
+}
+
+/// Applies the filter `name` to `input`, which is only `None` for an
+/// undefined variable given to `default`.
+fn apply_filter(name: &str, input: Option<Val>, args: &[Val]) -> Result<Val> {
+    let arg = |index: usize| -> Result<Val> {
+        args.get(index).cloned()
+            .ok_or_else(|| format!("Template filter '{}' expects {} argument(s)", name, index + 1).into())
+    };
+
+    let input = match (name, input) {
+        ("default", None) => return arg(0),
+        ("default", Some(input)) => return if input.is_truthy() { Ok(input) } else { arg(0) },
+        (_, input) => input.unwrap_or(Val::Str(String::new())),
+    };
+
+    let text = input.to_text();
+
+    let val = match name {
+        "raw" => input,
+        "upper" => Val::Str(text.to_uppercase()),
+        "lower" => Val::Str(text.to_lowercase()),
+        "trim" => Val::Str(text.trim().to_owned()),
+        "shellquote" | "shell_quote" => Val::Str(shell_quote(&text)),
+        "trim_domain" => Val::Str(text.split('.').next().unwrap_or("").to_owned()),
+        "replace" => Val::Str(text.replace(&arg(0)?.to_text(), &arg(1)?.to_text())),
+        "split" => Val::List(text.split(arg(0)?.to_text().as_str()).map(|part| Val::Str(part.to_owned())).collect()),
+        "join" => Val::Str(input.items().iter().map(|item| item.to_text()).collect::<Vec<_>>().join(&arg(0)?.to_text())),
+        "first" => input.items().into_iter().next().unwrap_or(Val::Str(String::new())),
+        "last" => input.items().into_iter().last().unwrap_or(Val::Str(String::new())),
+        "length" => Val::Str(match input {
+            Val::List(ref items) => items.len(),
+            _ => text.chars().count(),
+        }.to_string()),
+        _ => bail!("Unknown template filter '{}'", name),
+    };
+
+    Ok(val)
+}
+
+/// Quotes `s` for POSIX shells, leaving it bare if it has no special
+/// characters.
+pub fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Evaluates `expr` as a condition, where undefined variables are false.
+fn eval_cond(expr: &Expr, vars: &BTreeMap<String, Val>) -> Result<bool> {
+    Ok(eval(expr, vars, false)?.is_truthy())
+}
+
+/// Compares numerically when both sides are numbers, else as text.
+fn compare(op: &str, left: &Val, right: &Val) -> bool {
+    let (left, right) = (left.to_text(), right.to_text());
+
+    let ordering = match (left.parse::<f64>(), right.parse::<f64>()) {
+        (Ok(l), Ok(r)) => l.partial_cmp(&r),
+        _ => Some(left.cmp(&right)),
+    };
+
+    match (op, ordering) {
+        ("==", Some(ordering)) => ordering.is_eq(),
+        ("!=", Some(ordering)) => ordering.is_ne(),
+        ("<", Some(ordering)) => ordering.is_lt(),
+        ("<=", Some(ordering)) => ordering.is_le(),
+        (">", Some(ordering)) => ordering.is_gt(),
+        (">=", Some(ordering)) => ordering.is_ge(),
+        _ => op == "!=",
+    }
+}
+
+fn render_nodes(nodes: &[Node], vars: &mut BTreeMap<String, Val>, options: &Options, out: &mut String) -> Result<()> {
+    for node in nodes {
+        match *node {
+            Node::Text(ref text) => out.push_str(text),
+            Node::Output(ref expr) => {
+                let text = eval(expr, vars, true)?.to_text();
+
+                // values already quoted, or marked raw, are output as they are
+                let is_final = match *expr {
+                    Expr::Filter(_, ref name, _) => ["raw", "shellquote", "shell_quote"].contains(&name.as_str()),
+                    _ => false,
+                };
+
+                if options.shell_quote && !is_final {
+                    out.push_str(&shell_quote(&text));
+                } else {
+                    out.push_str(&text);
+                }
+            },
+            Node::If(ref branches, ref otherwise) => {
+                let mut chosen = otherwise;
+
+                for (cond, body) in branches {
+                    if eval_cond(cond, vars)? {
+                        chosen = body;
+                        break;
+                    }
+                }
+
+                render_nodes(chosen, vars, options, out)?;
+            },
+            Node::For(ref var, ref iterable, ref body) => {
+                let items = eval(iterable, vars, true)?.items();
+                let saved: Vec<(String, Option<Val>)> = [var.as_str(), "loop.index", "loop.index0", "loop.first", "loop.last"].iter()
+                    .map(|&key| (key.to_owned(), vars.get(key).cloned()))
+                    .collect();
+
+                for (i, item) in items.iter().enumerate() {
+                    vars.insert(var.clone(), item.clone());
+                    vars.insert("loop.index".into(), Val::Str((i + 1).to_string()));
+                    vars.insert("loop.index0".into(), Val::Str(i.to_string()));
+                    vars.insert("loop.first".into(), Val::Bool(i == 0));
+                    vars.insert("loop.last".into(), Val::Bool(i + 1 == items.len()));
+                    render_nodes(body, vars, options, out)?;
+                }
+
+                // restores what the loop variables shadowed
+                for (key, value) in saved {
+                    match value {
+                        Some(value) => vars.insert(key, value),
+                        None => vars.remove(&key),
+                    };
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position() -> Position {
+        Position { index: 0, total: 1 }
+    }
+
+    fn options(shell_quote: bool) -> Options {
+        Options { tag: "{}".to_owned(), shell_quote }
+    }
+
+    fn host_with(vars: &[(&str, &str)]) -> Host {
+        let mut host = Host::new("web1.example.com");
+        host.vars = vars.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect();
+        host
+    }
+
+    #[test]
+    fn plain_substitutes_tag_vars_and_builtins() {
+        let host = host_with(&[("port", "8080")]);
+        let rendered = render("ssh {} -p {port} {host_short} {number}/{total}", &host, position(), &options(false)).unwrap();
+
+        assert_eq!(rendered, "ssh web1.example.com -p 8080 web1 1/1");
+    }
+
+    #[test]
+    fn plain_leaves_unknown_references() {
+        let rendered = render("echo {unknown} {", &host_with(&[]), position(), &options(false)).unwrap();
+        assert_eq!(rendered, "echo {unknown} {");
+    }
+
+    #[test]
+    fn plain_quotes_values_for_the_shell() {
+        let host = host_with(&[("msg", "it's $HOME; ls")]);
+        let rendered = render("echo {msg}", &host, position(), &options(true)).unwrap();
+
+        assert_eq!(rendered, "echo 'it'\\''s $HOME; ls'");
+    }
+
+    // a value holding a reference must not have it substituted in turn,
+    // unquoted inside the quotes of the value
+    #[test]
+    fn plain_does_not_substitute_into_values() {
+        let host = host_with(&[("a", "{b}"), ("b", "; echo INJECTED #")]);
+        let rendered = render("echo {a}", &host, position(), &options(true)).unwrap();
+
+        assert_eq!(rendered, "echo '{b}'");
+    }
+
+    #[test]
+    fn plain_does_not_substitute_the_tag_into_values() {
+        let host = host_with(&[("a", "{}")]);
+        let rendered = render("echo {a}", &host, position(), &options(false)).unwrap();
+
+        assert_eq!(rendered, "echo {}");
+    }
+
+    fn engine(template: &str, vars: &[(&str, &str)]) -> Result<String> {
+        render(template, &host_with(vars), position(), &options(false))
+    }
+
+    #[test]
+    fn engine_outputs_expressions() {
+        assert_eq!(engine("ssh {{ host }} {{ role }} {{ vars.role }}", &[("role", "web")]).unwrap(),
+            "ssh web1.example.com web web");
+        assert_eq!(engine("{{ host_short }}/{{ host_domain }} {{ number }} of {{ total }}", &[]).unwrap(),
+            "web1/example.com 1 of 1");
+        assert_eq!(engine("{{ 'quoted' }}{# comment #}.", &[]).unwrap(), "quoted.");
+    }
+
+    #[test]
+    fn engine_chooses_branches() {
+        let template = "{% if role == 'web' and not drain %}serve{% elif role %}{{ role }}{% else %}idle{% endif %}";
+
+        assert_eq!(engine(template, &[("role", "web")]).unwrap(), "serve");
+        assert_eq!(engine(template, &[("role", "web"), ("drain", "yes")]).unwrap(), "web");
+        assert_eq!(engine(template, &[]).unwrap(), "idle");
+        assert_eq!(engine("{% if weight >= 10 %}heavy{% endif %}", &[("weight", "9")]).unwrap(), "");
+    }
+
+    #[test]
+    fn engine_repeats_loops() {
+        let template = "{% for disk in disks %}{{ loop.index }}:{{ disk }}{% if not loop.last %},{% endif %}{% endfor %}";
+
+        assert_eq!(engine(template, &[("disks", "sda sdb")]).unwrap(), "1:sda,2:sdb");
+        assert_eq!(engine("{% for x in ['a', 'b'] %}{{ x | upper }}{% endfor %}", &[]).unwrap(), "AB");
+    }
+
+    #[test]
+    fn engine_applies_filters() {
+        let vars = [("name", " Web-1.Example.com "), ("list", "a,b,c")];
+
+        assert_eq!(engine("{{ name | trim | lower | trim_domain }}", &vars).unwrap(), "web-1");
+        assert_eq!(engine("{{ missing | default('none') }}", &vars).unwrap(), "none");
+        assert_eq!(engine("{{ list | split(',') | join('-') }}", &vars).unwrap(), "a-b-c");
+        assert_eq!(engine("{{ list | split(',') | last }} {{ list | length }}", &vars).unwrap(), "c 5");
+        assert_eq!(engine("{{ list | replace(',', ' ') | shellquote }}", &vars).unwrap(), "'a b c'");
+    }
+
+    #[test]
+    fn engine_quotes_values_unless_raw() {
+        let host = host_with(&[("msg", "a b")]);
+        let rendered = render("echo {{ msg }} {{ msg | raw }}", &host, position(), &options(true)).unwrap();
+
+        assert_eq!(rendered, "echo 'a b' a b");
+    }
+
+    #[test]
+    fn engine_rejects_malformed_templates() {
+        for template in &[
+            "{{ host",
+            "{% if role %}unclosed",
+            "{% for x in %}{% endfor %}",
+            "{% endif %}",
+            "{% if role %}{% endfor %}",
+            "{{ host | no_such_filter }}",
+            "{{ host | replace('a') }}",
+            "{% bogus %}",
+            "{{ 'unterminated }}",
+            "{{ missing }}",
+        ] {
+            assert!(engine(template, &[("role", "web")]).is_err(), "{:?} should not render", template);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn now_gives_empty_and_long_output() {
+        assert_eq!(format_now("").unwrap(), "");
+        assert_eq!(format_now(&"%%".repeat(1000)).unwrap(), "%".repeat(1000));
+        assert!(format_now(&"%%".repeat(100 * 1024)).is_err());
+    }
+
+    #[test]
+    fn rand_int_stays_within_the_widest_bounds() {
+        for &(low, high) in &[(i64::MIN, i64::MAX), (-1, i64::MAX), (i64::MIN, i64::MIN), (5, 5)] {
+            let args = [Val::Str(low.to_string()), Val::Str(high.to_string())];
+            let value: i64 = call("rand_int", &args).unwrap().to_text().parse().unwrap();
+
+            assert!(low <= value && value <= high, "{} is not within {} to {}", value, low, high);
+        }
+
+        assert!(engine("{{ rand_int(2, 1) }}", &[]).is_err());
+    }
+}
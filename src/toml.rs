@@ -0,0 +1,646 @@
+//! Minimal TOML reader that produces a `serde_json::Value`, so that TOML
+//! configs go through the same deserialization path as JSON ones.
+//!
+//! Supports tables, arrays of tables, dotted and quoted keys, inline tables,
+//! arrays, all four string flavours, integers, floats and booleans. Date-time
+//! values are kept as plain strings.
+
+use errors::*;
+use serde_json::{Map, Number, Value};
+
+pub fn parse(input: &str) -> Result<Value> {
+    Parser { chars: input.chars().collect(), pos: 0 }.parse_document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).cloned()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek_at(i) == Some(c))
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        let consumed = &self.chars[..self.pos.min(self.chars.len())];
+        let line = consumed.iter().filter(|&&c| c == '\n').count() + 1;
+
+        let col = consumed.iter().rev()
+            .take_while(|&&c| c != '\n')
+            .count() + 1;
+
+        bail!(ErrorKind::Syntax(line, col, msg.to_owned()))
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", c))
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while let Some(c) = self.peek() {
+                if c == '\n' {
+                    break;
+                }
+
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skips whitespace, newlines and comments, as allowed inside arrays.
+    fn skip_ws_lines(&mut self) {
+        loop {
+            self.skip_ws();
+            self.skip_comment();
+
+            match self.peek() {
+                Some('\n') | Some('\r') => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn expect_eol(&mut self) -> Result<()> {
+        self.skip_ws();
+        self.skip_comment();
+
+        if self.starts_with("\r\n") {
+            self.pos += 2;
+            return Ok(());
+        }
+
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.pos += 1;
+                Ok(())
+            },
+            _ => self.error("expected end of line"),
+        }
+    }
+
+    fn parse_document(&mut self) -> Result<Value> {
+        let mut root = Map::new();
+        let mut current: Vec<String> = Vec::new();
+
+        loop {
+            self.skip_ws_lines();
+
+            match self.peek() {
+                None => break,
+
+                Some('[') => {
+                    let is_array = self.starts_with("[[");
+                    self.pos += if is_array { 2 } else { 1 };
+                    self.skip_ws();
+                    let path = self.parse_key()?;
+                    self.skip_ws();
+
+                    if is_array {
+                        self.expect(']')?;
+                        self.expect(']')?;
+
+                        let (last, parents) = path.split_last().unwrap();
+                        let parent = self.table_at(&mut root, parents)?;
+
+                        let entry = parent.entry(last.clone())
+                            .or_insert_with(|| Value::Array(Vec::new()));
+
+                        match *entry {
+                            Value::Array(ref mut items) => items.push(Value::Object(Map::new())),
+                            _ => return self.error(&format!("key '{}' is not an array of tables", last)),
+                        }
+                    } else {
+                        self.expect(']')?;
+                        self.table_at(&mut root, &path)?;
+                    }
+
+                    current = path;
+                    self.expect_eol()?;
+                },
+
+                Some(_) => {
+                    let path = self.parse_key()?;
+                    self.skip_ws();
+                    self.expect('=')?;
+                    self.skip_ws();
+                    let value = self.parse_value()?;
+
+                    let table = self.table_at(&mut root, &current)?;
+                    self.insert(table, &path, value)?;
+                    self.expect_eol()?;
+                },
+            }
+        }
+
+        Ok(Value::Object(root))
+    }
+
+    /// Walks down `path` from `root`, creating tables as required. When an
+    /// array of tables is met, its last element is used.
+    fn table_at<'m>(&self, root: &'m mut Map<String, Value>, path: &[String]) -> Result<&'m mut Map<String, Value>> {
+        let mut table = root;
+
+        for key in path {
+            let entry = table.entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+
+            let next = match *entry {
+                Value::Object(ref mut inner) => inner,
+
+                Value::Array(ref mut items) => match items.last_mut() {
+                    Some(&mut Value::Object(ref mut inner)) => inner,
+                    _ => return self.error(&format!("key '{}' is not a table", key)),
+                },
+
+                _ => return self.error(&format!("key '{}' is not a table", key)),
+            };
+
+            table = next;
+        }
+
+        Ok(table)
+    }
+
+    fn insert(&self, table: &mut Map<String, Value>, path: &[String], value: Value) -> Result<()> {
+        let (last, parents) = path.split_last().unwrap();
+        let table = self.table_at(table, parents)?;
+
+        if table.contains_key(last) {
+            return self.error(&format!("duplicate key '{}'", last));
+        }
+
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+
+    fn parse_key(&mut self) -> Result<Vec<String>> {
+        let mut parts = Vec::new();
+
+        loop {
+            let part = match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    self.parse_basic_string()?
+                },
+
+                Some('\'') => {
+                    self.pos += 1;
+                    self.parse_literal_string()?
+                },
+
+                _ => {
+                    let start = self.pos;
+
+                    while let Some(c) = self.peek() {
+                        if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                            self.pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+
+                    if start == self.pos {
+                        return self.error("expected key");
+                    }
+
+                    self.chars[start..self.pos].iter().collect()
+                },
+            };
+
+            parts.push(part);
+            self.skip_ws();
+
+            if self.peek() == Some('.') {
+                self.pos += 1;
+                self.skip_ws();
+            } else {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        if self.starts_with("\"\"\"") {
+            self.pos += 3;
+            return self.parse_multiline_basic_string().map(Value::String);
+        }
+
+        if self.starts_with("'''") {
+            self.pos += 3;
+            return self.parse_multiline_literal_string().map(Value::String);
+        }
+
+        match self.peek() {
+            Some('"') => {
+                self.pos += 1;
+                self.parse_basic_string().map(Value::String)
+            },
+
+            Some('\'') => {
+                self.pos += 1;
+                self.parse_literal_string().map(Value::String)
+            },
+
+            Some('[') => {
+                self.pos += 1;
+                self.parse_array()
+            },
+
+            Some('{') => {
+                self.pos += 1;
+                self.parse_inline_table()
+            },
+
+            Some(_) if self.starts_with("true") => {
+                self.pos += 4;
+                Ok(Value::Bool(true))
+            },
+
+            Some(_) if self.starts_with("false") => {
+                self.pos += 5;
+                Ok(Value::Bool(false))
+            },
+
+            Some(_) => self.parse_number_or_date(),
+            None => self.error("expected value"),
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value> {
+        let mut items = Vec::new();
+
+        loop {
+            self.skip_ws_lines();
+
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+
+            items.push(self.parse_value()?);
+            self.skip_ws_lines();
+
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => (),
+                _ => return self.error("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> Result<Value> {
+        let mut table = Map::new();
+        self.skip_ws();
+
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Object(table));
+        }
+
+        loop {
+            self.skip_ws();
+            let path = self.parse_key()?;
+            self.skip_ws();
+            self.expect('=')?;
+            self.skip_ws();
+            let value = self.parse_value()?;
+            self.insert(&mut table, &path, value)?;
+            self.skip_ws();
+
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(table));
+                },
+                _ => return self.error("expected ',' or '}' in inline table"),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("unterminated escape sequence"),
+        };
+
+        self.pos += 1;
+
+        let unicode_len = match c {
+            'b' => return Ok('\u{8}'),
+            't' => return Ok('\t'),
+            'n' => return Ok('\n'),
+            'f' => return Ok('\u{c}'),
+            'r' => return Ok('\r'),
+            '"' => return Ok('"'),
+            '\\' => return Ok('\\'),
+            'u' => 4,
+            'U' => 8,
+            _ => return self.error(&format!("invalid escape sequence '\\{}'", c)),
+        };
+
+        if self.pos + unicode_len > self.chars.len() {
+            return self.error("truncated unicode escape");
+        }
+
+        let hex: String = self.chars[self.pos..self.pos + unicode_len].iter().collect();
+        self.pos += unicode_len;
+
+        match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+            Some(c) => Ok(c),
+            None => self.error(&format!("invalid unicode escape '{}'", hex)),
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                },
+
+                Some('\\') => {
+                    self.pos += 1;
+                    s.push(self.parse_escape()?);
+                },
+
+                Some('\n') | None => return self.error("unterminated string"),
+
+                Some(c) => {
+                    self.pos += 1;
+                    s.push(c);
+                },
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+
+        loop {
+            match self.peek() {
+                Some('\'') => {
+                    self.pos += 1;
+                    return Ok(s);
+                },
+
+                Some('\n') | None => return self.error("unterminated string"),
+
+                Some(c) => {
+                    self.pos += 1;
+                    s.push(c);
+                },
+            }
+        }
+    }
+
+    fn skip_leading_newline(&mut self) {
+        if self.starts_with("\r\n") {
+            self.pos += 2;
+        } else if self.peek() == Some('\n') {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_multiline_basic_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        self.skip_leading_newline();
+
+        loop {
+            if self.starts_with("\"\"\"") {
+                self.pos += 3;
+                return Ok(s);
+            }
+
+            match self.peek() {
+                Some('\\') => {
+                    self.pos += 1;
+
+                    // a line ending backslash trims all whitespace up to the
+                    // next non-whitespace character
+                    let mut lookahead = self.pos;
+
+                    while let Some(&c) = self.chars.get(lookahead) {
+                        if c == ' ' || c == '\t' {
+                            lookahead += 1;
+                        } else {
+                            break;
+                        }
+                    }
+
+                    match self.chars.get(lookahead) {
+                        Some(&'\n') | Some(&'\r') => {
+                            self.pos = lookahead;
+
+                            while let Some(c) = self.peek() {
+                                if c.is_whitespace() {
+                                    self.pos += 1;
+                                } else {
+                                    break;
+                                }
+                            }
+                        },
+
+                        _ => s.push(self.parse_escape()?),
+                    }
+                },
+
+                Some(c) => {
+                    self.pos += 1;
+                    s.push(c);
+                },
+
+                None => return self.error("unterminated multi-line string"),
+            }
+        }
+    }
+
+    fn parse_multiline_literal_string(&mut self) -> Result<String> {
+        let mut s = String::new();
+        self.skip_leading_newline();
+
+        loop {
+            if self.starts_with("'''") {
+                self.pos += 3;
+                return Ok(s);
+            }
+
+            match self.peek() {
+                Some(c) => {
+                    self.pos += 1;
+                    s.push(c);
+                },
+
+                None => return self.error("unterminated multi-line string"),
+            }
+        }
+    }
+
+    fn parse_number_or_date(&mut self) -> Result<Value> {
+        let start = self.pos;
+
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || "_+-.:".contains(c) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        let token: String = self.chars[start..self.pos].iter().collect();
+
+        if token.is_empty() {
+            return self.error("expected value");
+        }
+
+        let unsigned = token.trim_start_matches(['+', '-']);
+
+        // dates and times such as 1979-05-27T07:32:00Z are kept verbatim
+        if unsigned.contains(':') || unsigned.len() >= 10 && unsigned[..10].matches('-').count() == 2 {
+            return Ok(Value::String(token));
+        }
+
+        let cleaned = token.replace('_', "");
+
+        let radix = if cleaned.starts_with("0x") {
+            Some(16)
+        } else if cleaned.starts_with("0o") {
+            Some(8)
+        } else if cleaned.starts_with("0b") {
+            Some(2)
+        } else {
+            None
+        };
+
+        if let Some(radix) = radix {
+            return match i64::from_str_radix(&cleaned[2..], radix) {
+                Ok(n) => Ok(Value::Number(n.into())),
+                Err(_) => {
+                    self.pos = start;
+                    self.error(&format!("invalid integer '{}'", token))
+                },
+            };
+        }
+
+        if let Ok(n) = cleaned.parse::<i64>() {
+            return Ok(Value::Number(n.into()));
+        }
+
+        match cleaned.parse::<f64>().ok().and_then(Number::from_f64) {
+            Some(n) => Ok(Value::Number(n)),
+            None => {
+                self.pos = start;
+                self.error(&format!("invalid value '{}'", token))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values_of_every_kind() {
+        let value = parse(r#"
+# comment
+name = "web"   # trailing comment
+port = 8080
+ratio = 0.5
+enabled = true
+hosts = ["a", "b",]
+when = 2026-01-01T00:00:00Z
+"#).unwrap();
+
+        assert_eq!(value["name"], "web");
+        assert_eq!(value["port"], 8080);
+        assert_eq!(value["ratio"], 0.5);
+        assert_eq!(value["enabled"], true);
+        assert_eq!(value["hosts"], Value::from(vec!["a", "b"]));
+        assert_eq!(value["when"], "2026-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn parses_string_escapes_and_flavours() {
+        let value = parse(r#"
+basic = "tab\there \"quoted\" \u00e9 \\"
+literal = 'C:\path\n'
+multi = """
+first \
+  second"""
+raw = '''
+kept \n'''
+"#).unwrap();
+
+        assert_eq!(value["basic"], "tab\there \"quoted\" \u{e9} \\");
+        assert_eq!(value["literal"], "C:\\path\\n");
+        assert_eq!(value["multi"], "first second");
+        assert_eq!(value["raw"], "kept \\n");
+    }
+
+    #[test]
+    fn parses_nested_tables_and_arrays_of_tables() {
+        let value = parse(r#"
+a.b = 1
+"quoted key" = 2
+
+[server.tls]
+cert = "c.pem"
+
+[[groups]]
+name = "web"
+
+[[groups]]
+name = "db"
+limits = { cpu = 2, mem = { max = 4 } }
+"#).unwrap();
+
+        assert_eq!(value["a"]["b"], 1);
+        assert_eq!(value["quoted key"], 2);
+        assert_eq!(value["server"]["tls"]["cert"], "c.pem");
+        assert_eq!(value["groups"][0]["name"], "web");
+        assert_eq!(value["groups"][1]["limits"]["mem"]["max"], 4);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in &[
+            "key = ",
+            "key = \"unterminated",
+            "key = \"bad \\q escape\"",
+            "[table",
+            "key = [1, 2",
+            "key = { a = 1",
+            "key = 1 trailing",
+            "= 1",
+            "a = 1\na = 2",
+        ] {
+            assert!(parse(input).is_err(), "{:?} should not parse", input);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Local account that commands run as, switched to directly when each_cmd
+//! runs as root and through `sudo` otherwise.
+
+use errors::*;
+use std::process::Command;
+
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+#[cfg(unix)]
+use std::io;
+
+#[derive(Clone, Debug)]
+pub struct RunAs {
+    pub user: String,
+    uid: u32,
+    gid: u32,
+
+    /// Supplementary groups of the account.
+    groups: Vec<u32>,
+    home: String,
+
+    /// Whether commands go through sudo, each_cmd lacking the privileges to
+    /// switch users itself.
+    pub sudo: bool,
+}
+
+impl RunAs {
+    /// Looks up the account of `user`, a name or numeric uid.
+    #[cfg(unix)]
+    pub fn lookup(user: &str) -> Result<RunAs> {
+        let mut buffer = vec![0 as libc::c_char; 16384];
+        let mut passwd: libc::passwd = unsafe { ::std::mem::zeroed() };
+        let mut found: *mut libc::passwd = ::std::ptr::null_mut();
+
+        let code = match user.parse::<libc::uid_t>() {
+            Ok(uid) => unsafe {
+                libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut found)
+            },
+            Err(_) => {
+                let name = CString::new(user).chain_err(|| format!("Invalid user name '{}'", user))?;
+
+                unsafe {
+                    libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut found)
+                }
+            },
+        };
+
+        if code != 0 {
+            return Err(io::Error::from_raw_os_error(code)).chain_err(|| format!("Unable to look up user '{}'", user));
+        }
+
+        if found.is_null() {
+            bail!("No such user '{}' to run commands as", user);
+        }
+
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().into_owned();
+
+        let mut groups = vec![0 as libc::gid_t; 256];
+        let mut count = groups.len() as libc::c_int;
+
+        // falls back on the primary group alone if there are too many
+        let listed = unsafe {
+            libc::getgrouplist(name.as_ptr(), passwd.pw_gid as _, groups.as_mut_ptr() as *mut _, &mut count)
+        };
+
+        if listed < 0 {
+            groups = vec![passwd.pw_gid];
+        } else {
+            groups.truncate(count as usize);
+        }
+
+        let uid = passwd.pw_uid;
+
+        Ok(RunAs {
+            user: name.to_string_lossy().into_owned(),
+            uid,
+            gid: passwd.pw_gid,
+            groups,
+            home,
+            sudo: unsafe { libc::geteuid() } != 0 && unsafe { libc::geteuid() } != uid,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn lookup(user: &str) -> Result<RunAs> {
+        bail!("Running commands as user '{}' is only supported on Unix", user)
+    }
+
+    /// User and group ids of the account.
+    pub fn owner(&self) -> (u32, u32) {
+        (self.uid, self.gid)
+    }
+
+    /// Variables that the account would have in a login of its own.
+    pub fn env(&self) -> Vec<(String, String)> {
+        vec![
+            ("HOME".to_owned(), self.home.clone()),
+            ("USER".to_owned(), self.user.clone()),
+            ("LOGNAME".to_owned(), self.user.clone()),
+        ]
+    }
+
+    /// Wraps `command` in sudo, keeping the variables named by `keep`, which
+    /// sudo otherwise resets.
+    pub fn sudo_command(&self, command: &Command, keep: &[String]) -> Command {
+        // never prompts for a password, which nothing could answer
+        let mut sudo = Command::new("sudo");
+        sudo.args(["-n", "-u", &self.user]);
+
+        if !keep.is_empty() {
+            sudo.arg(format!("--preserve-env={}", keep.join(",")));
+        }
+
+        sudo.arg("--").arg(command.get_program()).args(command.get_args());
+        sudo
+    }
+
+    /// Switches the current process to the account, to be called between
+    /// fork and exec, where only system calls are safe.
+    #[cfg(unix)]
+    pub fn switch(&self) -> io::Result<()> {
+        let check = |code: libc::c_int| if code == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+
+        // the groups go first, as they can no longer be changed once the
+        // user is
+        unsafe {
+            check(libc::setgroups(self.groups.len() as _, self.groups.as_ptr() as *const _))?;
+            check(libc::setgid(self.gid))?;
+            check(libc::setuid(self.uid))
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! How much of the run is printed.
+
+use meter;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Normal as usize);
+
+/// What is printed, each level adding to those below it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Only the failures and the summary of the run.
+    Quiet,
+
+    /// How each host ended, with its output trimmed.
+    Normal,
+
+    /// The rendered commands as they start.
+    Commands,
+
+    /// The full output of the commands, untrimmed.
+    Output,
+
+    /// Why commands wait before starting, e.g. for a free slot or a
+    /// delay.
+    Scheduling,
+}
+
+impl Level {
+    /// Level of `verbose`, given as the number of `-v` flags.
+    pub fn verbose(verbose: u64) -> Level {
+        match verbose {
+            0 => Level::Normal,
+            1 => Level::Commands,
+            2 => Level::Output,
+            _ => Level::Scheduling,
+        }
+    }
+}
+
+pub fn set(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// Whether output of `level` is shown.
+pub fn shows(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as usize
+}
+
+pub fn quiet() -> bool {
+    !shows(Level::Normal)
+}
+
+/// Prints the scheduling decision given by `message` at `-vvv`.
+pub fn trace<F: FnOnce() -> String>(message: F) {
+    if shows(Level::Scheduling) {
+        let _hidden = meter::hide();
+        let _ = writeln!(io::stderr(), "Scheduling: {}", message());
+    }
+}
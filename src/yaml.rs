@@ -0,0 +1,730 @@
+//! Minimal YAML reader that produces a `serde_json::Value`, so that YAML
+//! configs go through the same deserialization path as JSON ones.
+//!
+//! Supports block mappings and sequences (including the compact `- key: value`
+//! form), flow collections, plain and quoted scalars, literal (`|`) and folded
+//! (`>`) block scalars and comments. Anchors, aliases, tags and multiple
+//! documents are not supported.
+
+use errors::*;
+use serde_json::{Map, Number, Value};
+
+pub fn parse(input: &str) -> Result<Value> {
+    let mut lines: Vec<Line> = input.lines()
+        .enumerate()
+        .map(|(i, raw)| Line { number: i + 1, indent: 0, text: raw.to_owned() })
+        .collect();
+
+    for line in &mut lines {
+        if line.text.starts_with('\t') {
+            bail!(ErrorKind::Syntax(line.number, 1, "tabs cannot be used for indentation".to_owned()));
+        }
+
+        let indent = line.text.len() - line.text.trim_start_matches(' ').len();
+        line.text = line.text[indent..].to_owned();
+        line.indent = indent;
+    }
+
+    // a leading document start marker is allowed, as is a document end marker
+    let mut parser = Parser { lines, idx: 0 };
+    parser.skip_blank();
+
+    if parser.idx < parser.lines.len() && parser.lines[parser.idx].is_marker("---") {
+        let line = &mut parser.lines[parser.idx];
+
+        // "--- value" keeps the value on the marker line
+        line.text = line.text[3..].trim_start().to_owned();
+        line.indent += 4;
+    }
+
+    if let Some(end) = parser.lines.iter().position(|line| line.indent == 0 && line.is_marker("...")) {
+        parser.lines.truncate(end);
+    }
+
+    parser.skip_blank();
+
+    if parser.idx >= parser.lines.len() {
+        return Ok(Value::Null);
+    }
+
+    let indent = parser.lines[parser.idx].indent;
+    let value = parser.parse_block(indent)?;
+    parser.skip_blank();
+
+    if parser.idx < parser.lines.len() {
+        let line = &parser.lines[parser.idx];
+        bail!(ErrorKind::Syntax(line.number, line.indent + 1, "unexpected content after document".to_owned()));
+    }
+
+    Ok(value)
+}
+
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+impl Line {
+    /// Line text with any trailing comment and whitespace removed.
+    fn content(&self) -> &str {
+        strip_comment(&self.text)
+    }
+
+    fn is_blank(&self) -> bool {
+        self.content().is_empty()
+    }
+
+    fn is_marker(&self, marker: &str) -> bool {
+        self.text == marker || self.text.starts_with(marker) && self.text[3..].starts_with(' ')
+    }
+
+    fn is_seq_item(&self) -> bool {
+        let content = self.content();
+        content == "-" || content.starts_with("- ")
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut prev_is_space = true;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            // a backslash only escapes within double quotes
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == '#' && prev_is_space => return text[..i].trim_end(),
+            None if (c == '"' || c == '\'') && prev_is_space => quote = Some(c),
+            None => (),
+        }
+
+        prev_is_space = c == ' ' || c == '\t' || c == '[' || c == '{' || c == ',';
+    }
+
+    text.trim_end()
+}
+
+fn syntax_error<T>(line: usize, col: usize, msg: &str) -> Result<T> {
+    bail!(ErrorKind::Syntax(line, col, msg.to_owned()))
+}
+
+struct Parser {
+    lines: Vec<Line>,
+    idx: usize,
+}
+
+impl Parser {
+    fn skip_blank(&mut self) {
+        while self.idx < self.lines.len() && self.lines[self.idx].is_blank() {
+            self.idx += 1;
+        }
+    }
+
+    /// Indentation of the next non-blank line, if any.
+    fn next_indent(&mut self) -> Option<usize> {
+        self.skip_blank();
+        self.lines.get(self.idx).map(|line| line.indent)
+    }
+
+    fn parse_block(&mut self, indent: usize) -> Result<Value> {
+        self.skip_blank();
+        let line = &self.lines[self.idx];
+
+        if line.is_seq_item() {
+            self.parse_sequence(indent)
+        } else if split_mapping_key(line.content(), line.number, line.indent)?.is_some() {
+            self.parse_mapping(indent)
+        } else {
+            let (number, col, content) = (line.number, line.indent + 1, line.content().to_owned());
+            self.idx += 1;
+            self.parse_inline(&content, number, col, indent)
+        }
+    }
+
+    fn parse_sequence(&mut self, indent: usize) -> Result<Value> {
+        let mut items = Vec::new();
+
+        while self.next_indent() == Some(indent) && self.lines[self.idx].is_seq_item() {
+            let line = &mut self.lines[self.idx];
+            let rest = line.text[1..].trim_start().to_owned();
+
+            if strip_comment(&rest).is_empty() {
+                self.idx += 1;
+
+                match self.next_indent() {
+                    Some(child) if child > indent => items.push(self.parse_block(child)?),
+                    _ => items.push(Value::Null),
+                }
+            } else {
+                // re-reads the remainder of the line as if it started on its
+                // own line, so that "- key: value" opens a nested mapping
+                let offset = line.text.len() - rest.len();
+                line.indent += offset;
+                line.text = rest;
+
+                let child = line.indent;
+                items.push(self.parse_block(child)?);
+            }
+        }
+
+        Ok(Value::Array(items))
+    }
+
+    fn parse_mapping(&mut self, indent: usize) -> Result<Value> {
+        let mut map = Map::new();
+
+        while self.next_indent() == Some(indent) {
+            let (number, col) = (self.lines[self.idx].number, self.lines[self.idx].indent + 1);
+
+            let (key, rest) = {
+                let line = &self.lines[self.idx];
+
+                match split_mapping_key(line.content(), line.number, line.indent)? {
+                    Some((key, rest)) => (key, rest.to_owned()),
+                    None => break,
+                }
+            };
+
+            if map.contains_key(&key) {
+                return syntax_error(number, col, &format!("duplicate key '{}'", key));
+            }
+
+            self.idx += 1;
+
+            let value = if rest.is_empty() {
+                match self.next_indent() {
+                    Some(child) if child > indent => self.parse_block(child)?,
+
+                    // sequences may sit at the same indentation as their key
+                    Some(child) if child == indent && self.lines[self.idx].is_seq_item() => {
+                        self.parse_sequence(child)?
+                    },
+
+                    _ => Value::Null,
+                }
+            } else {
+                let col = col + self.lines[self.idx - 1].text.len() - rest.len();
+                self.parse_inline(&rest, number, col, indent)?
+            };
+
+            map.insert(key, value);
+        }
+
+        Ok(Value::Object(map))
+    }
+
+    /// Parses a value that starts on an already consumed line, which may
+    /// continue onto lines indented deeper than `indent`.
+    fn parse_inline(&mut self, content: &str, number: usize, col: usize, indent: usize) -> Result<Value> {
+        if content.starts_with('|') || content.starts_with('>') {
+            return self.parse_block_scalar(content, number, indent).map(Value::String);
+        }
+
+        if content.starts_with('[') || content.starts_with('{') {
+            // flow collections may span several lines
+            let mut text = content.to_owned();
+
+            while !flow_is_closed(&text) && self.idx < self.lines.len() {
+                text.push(' ');
+                text.push_str(self.lines[self.idx].content());
+                self.idx += 1;
+            }
+
+            let chars: Vec<char> = text.chars().collect();
+            let mut flow = Flow { chars: &chars, pos: 0, line: number, col };
+            let value = flow.parse_value()?;
+            flow.skip_ws();
+
+            if flow.pos < chars.len() {
+                return flow.error("unexpected content after flow collection");
+            }
+
+            return Ok(value);
+        }
+
+        if content.starts_with('"') || content.starts_with('\'') {
+            let chars: Vec<char> = content.chars().collect();
+            let mut flow = Flow { chars: &chars, pos: 0, line: number, col };
+            let value = flow.parse_quoted()?;
+            flow.skip_ws();
+
+            if flow.pos < chars.len() {
+                return flow.error("unexpected content after quoted scalar");
+            }
+
+            return Ok(Value::String(value));
+        }
+
+        // plain scalars fold continuation lines into single spaces
+        let mut text = content.to_owned();
+
+        while let Some(child) = self.next_indent() {
+            if child <= indent {
+                break;
+            }
+
+            text.push(' ');
+            text.push_str(self.lines[self.idx].content());
+            self.idx += 1;
+        }
+
+        Ok(resolve_plain(&text))
+    }
+
+    fn parse_block_scalar(&mut self, header: &str, number: usize, indent: usize) -> Result<String> {
+        let literal = header.starts_with('|');
+        let indicators = header[1..].trim();
+
+        let chomp = if indicators.contains('-') {
+            '-'
+        } else if indicators.contains('+') {
+            '+'
+        } else {
+            ' '
+        };
+
+        if indicators.chars().any(|c| c != '-' && c != '+') {
+            return syntax_error(number, indent + 1, "unsupported block scalar indicator");
+        }
+
+        let mut body: Vec<String> = Vec::new();
+        let mut block_indent = None;
+
+        while self.idx < self.lines.len() {
+            let line = &self.lines[self.idx];
+
+            if line.text.is_empty() {
+                body.push(String::new());
+                self.idx += 1;
+                continue;
+            }
+
+            let required = *block_indent.get_or_insert(line.indent);
+
+            if line.indent <= indent || line.indent < required {
+                break;
+            }
+
+            body.push(format!("{}{}", " ".repeat(line.indent - required), line.text));
+            self.idx += 1;
+        }
+
+        let trailing_blanks = body.iter().rev().take_while(|line| line.is_empty()).count();
+        let content_len = body.len() - trailing_blanks;
+
+        let mut text = if literal {
+            body[..content_len].join("\n")
+        } else {
+            let mut folded = String::new();
+
+            for (i, line) in body[..content_len].iter().enumerate() {
+                if i > 0 {
+                    let prev = &body[i - 1];
+
+                    // single line breaks fold into spaces, except around
+                    // blank or more indented lines
+                    if line.is_empty() || line.starts_with(' ') || prev.starts_with(' ') {
+                        folded.push('\n');
+                    } else if !prev.is_empty() {
+                        folded.push(' ');
+                    }
+                }
+
+                folded.push_str(line);
+            }
+
+            folded
+        };
+
+        match chomp {
+            '-' => (),
+            '+' => {
+                text.push('\n');
+                text.push_str(&"\n".repeat(trailing_blanks));
+            },
+            _ => if content_len > 0 {
+                text.push('\n');
+            },
+        }
+
+        Ok(text)
+    }
+}
+
+/// Splits "key: value" into its key and the trimmed remainder, or returns
+/// `None` if the content is not a mapping entry.
+fn split_mapping_key(content: &str, number: usize, indent: usize) -> Result<Option<(String, &str)>> {
+    if content.starts_with('[') || content.starts_with('{') {
+        return Ok(None);
+    }
+
+    if content.starts_with('"') || content.starts_with('\'') {
+        let chars: Vec<char> = content.chars().collect();
+        let mut flow = Flow { chars: &chars, pos: 0, line: number, col: indent + 1 };
+        let key = flow.parse_quoted()?;
+        flow.skip_ws();
+
+        if flow.peek() != Some(':') {
+            return Ok(None);
+        }
+
+        let rest: String = chars[flow.pos + 1..].iter().collect();
+
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            return Ok(None);
+        }
+
+        let rest_start = content.len() - rest.len();
+        return Ok(Some((key, content[rest_start..].trim())));
+    }
+
+    let mut search = 0;
+
+    while let Some(found) = content[search..].find(':') {
+        let colon = search + found;
+        let after = &content[colon + 1..];
+
+        if after.is_empty() || after.starts_with(' ') {
+            let key = content[..colon].trim_end();
+
+            if key.is_empty() {
+                return Ok(None);
+            }
+
+            return Ok(Some((key.to_owned(), after.trim())));
+        }
+
+        search = colon + 1;
+    }
+
+    Ok(None)
+}
+
+fn flow_is_closed(text: &str) -> bool {
+    let mut depth = 0i32;
+    let mut quote = None;
+
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '[' | '{' => depth += 1,
+                ']' | '}' => depth -= 1,
+                _ => (),
+            },
+        }
+    }
+
+    depth <= 0
+}
+
+/// Applies the YAML core schema to an unquoted scalar.
+fn resolve_plain(text: &str) -> Value {
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => return Value::Null,
+        "true" | "True" | "TRUE" => return Value::Bool(true),
+        "false" | "False" | "FALSE" => return Value::Bool(false),
+        _ => (),
+    }
+
+    if let Ok(n) = text.parse::<i64>() {
+        return Value::Number(n.into());
+    }
+
+    let radix = if text.starts_with("0x") {
+        Some(16)
+    } else if text.starts_with("0o") {
+        Some(8)
+    } else {
+        None
+    };
+
+    if let Some(n) = radix.and_then(|radix| i64::from_str_radix(&text[2..], radix).ok()) {
+        return Value::Number(n.into());
+    }
+
+    let looks_numeric = text.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+        && text.chars().any(|c| c.is_ascii_digit());
+
+    if looks_numeric {
+        if let Some(n) = text.parse::<f64>().ok().and_then(Number::from_f64) {
+            return Value::Number(n);
+        }
+    }
+
+    Value::String(text.to_owned())
+}
+
+/// Character level parser for flow collections and quoted scalars.
+struct Flow<'a> {
+    chars: &'a [char],
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl<'a> Flow<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        syntax_error(self.line, self.col + self.pos, msg)
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(' ') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        self.skip_ws();
+
+        match self.peek() {
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+
+                loop {
+                    self.skip_ws();
+
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(Value::Array(items));
+                    }
+
+                    items.push(self.parse_value()?);
+                    self.skip_ws();
+
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => (),
+                        _ => return self.error("expected ',' or ']' in flow sequence"),
+                    }
+                }
+            },
+
+            Some('{') => {
+                self.pos += 1;
+                let mut map = Map::new();
+
+                loop {
+                    self.skip_ws();
+
+                    if self.peek() == Some('}') {
+                        self.pos += 1;
+                        return Ok(Value::Object(map));
+                    }
+
+                    let key = match self.parse_value()? {
+                        Value::String(key) => key,
+                        Value::Null => String::new(),
+                        other => other.to_string(),
+                    };
+
+                    self.skip_ws();
+
+                    let value = if self.peek() == Some(':') {
+                        self.pos += 1;
+                        self.parse_value()?
+                    } else {
+                        Value::Null
+                    };
+
+                    map.insert(key, value);
+                    self.skip_ws();
+
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some('}') => (),
+                        _ => return self.error("expected ',' or '}' in flow mapping"),
+                    }
+                }
+            },
+
+            Some('"') | Some('\'') => self.parse_quoted().map(Value::String),
+
+            _ => {
+                let start = self.pos;
+
+                while let Some(c) = self.peek() {
+                    let ends_key = c == ':'
+                        && matches!(self.chars.get(self.pos + 1), None | Some(&' ') | Some(&',') | Some(&']') | Some(&'}'));
+
+                    if c == ',' || c == ']' || c == '}' || ends_key {
+                        break;
+                    }
+
+                    self.pos += 1;
+                }
+
+                let text: String = self.chars[start..self.pos].iter().collect();
+                Ok(resolve_plain(text.trim()))
+            },
+        }
+    }
+
+    fn parse_quoted(&mut self) -> Result<String> {
+        let quote = match self.peek() {
+            Some(c) => c,
+            None => return self.error("expected quoted scalar"),
+        };
+
+        self.pos += 1;
+        let mut s = String::new();
+
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.error("unterminated quoted scalar"),
+            };
+
+            self.pos += 1;
+
+            if c == quote {
+                // single quotes are escaped by doubling them
+                if quote == '\'' && self.peek() == Some('\'') {
+                    self.pos += 1;
+                    s.push('\'');
+                    continue;
+                }
+
+                return Ok(s);
+            }
+
+            if c == '\\' && quote == '"' {
+                s.push(self.parse_escape()?);
+            } else {
+                s.push(c);
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("unterminated escape sequence"),
+        };
+
+        self.pos += 1;
+
+        let hex_len = match c {
+            '0' => return Ok('\0'),
+            'a' => return Ok('\u{7}'),
+            'b' => return Ok('\u{8}'),
+            't' => return Ok('\t'),
+            'n' => return Ok('\n'),
+            'v' => return Ok('\u{b}'),
+            'f' => return Ok('\u{c}'),
+            'r' => return Ok('\r'),
+            'e' => return Ok('\u{1b}'),
+            ' ' | '"' | '/' | '\\' => return Ok(c),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return self.error(&format!("invalid escape sequence '\\{}'", c)),
+        };
+
+        if self.pos + hex_len > self.chars.len() {
+            return self.error("truncated escape sequence");
+        }
+
+        let hex: String = self.chars[self.pos..self.pos + hex_len].iter().collect();
+        self.pos += hex_len;
+
+        match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+            Some(c) => Ok(c),
+            None => self.error(&format!("invalid escape sequence '\\{}{}'", c, hex)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_block_collections_and_scalars() {
+        let value = parse("---
+# comment
+name: web   # trailing comment
+port: 8080
+ratio: 0.5
+enabled: yes
+off: false
+missing: ~
+hex: 0x1f
+hosts:
+  - a
+  - b
+groups:
+  - name: web
+    tags: [prod, 'eu west']
+  - name: db
+    limits: { cpu: 2, mem: 4 }
+...
+ignored: after the end marker
+").unwrap();
+
+        assert_eq!(value["name"], "web");
+        assert_eq!(value["port"], 8080);
+        assert_eq!(value["ratio"], 0.5);
+        assert_eq!(value["enabled"], "yes");
+        assert_eq!(value["off"], false);
+        assert_eq!(value["missing"], Value::Null);
+        assert_eq!(value["hex"], 31);
+        assert_eq!(value["hosts"], Value::from(vec!["a", "b"]));
+        assert_eq!(value["groups"][0]["tags"], Value::from(vec!["prod", "eu west"]));
+        assert_eq!(value["groups"][1]["limits"]["mem"], 4);
+        assert_eq!(value.get("ignored"), None);
+    }
+
+    #[test]
+    fn parses_quoted_and_block_scalars() {
+        let value = parse(r#"
+double: "tab\there \"quoted\" é # not a comment"
+single: 'it''s \n'
+literal: |
+  line one
+  line two
+folded: >
+  folded
+  together
+"#).unwrap();
+
+        assert_eq!(value["double"], "tab\there \"quoted\" \u{e9} # not a comment");
+        assert_eq!(value["single"], "it's \\n");
+        assert_eq!(value["literal"], "line one\nline two\n");
+        assert_eq!(value["folded"], "folded together\n");
+    }
+
+    #[test]
+    fn parses_empty_documents_as_null() {
+        assert_eq!(parse("").unwrap(), Value::Null);
+        assert_eq!(parse("# only a comment\n---\n").unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for input in &[
+            "\tkey: value",
+            "key: \"unterminated",
+            "key: \"bad \\q escape\"",
+            "key: [a, b",
+            "key: {a: 1",
+            "key: [a] trailing",
+            "a: 1\na: 2",
+            "key: |x\n  text",
+            "- item\nkey: value",
+        ] {
+            assert!(parse(input).is_err(), "{:?} should not parse", input);
+        }
+    }
+}